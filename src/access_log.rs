@@ -0,0 +1,148 @@
+// Access logging, a JSON line per request logged to the "access_log" target
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use hyper::body::HttpBody;
+use hyper::http::{Method, Request, Response};
+use hyper::Body;
+use log::{info, warn};
+use serde::Serialize;
+
+use super::body::body_with_guard;
+use super::connection::UpstreamConnection;
+use super::listener::ConnectionId;
+
+#[derive(Debug, Clone, Default)]
+pub struct ResponseContext {
+    // Added to the extensions of proxied responses, describing how they were obtained
+    pub location: Option<String>,
+    pub upstream_connection: Option<UpstreamConnection>,
+    pub routing: Option<Duration>, // Matching the backend and selecting the location
+    pub connect: Option<Duration>, // Zero when an existing connection is reused
+    pub ttfb: Option<Duration>,    // From sending the request until the response head
+    pub host: Option<String>,      // The name of the backend matched, as configured
+    pub path_prefix: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct AccessLogEntry {
+    client: String,
+    connection_id: Option<u64>, // The client connection, as forwarded in X-Proxy-Connection-Id
+    method: String,
+    host: String,
+    path: String,
+    status: u16,
+    duration_ms: f64, // Until the response head
+    location: Option<String>,
+    upstream_connection: Option<&'static str>,
+    routing_ms: Option<f64>,
+    connect_ms: Option<f64>,
+    ttfb_ms: Option<f64>,
+    transfer_ms: Option<f64>, // Relaying the response body to the client
+}
+
+fn milliseconds(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+pub struct AccessLog {
+    client_addr: SocketAddr,
+    connection_id: Option<u64>,
+    method: Method,
+    host: String,
+    path: String,
+    start: Instant,
+}
+
+impl AccessLog {
+    pub fn start(req: &Request<Body>, client_addr: SocketAddr) -> AccessLog {
+        AccessLog {
+            client_addr,
+            connection_id: req
+                .extensions()
+                .get::<ConnectionId>()
+                .map(|connection_id| connection_id.0),
+            method: req.method().clone(),
+            host: req
+                .headers()
+                .get("host")
+                .and_then(|host| host.to_str().ok())
+                .or_else(|| req.uri().authority().map(|authority| authority.as_str()))
+                .unwrap_or_default()
+                .to_string(),
+            path: req.uri().path().to_string(),
+            start: Instant::now(),
+        }
+    }
+
+    fn entry(&self, response: &Response<Body>) -> AccessLogEntry {
+        let context = response.extensions().get::<ResponseContext>();
+        AccessLogEntry {
+            client: self.client_addr.to_string(),
+            connection_id: self.connection_id,
+            method: self.method.to_string(),
+            host: self.host.clone(),
+            path: self.path.clone(),
+            status: response.status().as_u16(),
+            duration_ms: milliseconds(self.start.elapsed()),
+            location: context.and_then(|context| context.location.clone()),
+            upstream_connection: context
+                .and_then(|context| context.upstream_connection)
+                .map(|upstream_connection| upstream_connection.as_str()),
+            routing_ms: context
+                .and_then(|context| context.routing)
+                .map(milliseconds),
+            connect_ms: context
+                .and_then(|context| context.connect)
+                .map(milliseconds),
+            ttfb_ms: context.and_then(|context| context.ttfb).map(milliseconds),
+            transfer_ms: None,
+        }
+    }
+
+    pub fn log(&self, response: Response<Body>) -> Response<Body> {
+        self.log_with(response, |line| info!(target: "access_log", "{}", line))
+    }
+
+    pub fn log_with<F>(&self, response: Response<Body>, emit: F) -> Response<Body>
+    where
+        F: FnOnce(String) + Send + 'static,
+    {
+        // Logged once the body has been relayed, so that the transfer time is known
+        let mut entry = self.entry(&response);
+        if response.body().is_end_stream() {
+            entry.transfer_ms = Some(0.0);
+            emit(encode(&entry));
+            return response;
+        }
+        let transfer = TransferLog {
+            entry,
+            start: Instant::now(),
+            emit: Some(Box::new(emit)),
+        };
+        response.map(|body| body_with_guard(body, transfer))
+    }
+}
+
+fn encode(entry: &AccessLogEntry) -> String {
+    serde_json::to_string(entry).unwrap_or_else(|e| {
+        warn!("Error encoding access log: {e}");
+        String::new()
+    })
+}
+
+struct TransferLog {
+    entry: AccessLogEntry,
+    start: Instant,
+    emit: Option<Box<dyn FnOnce(String) + Send>>,
+}
+
+impl Drop for TransferLog {
+    fn drop(&mut self) {
+        // The body was relayed, or the client went away
+        self.entry.transfer_ms = Some(milliseconds(self.start.elapsed()));
+        if let Some(emit) = self.emit.take() {
+            emit(encode(&self.entry));
+        }
+    }
+}
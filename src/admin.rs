@@ -0,0 +1,374 @@
+// Admin server, serving the internal endpoints on a separate listener
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::Error;
+use axum::{
+    extract::Extension,
+    http::{header, HeaderValue, Method, Request, Response, StatusCode},
+    routing::get,
+    Router,
+};
+use axum_server::Handle;
+use hyper::Body;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::reload::Reloader;
+use super::tls::{rustls_config, TlsConfig};
+use super::{error_handler, metrics_handler, status_handler, ProxyConfig};
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct AdminConfig {
+    pub listen: SocketAddr,
+    pub tls: Option<AdminTlsConfig>,
+    pub auth: Option<AdminAuthConfig>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct AdminTlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    // Client certificates are required and verified against this CA when set
+    pub client_ca_path: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct AdminAuthConfig {
+    pub token: String,
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    // Compare secrets without returning early on the first mismatching byte
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub fn bearer_token_valid(req: &Request<Body>, token: &str) -> bool {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|provided| constant_time_eq(provided.as_bytes(), token.as_bytes()))
+}
+
+async fn admin_handler(
+    Extension(admin_config): Extension<Arc<AdminConfig>>,
+    Extension(reloader): Extension<Reloader>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let mut response = Response::new(Body::empty());
+
+    if let Some(auth) = &admin_config.auth {
+        if !bearer_token_valid(&req, &auth.token) {
+            *response.status_mut() = StatusCode::UNAUTHORIZED;
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            return Ok(response);
+        }
+    }
+
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/status") => response = status_handler(response),
+        (&Method::GET, "/metrics") => response = metrics_handler(response),
+        (&Method::GET, "/admin/connections") => {
+            response = connections_handler(response, &reloader.proxy_config())
+        }
+        (&Method::POST, "/reload") => match reloader.reload().await {
+            Ok(()) => *response.body_mut() = Body::from("Config reloaded"),
+            Err(e) => {
+                warn!("Error reloading config: {e}");
+                response = error_handler(
+                    response,
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Error reloading config: {e}"),
+                );
+            }
+        },
+        _ => *response.status_mut() = StatusCode::NOT_FOUND,
+    }
+    Ok(response)
+}
+
+fn connections_handler(mut response: Response<Body>, proxy_config: &ProxyConfig) -> Response<Body> {
+    // Upstream connections of the current client, per backend location
+    match serde_json::to_string(&proxy_config.client.connections()) {
+        Ok(body) => {
+            response.headers_mut().insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            );
+            *response.body_mut() = Body::from(body);
+            response
+        }
+        Err(e) => error_handler(
+            response,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error encoding connections: {e}"),
+        ),
+    }
+}
+
+pub async fn run_admin_server(
+    admin_config: AdminConfig,
+    reloader: Reloader,
+    handle: Handle,
+) -> Result<(), Error> {
+    let listen_address = admin_config.listen;
+    let tls = admin_config.tls.clone();
+
+    let app = Router::new()
+        .route("/*path", get(admin_handler).post(admin_handler))
+        .layer(Extension(Arc::new(admin_config)))
+        .layer(Extension(reloader));
+
+    match tls {
+        Some(tls) => {
+            // Loaded the same way as the proxy listener's default certificate and client CA
+            let tls_config = rustls_config(&TlsConfig {
+                cert_path: Some(tls.cert_path),
+                key_path: Some(tls.key_path),
+                client_ca_path: tls.client_ca_path,
+                ..Default::default()
+            })
+            .map_err(|e| Error::msg(format!("Admin TLS config error: {}", e)))?;
+
+            info!("Admin server listening on {} (TLS)", listen_address);
+            axum_server::bind_rustls(listen_address, tls_config)
+                .handle(handle)
+                .serve(app.into_make_service())
+                .await?;
+        }
+        None => {
+            info!("Admin server listening on {}", listen_address);
+            axum_server::bind(listen_address)
+                .handle(handle)
+                .serve(app.into_make_service())
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{read_proxy_config, Client, ProxyConfig, ProxyState};
+    use std::convert::TryFrom;
+    use std::sync::RwLock;
+
+    async fn test_reloader() -> Reloader {
+        let config_path = "tests/config.yaml".to_string();
+        let config = read_proxy_config(config_path.clone()).await.unwrap();
+        let client = Client::new(config.timeouts(), &config.client).unwrap();
+        let proxy_state = Arc::new(RwLock::new(ProxyState::new(&config)));
+        let proxy_config = Arc::new(ProxyConfig::new(config, client).unwrap());
+        Reloader::new(
+            config_path,
+            Arc::new(RwLock::new(proxy_config)),
+            proxy_state,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+    }
+
+    #[tokio::test]
+    async fn test_admin_server_token() {
+        let handle = Handle::new();
+        tokio::spawn(run_admin_server(
+            AdminConfig {
+                listen: "127.0.0.1:0".parse().unwrap(),
+                tls: None,
+                auth: Some(AdminAuthConfig {
+                    token: "admin-secret".to_string(),
+                }),
+            },
+            test_reloader().await,
+            handle.clone(),
+        ));
+        let admin_address = handle.listening().await;
+        let client = hyper::Client::new();
+        let request = |token: Option<&str>| {
+            let mut builder = Request::builder().uri(format!("http://{}/status", admin_address));
+            if let Some(token) = token {
+                builder = builder.header(header::AUTHORIZATION, format!("Bearer {}", token));
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let response = client.request(request(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = client.request(request(Some("wrong"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = client.request(request(Some("admin-secret"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "The proxy is running");
+
+        let mut reload_request = request(Some("admin-secret"));
+        *reload_request.method_mut() = Method::POST;
+        *reload_request.uri_mut() = format!("http://{}/reload", admin_address).parse().unwrap();
+        let response = client.request(reload_request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_admin_connections() {
+        let mock_server = wiremock::MockServer::start().await;
+        wiremock::Mock::given(wiremock::matchers::method("GET"))
+            .respond_with(wiremock::ResponseTemplate::new(200).set_body_string("ok"))
+            .mount(&mock_server)
+            .await;
+        let handle = Handle::new();
+        let reloader = test_reloader().await;
+        tokio::spawn(run_admin_server(
+            AdminConfig {
+                listen: "127.0.0.1:0".parse().unwrap(),
+                tls: None,
+                auth: Some(AdminAuthConfig {
+                    token: "admin-secret".to_string(),
+                }),
+            },
+            reloader.clone(),
+            handle.clone(),
+        ));
+        let admin_address = handle.listening().await;
+        let client = hyper::Client::new();
+        let connections = || async {
+            let request = Request::builder()
+                .uri(format!("http://{}/admin/connections", admin_address))
+                .header(header::AUTHORIZATION, "Bearer admin-secret")
+                .body(Body::empty())
+                .unwrap();
+            let response = client.request(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+        let location = mock_server.address().to_string();
+        assert!(connections().await.get(&location).is_none());
+
+        let mut request = Request::new(Body::empty());
+        *request.uri_mut() = format!("{}/", mock_server.uri()).parse().unwrap();
+        let response = reloader
+            .proxy_config()
+            .client
+            .make_request(request, true)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        // Active while the body is being received, then idle in the pool
+        let counts = connections().await;
+        assert_eq!(counts[&location]["open"], 1);
+        assert_eq!(counts[&location]["active"], 1);
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let counts = connections().await;
+        assert_eq!(counts[&location]["open"], 1);
+        assert_eq!(counts[&location]["idle"], 1);
+
+        // Authentication is required
+        let request = Request::builder()
+            .uri(format!("http://{}/admin/connections", admin_address))
+            .body(Body::empty())
+            .unwrap();
+        let response = client.request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_admin_server_client_cert() {
+        let mut ca_params = rcgen::CertificateParams::new(vec![]);
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(ca_params).unwrap();
+        let server = rcgen::Certificate::from_params(rcgen::CertificateParams::new(vec![
+            "admin.home".to_string(),
+        ]))
+        .unwrap();
+        let client = rcgen::Certificate::from_params(rcgen::CertificateParams::new(vec![
+            "operator.home".to_string(),
+        ]))
+        .unwrap();
+        let temp_path = |name: &str, contents: String| {
+            let path = std::env::temp_dir().join(name);
+            std::fs::write(&path, contents).unwrap();
+            path.to_string_lossy().to_string()
+        };
+        let handle = Handle::new();
+        tokio::spawn(run_admin_server(
+            AdminConfig {
+                listen: "127.0.0.1:0".parse().unwrap(),
+                tls: Some(AdminTlsConfig {
+                    cert_path: temp_path(
+                        "brachyura_test_admin_cert.crt",
+                        server.serialize_pem_with_signer(&ca).unwrap(),
+                    ),
+                    key_path: temp_path(
+                        "brachyura_test_admin_cert.key",
+                        server.serialize_private_key_pem(),
+                    ),
+                    client_ca_path: Some(temp_path(
+                        "brachyura_test_admin_cert_ca.pem",
+                        ca.serialize_pem().unwrap(),
+                    )),
+                }),
+                auth: None,
+            },
+            test_reloader().await,
+            handle.clone(),
+        ));
+        let admin_address = handle.listening().await;
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots
+            .add(&rustls::Certificate(ca.serialize_der().unwrap()))
+            .unwrap();
+        let status = |client_cert: Option<(Vec<u8>, Vec<u8>)>| {
+            let builder = rustls::ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots.clone());
+            let client_config = match client_cert {
+                Some((cert, key)) => builder
+                    .with_single_cert(vec![rustls::Certificate(cert)], rustls::PrivateKey(key))
+                    .unwrap(),
+                None => builder.with_no_client_auth(),
+            };
+            async move {
+                let tcp = tokio::net::TcpStream::connect(admin_address).await.unwrap();
+                let stream = tokio_rustls::TlsConnector::from(Arc::new(client_config))
+                    .connect(rustls::ServerName::try_from("admin.home").unwrap(), tcp)
+                    .await?;
+                let (mut sender, connection) = hyper::client::conn::handshake(stream).await?;
+                tokio::spawn(connection);
+                let request = Request::builder()
+                    .uri("/status")
+                    .header(header::HOST, "admin.home")
+                    .body(Body::empty())
+                    .unwrap();
+                Ok::<_, Error>(sender.send_request(request).await?.status())
+            }
+        };
+
+        let client_cert = (
+            client.serialize_der_with_signer(&ca).unwrap(),
+            client.serialize_private_key_der(),
+        );
+        assert_eq!(status(Some(client_cert)).await.unwrap(), StatusCode::OK);
+        // Connections without a certificate from the CA are refused
+        assert!(status(None).await.is_err());
+        let untrusted = (
+            client.serialize_der().unwrap(),
+            client.serialize_private_key_der(),
+        );
+        assert!(status(Some(untrusted)).await.is_err());
+    }
+}
@@ -0,0 +1,217 @@
+// Verification of client JWTs, so that requests can be routed by their claims
+use std::collections::HashMap;
+
+use anyhow::Error;
+use hyper::http::{header, Request};
+use hyper::Body;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub type Claims = serde_json::Map<String, Value>;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnInvalidToken {
+    // Requests with a missing or invalid token are routed to the host's default backend
+    #[default]
+    Default,
+    // Or rejected with a 401
+    Reject,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct JwtConfig {
+    secret: Option<String>, // Verifies HMAC (HS256, HS384 and HS512) signed tokens
+    jwks_path: Option<String>, // JWKS file of the public keys verifying other tokens
+    pub on_invalid: Option<OnInvalidToken>,
+}
+
+pub struct JwtVerifier {
+    secret: Option<DecodingKey>,
+    jwks: Option<JwkSet>,
+}
+
+const HMAC_ALGORITHMS: [Algorithm; 3] = [Algorithm::HS256, Algorithm::HS384, Algorithm::HS512];
+
+impl JwtVerifier {
+    pub fn load(config: &JwtConfig) -> Result<JwtVerifier, Error> {
+        let jwks = match &config.jwks_path {
+            Some(jwks_path) => {
+                let jwks = std::fs::read(jwks_path).map_err(|e| {
+                    Error::msg(format!("Unable to read jwks_path {}: {}", jwks_path, e))
+                })?;
+                Some(serde_json::from_slice(&jwks)?)
+            }
+            None => None,
+        };
+        Ok(JwtVerifier {
+            secret: config
+                .secret
+                .as_ref()
+                .map(|secret| DecodingKey::from_secret(secret.as_bytes())),
+            jwks,
+        })
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, Error> {
+        // The signature and expiry are verified, with only the algorithms valid for the key
+        let token_header = decode_header(token)?;
+        let (key, algorithms) = if HMAC_ALGORITHMS.contains(&token_header.alg) {
+            let secret = self
+                .secret
+                .clone()
+                .ok_or_else(|| Error::msg("No secret configured for HMAC tokens"))?;
+            (secret, HMAC_ALGORITHMS.to_vec())
+        } else {
+            let jwks = self
+                .jwks
+                .as_ref()
+                .ok_or_else(|| Error::msg("No JWKS configured"))?;
+            // Without a key ID a JWKS of a single key is used
+            let jwk = match &token_header.kid {
+                Some(kid) => jwks.find(kid),
+                None if jwks.keys.len() == 1 => jwks.keys.first(),
+                None => None,
+            }
+            .ok_or_else(|| Error::msg("No matching key in the JWKS"))?;
+            let algorithm = jwk.common.algorithm.unwrap_or(token_header.alg);
+            if HMAC_ALGORITHMS.contains(&algorithm) {
+                return Err(Error::msg("JWKS keys must be public keys"));
+            }
+            (DecodingKey::from_jwk(jwk)?, vec![algorithm])
+        };
+        let mut validation = Validation::new(token_header.alg);
+        validation.algorithms = algorithms;
+        Ok(decode::<Claims>(token, &key, &validation)?.claims)
+    }
+}
+
+pub fn bearer_token(req: &Request<Body>) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+pub fn claims_match(match_claims: &HashMap<String, String>, claims: &Claims) -> bool {
+    // Claims which aren't strings are compared as JSON, e.g. true or 3
+    match_claims
+        .iter()
+        .all(|(name, expected)| match claims.get(name) {
+            Some(Value::String(value)) => value == expected,
+            Some(value) => {
+                serde_json::from_str::<Value>(expected).is_ok_and(|expected| expected == *value)
+            }
+            None => false,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn expiry() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 60
+    }
+
+    #[tokio::test]
+    async fn test_verify_secret() {
+        let verifier = JwtVerifier::load(&JwtConfig {
+            secret: Some("secret".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        let claims = json!({"tier": "premium", "exp": expiry()});
+        let token = encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        assert_eq!(verifier.verify(&token).unwrap()["tier"], "premium");
+
+        let token = encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"wrong"),
+        )
+        .unwrap();
+        assert!(verifier.verify(&token).is_err());
+
+        // Expired
+        let claims = json!({"tier": "premium", "exp": expiry() - 3600});
+        let token = encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        assert!(verifier.verify(&token).is_err());
+        assert!(verifier.verify("not a token").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_verify_jwks() {
+        let key_pair = rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        // The raw public key is the uncompressed point, 0x04 followed by x and y
+        let public_key = key_pair.public_key_raw();
+        let jwks = json!({"keys": [{
+            "kty": "EC",
+            "crv": "P-256",
+            "kid": "test",
+            "x": base64::encode_config(&public_key[1..33], base64::URL_SAFE_NO_PAD),
+            "y": base64::encode_config(&public_key[33..], base64::URL_SAFE_NO_PAD),
+        }]});
+        let jwks_path = std::env::temp_dir().join("brachyura_test_jwks.json");
+        std::fs::write(&jwks_path, jwks.to_string()).unwrap();
+        let verifier = JwtVerifier::load(&JwtConfig {
+            jwks_path: Some(jwks_path.to_str().unwrap().to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some("test".to_string());
+        let encoding_key = EncodingKey::from_ec_pem(key_pair.serialize_pem().as_bytes()).unwrap();
+        let claims = json!({"tier": "premium", "exp": expiry()});
+        let token = encode(&header, &claims, &encoding_key).unwrap();
+        assert_eq!(verifier.verify(&token).unwrap()["tier"], "premium");
+
+        // HMAC tokens aren't verified without a secret
+        let token = encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        assert!(verifier.verify(&token).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_claims_match() {
+        let claims: Claims =
+            serde_json::from_value(json!({"tier": "premium", "level": 3})).unwrap();
+        let match_claims = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<HashMap<String, String>>()
+        };
+        assert!(claims_match(&match_claims(&[("tier", "premium")]), &claims));
+        assert!(claims_match(
+            &match_claims(&[("tier", "premium"), ("level", "3")]),
+            &claims
+        ));
+        assert!(!claims_match(&match_claims(&[("tier", "basic")]), &claims));
+        assert!(!claims_match(&match_claims(&[("region", "eu")]), &claims));
+    }
+}
@@ -0,0 +1,84 @@
+// Body wrappers shared by the proxy, e.g. to hold state until a body has been relayed
+use futures::StreamExt;
+use hyper::body::{Bytes, HttpBody};
+use hyper::Body;
+
+pub fn body_with_guard<G: Send + 'static>(body: Body, guard: G) -> Body {
+    // The guard is dropped once the body has been streamed, or the body is dropped unread
+    inspect_body_with_guard(body, guard, |_, _| {})
+}
+
+pub fn inspect_body_with_guard<G, F>(body: Body, mut guard: G, mut inspect: F) -> Body
+where
+    G: Send + 'static,
+    F: FnMut(&mut G, &Bytes) + Send + 'static,
+{
+    // As body_with_guard, with the guard also given each chunk as it is streamed. A body
+    // already at its end is returned as it is, keeping its size hint and is_end_stream, with
+    // the guard dropped at once. hyper's wrap_stream can't carry the size hint of other bodies,
+    // their Content-Length header still gives the length to clients
+    if body.is_end_stream() {
+        return body;
+    }
+    Body::wrap_stream(body.map(move |chunk| {
+        if let Ok(chunk) = &chunk {
+            inspect(&mut guard, chunk);
+        }
+        chunk
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_body_with_guard() {
+        // Held until the body has been streamed
+        let dropped = Arc::new(AtomicBool::new(false));
+        let body = body_with_guard(Body::from("streamed"), DropFlag(dropped.clone()));
+        assert!(!dropped.load(Ordering::SeqCst));
+        let bytes = hyper::body::to_bytes(body).await.unwrap();
+        assert_eq!(bytes, "streamed");
+        assert!(dropped.load(Ordering::SeqCst));
+
+        // Or the body is dropped unread
+        let dropped = Arc::new(AtomicBool::new(false));
+        let body = body_with_guard(Body::from("unread"), DropFlag(dropped.clone()));
+        drop(body);
+        assert!(dropped.load(Ordering::SeqCst));
+
+        // An empty body isn't wrapped
+        let dropped = Arc::new(AtomicBool::new(false));
+        let body = body_with_guard(Body::empty(), DropFlag(dropped.clone()));
+        assert!(dropped.load(Ordering::SeqCst));
+        assert!(body.is_end_stream());
+        assert_eq!(body.size_hint().exact(), Some(0));
+
+        // The guard is given each chunk
+        let streamed = Arc::new(AtomicUsize::new(0));
+        let chunks = futures::stream::iter(vec![
+            Ok::<_, std::io::Error>(Bytes::from("ab")),
+            Ok(Bytes::from("cde")),
+        ]);
+        let body = inspect_body_with_guard(
+            Body::wrap_stream(chunks),
+            streamed.clone(),
+            |streamed, chunk| {
+                streamed.fetch_add(chunk.len(), Ordering::SeqCst);
+            },
+        );
+        hyper::body::to_bytes(body).await.unwrap();
+        assert_eq!(streamed.load(Ordering::SeqCst), 5);
+    }
+}
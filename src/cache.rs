@@ -0,0 +1,402 @@
+// Caching of backend responses
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::stream::{self, StreamExt};
+use hyper::body::{Bytes, HttpBody};
+use hyper::http::header::{AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, COOKIE, SET_COOKIE, VARY};
+use hyper::http::{HeaderMap, Response, StatusCode};
+use hyper::Body;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::metrics::METRICS;
+
+const DEFAULT_MAX_ENTRIES: usize = 1024;
+const DEFAULT_MAX_BODY_BYTES: u64 = 1024 * 1024;
+
+#[derive(Debug, Default, Eq, PartialEq, Serialize, Deserialize, Clone)]
+pub struct StaleIfErrorConfig {
+    pub max_stale: u64, // Seconds a cached response may be served for after it was stored
+    pub max_entries: Option<usize>, // The least recently used response is evicted, defaults to 1024
+    pub max_body_bytes: Option<u64>, // Larger responses are streamed uncached, defaults to 1MiB
+}
+
+struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored_at: Instant,
+    last_used: u64, // Use counter value when last used, the lowest entry is evicted
+}
+
+impl CachedResponse {
+    fn to_response(&self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        response
+    }
+}
+
+#[derive(Default)]
+struct CachedResponses {
+    responses: HashMap<String, CachedResponse>,
+    uses: u64,
+}
+
+pub struct ResponseCache {
+    // The last successful response for a given cache key, of a backend
+    max_stale: Duration,
+    max_entries: usize,
+    max_body_bytes: u64,
+    cached: Mutex<CachedResponses>,
+}
+
+impl ResponseCache {
+    pub fn new(config: &StaleIfErrorConfig) -> ResponseCache {
+        ResponseCache {
+            max_stale: Duration::from_secs(config.max_stale),
+            max_entries: config.max_entries.unwrap_or(DEFAULT_MAX_ENTRIES),
+            max_body_bytes: config.max_body_bytes.unwrap_or(DEFAULT_MAX_BODY_BYTES),
+            cached: Mutex::new(CachedResponses::default()),
+        }
+    }
+
+    pub async fn store(
+        &self,
+        key: String,
+        response: Response<Body>,
+    ) -> Result<Response<Body>, hyper::Error> {
+        // The body has to be buffered in order to cache it, the buffered copy is then returned
+        // in place of the original response. A body over max_body_bytes isn't cached, what has
+        // been buffered is streamed followed by the rest of the body
+        let content_length = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        if !cacheable_response(response.headers())
+            || content_length.is_some_and(|length| length > self.max_body_bytes)
+        {
+            return Ok(response);
+        }
+        let (parts, mut body) = response.into_parts();
+        let mut chunks = Vec::new();
+        let mut buffered = 0;
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            buffered += chunk.len() as u64;
+            chunks.push(chunk);
+            if buffered > self.max_body_bytes {
+                let streamed = stream::iter(chunks.into_iter().map(Ok)).chain(body);
+                return Ok(Response::from_parts(parts, Body::wrap_stream(streamed)));
+            }
+        }
+        let body = Bytes::from(chunks.concat());
+        self.insert(
+            key,
+            CachedResponse {
+                status: parts.status,
+                headers: parts.headers.clone(),
+                body: body.clone(),
+                stored_at: Instant::now(),
+                last_used: 0,
+            },
+        );
+        Ok(Response::from_parts(parts, Body::from(body)))
+    }
+
+    fn insert(&self, key: String, mut response: CachedResponse) {
+        if self.max_entries == 0 {
+            return;
+        }
+        let mut cached = self.cached.lock().unwrap();
+        if !cached.responses.contains_key(&key) && cached.responses.len() >= self.max_entries {
+            // Responses past max_stale are evicted before the least recently used
+            let max_stale = self.max_stale;
+            cached
+                .responses
+                .retain(|_, response| response.stored_at.elapsed() <= max_stale);
+            if cached.responses.len() >= self.max_entries {
+                let least_recently_used = cached
+                    .responses
+                    .iter()
+                    .min_by_key(|(_, response)| response.last_used)
+                    .map(|(key, _)| key.clone());
+                if let Some(least_recently_used) = least_recently_used {
+                    cached.responses.remove(&least_recently_used);
+                }
+            }
+        }
+        cached.uses += 1;
+        response.last_used = cached.uses;
+        cached.responses.insert(key, response);
+    }
+
+    pub fn get_stale(&self, key: &str) -> Option<Response<Body>> {
+        // A response past max_stale can't be served again, so is removed
+        let mut cached = self.cached.lock().unwrap();
+        if cached.responses.get(key)?.stored_at.elapsed() > self.max_stale {
+            cached.responses.remove(key);
+            return None;
+        }
+        cached.uses += 1;
+        let uses = cached.uses;
+        let response = cached.responses.get_mut(key)?;
+        response.last_used = uses;
+        Some(response.to_response())
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.cached.lock().unwrap().responses.len()
+    }
+}
+
+pub fn cacheable_request(headers: &HeaderMap) -> bool {
+    // The cache is shared by all clients, so responses to credentialed requests aren't cached
+    // (nor served a cached response, which may not be what the client is authorized for)
+    !headers.contains_key(AUTHORIZATION) && !headers.contains_key(COOKIE)
+}
+
+fn cacheable_response(headers: &HeaderMap) -> bool {
+    // Responses which are private to a client, set cookies or vary by request headers can't be
+    // served to every client
+    let private = headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.split('=').next().unwrap_or_default().trim())
+        .any(|directive| {
+            directive.eq_ignore_ascii_case("private") || directive.eq_ignore_ascii_case("no-store")
+        });
+    !private && !headers.contains_key(SET_COOKIE) && !headers.contains_key(VARY)
+}
+
+pub async fn stale_if_error(
+    cache: &ResponseCache,
+    key: String,
+    response: Response<Body>,
+    backend: &str,
+) -> Response<Body> {
+    // Cache successful responses, and replace backend errors with the
+    // last successful response (provided it is not too stale)
+    if response.status().is_success() {
+        match cache.store(key.clone(), response).await {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Error reading backend response body: {e}");
+                record_lookup(cache.get_stale(&key), backend).unwrap_or_else(|| {
+                    let mut response = Response::new(Body::from("Error reading backend response"));
+                    *response.status_mut() = StatusCode::BAD_GATEWAY;
+                    response
+                })
+            }
+        }
+    } else if response.status().is_server_error() {
+        match record_lookup(cache.get_stale(&key), backend) {
+            Some(stale) => {
+                debug!(
+                    "Serving stale response for: {} | Backend status: {}",
+                    key,
+                    response.status()
+                );
+                stale
+            }
+            None => response,
+        }
+    } else {
+        response
+    }
+}
+
+fn record_lookup(stale: Option<Response<Body>>, backend: &str) -> Option<Response<Body>> {
+    let counter = match stale {
+        Some(_) => &METRICS.cache_hits,
+        None => &METRICS.cache_misses,
+    };
+    counter.with_label_values(&[backend]).inc();
+    stale
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::IntCounterVec;
+
+    fn test_cache(max_stale: u64) -> ResponseCache {
+        ResponseCache::new(&StaleIfErrorConfig {
+            max_stale,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_response_cache_get_stale() {
+        let cache = test_cache(60);
+        let response = Response::new(Body::from("cached"));
+        let response = cache.store("key".to_string(), response).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "cached");
+
+        let stale = cache.get_stale("key").unwrap();
+        assert_eq!(stale.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(stale.into_body()).await.unwrap();
+        assert_eq!(body, "cached");
+        assert!(cache.get_stale("missing").is_none());
+
+        // A response past max_stale is removed
+        let cache = ResponseCache {
+            max_stale: Duration::from_millis(20),
+            ..test_cache(0)
+        };
+        let response = Response::new(Body::from("cached"));
+        cache.store("key".to_string(), response).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        assert!(cache.get_stale("key").is_none());
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_response_cache_max_entries() {
+        let cache = ResponseCache::new(&StaleIfErrorConfig {
+            max_stale: 60,
+            max_entries: Some(2),
+            ..Default::default()
+        });
+        for key in ["a", "b"] {
+            let response = Response::new(Body::from(key));
+            cache.store(key.to_string(), response).await.unwrap();
+        }
+        // a is used more recently than b, so b is evicted
+        assert!(cache.get_stale("a").is_some());
+        cache
+            .store("c".to_string(), Response::new(Body::from("c")))
+            .await
+            .unwrap();
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get_stale("a").is_some());
+        assert!(cache.get_stale("b").is_none());
+        assert!(cache.get_stale("c").is_some());
+
+        // Responses past max_stale are evicted first
+        let cache = ResponseCache {
+            max_stale: Duration::from_millis(20),
+            ..ResponseCache::new(&StaleIfErrorConfig {
+                max_entries: Some(2),
+                ..Default::default()
+            })
+        };
+        for key in ["a", "b"] {
+            let response = Response::new(Body::from(key));
+            cache.store(key.to_string(), response).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        let response = Response::new(Body::from("c"));
+        cache.store("c".to_string(), response).await.unwrap();
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_response_cache_max_body_bytes() {
+        let cache = ResponseCache::new(&StaleIfErrorConfig {
+            max_stale: 60,
+            max_body_bytes: Some(4),
+            ..Default::default()
+        });
+
+        // Over max_body_bytes by its Content-Length
+        let response = Response::builder()
+            .header(CONTENT_LENGTH, "5")
+            .body(Body::from("large"))
+            .unwrap();
+        let response = cache.store("length".to_string(), response).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "large");
+        assert!(cache.get_stale("length").is_none());
+
+        // Or once streamed, when the length isn't known
+        let chunks = stream::iter(vec![
+            Ok::<_, std::io::Error>(Bytes::from("abc")),
+            Ok(Bytes::from("de")),
+            Ok(Bytes::from("fg")),
+        ]);
+        let response = Response::new(Body::wrap_stream(chunks));
+        let response = cache.store("streamed".to_string(), response).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "abcdefg");
+        assert!(cache.get_stale("streamed").is_none());
+
+        let response = Response::new(Body::from("fits"));
+        cache.store("fits".to_string(), response).await.unwrap();
+        assert!(cache.get_stale("fits").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_response_cache_uncacheable() {
+        let cache = test_cache(60);
+        let uncacheable = [
+            (CACHE_CONTROL, "private"),
+            (CACHE_CONTROL, "max-age=60, No-Store"),
+            (CACHE_CONTROL, "private=\"x-user\""),
+            (SET_COOKIE, "session=abc"),
+            (VARY, "accept-language"),
+        ];
+        for (name, value) in uncacheable {
+            let response = Response::builder()
+                .header(name.clone(), value)
+                .body(Body::from("uncacheable"))
+                .unwrap();
+            let response = cache.store("key".to_string(), response).await.unwrap();
+            assert_eq!(response.headers()[&name], value);
+            assert_eq!(
+                hyper::body::to_bytes(response.into_body()).await.unwrap(),
+                "uncacheable"
+            );
+            assert!(cache.get_stale("key").is_none());
+        }
+
+        let response = Response::builder()
+            .header(CACHE_CONTROL, "public, max-age=60")
+            .body(Body::from("cacheable"))
+            .unwrap();
+        cache.store("key".to_string(), response).await.unwrap();
+        assert!(cache.get_stale("key").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_cacheable_request() {
+        let mut headers = HeaderMap::new();
+        assert!(cacheable_request(&headers));
+        headers.insert(AUTHORIZATION, "Bearer abc".parse().unwrap());
+        assert!(!cacheable_request(&headers));
+        headers.clear();
+        headers.insert(COOKIE, "session=abc".parse().unwrap());
+        assert!(!cacheable_request(&headers));
+    }
+
+    #[tokio::test]
+    async fn test_stale_if_error_cache_hits() {
+        let count = |counter: &IntCounterVec| counter.with_label_values(&["hits.home"]).get();
+        let cache = test_cache(60);
+        let error = || {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::BAD_GATEWAY;
+            response
+        };
+
+        let response = stale_if_error(&cache, "key".to_string(), error(), "hits.home").await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(count(&METRICS.cache_misses), 1);
+
+        let fresh = Response::new(Body::from("fresh"));
+        stale_if_error(&cache, "key".to_string(), fresh, "hits.home").await;
+        let response = stale_if_error(&cache, "key".to_string(), error(), "hits.home").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(count(&METRICS.cache_hits), 1);
+        assert_eq!(count(&METRICS.cache_misses), 1);
+    }
+}
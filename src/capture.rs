@@ -0,0 +1,248 @@
+// Capture of request and response bodies for auditing, written to a file or HTTP sink by a
+// background task so that the client path isn't delayed
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Error;
+use futures::StreamExt;
+use hyper::body::HttpBody;
+use hyper::http::{header, uri::Scheme, Request, StatusCode};
+use hyper::{Body, Uri};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+use super::Backend;
+
+const DEFAULT_MAX_BODY_BYTES: usize = 65536;
+// Captures waiting to be written, further captures are dropped while the sink is behind
+const CAPTURE_QUEUE: usize = 1024;
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Clone)]
+pub struct CaptureConfig {
+    file: Option<String>, // A JSON line per exchange is appended to the file
+    url: Option<String>,  // Or each exchange is POSTed as JSON to an http:// endpoint
+    max_body_bytes: Option<usize>, // Bodies are truncated beyond this, defaults to 65536
+}
+
+enum Sink {
+    File(String),
+    Http(Uri),
+}
+
+#[derive(Debug, Serialize)]
+struct CaptureRecord {
+    backend: String,
+    method: String,
+    uri: String,
+    status: Option<u16>,
+    request_body: String, // Base64 encoded
+    request_truncated: bool,
+    response_body: String,
+    response_truncated: bool,
+}
+
+pub struct Capture {
+    backend: String,
+    max_body_bytes: usize,
+    sender: mpsc::Sender<CaptureRecord>,
+}
+
+impl Capture {
+    pub fn new(backend: &str, config: &CaptureConfig) -> Result<Capture, Error> {
+        let sink = match (&config.file, &config.url) {
+            (Some(file), None) => Sink::File(file.clone()),
+            (None, Some(url)) => {
+                let uri: Uri = url.parse()?;
+                if uri.scheme() != Some(&Scheme::HTTP) {
+                    return Err(Error::msg(format!(
+                        "Invalid capture url {}, only http sinks are supported",
+                        url
+                    )));
+                }
+                Sink::Http(uri)
+            }
+            _ => {
+                return Err(Error::msg(format!(
+                    "Capture for backend {} requires either a file or a url",
+                    backend
+                )))
+            }
+        };
+        let (sender, receiver) = mpsc::channel(CAPTURE_QUEUE);
+        tokio::spawn(write_captures(sink, receiver));
+        Ok(Capture {
+            backend: backend.to_string(),
+            max_body_bytes: config.max_body_bytes.unwrap_or(DEFAULT_MAX_BODY_BYTES),
+            sender,
+        })
+    }
+
+    pub fn start(&self, req: &Request<Body>) -> Arc<Exchange> {
+        Arc::new(Exchange {
+            captured: Mutex::new(Captured {
+                backend: self.backend.clone(),
+                method: req.method().to_string(),
+                uri: req.uri().to_string(),
+                ..Default::default()
+            }),
+            max_body_bytes: self.max_body_bytes,
+            sender: self.sender.clone(),
+        })
+    }
+}
+
+pub fn load_captures(backends: &[Backend]) -> Result<HashMap<String, Capture>, Error> {
+    let mut captures = HashMap::new();
+    for backend in backends {
+        if let (Some(name), Some(capture)) = (&backend.name, &backend.capture) {
+            captures.insert(name.clone(), Capture::new(name, capture)?);
+        }
+    }
+    Ok(captures)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+    Request,
+    Response,
+}
+
+#[derive(Default)]
+struct CapturedBody {
+    bytes: Vec<u8>,
+    truncated: bool,
+}
+
+impl CapturedBody {
+    fn append(&mut self, chunk: &[u8], max_body_bytes: usize) {
+        let remaining = max_body_bytes.saturating_sub(self.bytes.len());
+        if chunk.len() > remaining {
+            self.truncated = true;
+        }
+        self.bytes
+            .extend_from_slice(&chunk[..chunk.len().min(remaining)]);
+    }
+}
+
+#[derive(Default)]
+struct Captured {
+    backend: String,
+    method: String,
+    uri: String,
+    status: Option<u16>,
+    request: CapturedBody,
+    response: CapturedBody,
+}
+
+pub struct Exchange {
+    // Shared by the teed request and response bodies, queued for the sink once both are dropped
+    captured: Mutex<Captured>,
+    max_body_bytes: usize,
+    sender: mpsc::Sender<CaptureRecord>,
+}
+
+impl Exchange {
+    pub fn set_status(&self, status: StatusCode) {
+        self.captured.lock().unwrap().status = Some(status.as_u16());
+    }
+
+    pub fn tee(self: &Arc<Self>, body: Body, direction: Direction) -> Body {
+        // Bodies known to be empty are left as is, so that they are still sent without one
+        if body.is_end_stream() {
+            return body;
+        }
+        let exchange = self.clone();
+        Body::wrap_stream(body.inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                let captured = &mut exchange.captured.lock().unwrap();
+                let captured_body = match direction {
+                    Direction::Request => &mut captured.request,
+                    Direction::Response => &mut captured.response,
+                };
+                captured_body.append(chunk, exchange.max_body_bytes);
+            }
+        }))
+    }
+}
+
+impl Drop for Exchange {
+    fn drop(&mut self) {
+        let captured = std::mem::take(self.captured.get_mut().unwrap());
+        let record = CaptureRecord {
+            backend: captured.backend,
+            method: captured.method,
+            uri: captured.uri,
+            status: captured.status,
+            request_body: base64::encode(captured.request.bytes),
+            request_truncated: captured.request.truncated,
+            response_body: base64::encode(captured.response.bytes),
+            response_truncated: captured.response.truncated,
+        };
+        if self.sender.try_send(record).is_err() {
+            warn!("Capture queue full, dropping capture");
+        }
+    }
+}
+
+async fn write_captures(sink: Sink, mut receiver: mpsc::Receiver<CaptureRecord>) {
+    // Runs until the config is replaced and every pending exchange is done
+    let client = hyper::Client::new();
+    while let Some(record) = receiver.recv().await {
+        let json = match serde_json::to_string(&record) {
+            Ok(json) => json,
+            Err(e) => {
+                warn!("Error encoding capture: {e}");
+                continue;
+            }
+        };
+        let result = match &sink {
+            Sink::File(path) => append_line(path, json).await,
+            Sink::Http(uri) => {
+                let request = Request::post(uri)
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(json))
+                    .expect("Unable to build capture request");
+                match client.request(request).await {
+                    Ok(response) if response.status().is_success() => Ok(()),
+                    Ok(response) => Err(Error::msg(format!(
+                        "Capture sink responded with {}",
+                        response.status()
+                    ))),
+                    Err(e) => Err(e.into()),
+                }
+            }
+        };
+        if let Err(e) = result {
+            warn!("Error writing capture: {e}");
+        }
+    }
+}
+
+async fn append_line(path: &str, mut line: String) -> Result<(), Error> {
+    // The file is reopened for each capture, so that it can be rotated
+    line.push('\n');
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(line.as_bytes()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_captured_body_truncated() {
+        let mut captured = CapturedBody::default();
+        captured.append(b"abc", 5);
+        assert!(!captured.truncated);
+        captured.append(b"def", 5);
+        assert_eq!(captured.bytes, b"abcde");
+        assert!(captured.truncated);
+    }
+}
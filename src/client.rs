@@ -1,63 +1,658 @@
-use hyper::{client::HttpConnector, http::StatusCode, Body, Request, Response};
-use log::info;
-use std::time::Duration;
+use super::access_log::ResponseContext;
+use super::body::body_with_guard;
+use super::connection::{
+    ConnectionCounts, ConnectionInfo, ConnectionRegistry, StreamingGuard, TrackedConnector,
+    UpstreamConnection,
+};
+use super::upstream_proxy::{ProxyConnector, UpstreamProxy, UpstreamProxyConfig};
+use anyhow::Error;
+use futures::future::BoxFuture;
+use hyper::body::HttpBody;
+use hyper::client::connect::dns::{GaiResolver, Name};
+use hyper::client::connect::{capture_connection, CaptureConnection};
+use hyper::http::{header, uri::Scheme, Version};
+use hyper::service::Service;
+use hyper::{client::HttpConnector, http::StatusCode, Body, Request, Response, Uri};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use log::{debug, info, warn};
+use rustls::{Certificate, OwnedTrustAnchor, RootCertStore};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use tokio::time::timeout;
-type HttpClient = hyper::client::Client<HttpConnector, Body>;
+type HttpClient = hyper::client::Client<
+    TrackedConnector<
+        HttpsConnector<
+            ProxyConnector<ConnectTimeout<HttpConnector<CachingResolver<FamilyResolver>>>>,
+        >,
+    >,
+    Body,
+>;
 
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+    #[default]
+    Auto,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClientConfig {
+    pub address_family: Option<AddressFamily>,
+    pub ca_path: Option<String>, // Additional CA certificates trusted for HTTPS backends
+    pub first_byte_timeout: Option<u64>, // Milliseconds to wait for the response head
+    pub upstream_proxy: Option<UpstreamProxyConfig>, // Connect to backends through an HTTP proxy
+    pub dns_cache: Option<DnsCacheConfig>, // Cache resolved backend addresses
+}
+
+const DEFAULT_REQUEST_TIMEOUT: u64 = 60_000;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    pub connect: Option<u64>, // Milliseconds, a slow to connect backend fails fast
+    pub request: Option<u64>, // Milliseconds for the whole request, defaults to 60000
+}
+
+const DEFAULT_DNS_CACHE_MAX_ENTRIES: usize = 1024;
+const DEFAULT_DNS_CACHE_TTL: u64 = 30_000;
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DnsCacheConfig {
+    pub max_entries: Option<usize>, // The least recently used entry is evicted, defaults to 1024
+    pub ttl: Option<u64>,           // Milliseconds an entry is used for, defaults to 30000
+}
+
+#[derive(Clone)]
+pub struct FamilyResolver<R = GaiResolver> {
+    // Wraps a resolver, keeping only the preferred address family when a name resolves to both
+    inner: R,
+    address_family: AddressFamily,
+}
+
+impl<R> FamilyResolver<R> {
+    pub fn new(inner: R, address_family: AddressFamily) -> FamilyResolver<R> {
+        FamilyResolver {
+            inner,
+            address_family,
+        }
+    }
+}
+
+impl<R> Service<Name> for FamilyResolver<R>
+where
+    R: Service<Name>,
+    R::Response: Iterator<Item = SocketAddr>,
+    R::Future: Send + 'static,
+{
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = R::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let resolving = self.inner.call(name);
+        let address_family = self.address_family;
+        Box::pin(async move {
+            let addrs = resolving.await?.collect();
+            Ok(filter_address_family(addrs, address_family).into_iter())
+        })
+    }
+}
+
+struct DnsCacheEntry {
+    addrs: Vec<SocketAddr>,
+    resolved: Instant,
+    last_used: u64, // Use counter value when last used, the lowest entry is evicted
+}
+
+pub struct DnsCache {
+    max_entries: usize,
+    ttl: Duration,
+    entries: HashMap<Name, DnsCacheEntry>,
+    uses: u64,
+}
+
+impl DnsCache {
+    pub fn new(config: &DnsCacheConfig) -> DnsCache {
+        DnsCache {
+            max_entries: config.max_entries.unwrap_or(DEFAULT_DNS_CACHE_MAX_ENTRIES),
+            ttl: Duration::from_millis(config.ttl.unwrap_or(DEFAULT_DNS_CACHE_TTL)),
+            entries: HashMap::new(),
+            uses: 0,
+        }
+    }
+
+    fn get(&mut self, name: &Name) -> Option<Vec<SocketAddr>> {
+        let ttl = self.ttl;
+        if self
+            .entries
+            .get(name)
+            .is_some_and(|entry| entry.resolved.elapsed() >= ttl)
+        {
+            self.entries.remove(name);
+            return None;
+        }
+        self.uses += 1;
+        let entry = self.entries.get_mut(name)?;
+        entry.last_used = self.uses;
+        Some(entry.addrs.clone())
+    }
+
+    fn insert(&mut self, name: Name, addrs: Vec<SocketAddr>) {
+        if self.max_entries == 0 {
+            return;
+        }
+        if !self.entries.contains_key(&name) && self.entries.len() >= self.max_entries {
+            // Expired entries are evicted before the least recently used
+            let ttl = self.ttl;
+            self.entries
+                .retain(|_, entry| entry.resolved.elapsed() < ttl);
+            if self.entries.len() >= self.max_entries {
+                let least_recently_used = self
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(name, _)| name.clone());
+                if let Some(least_recently_used) = least_recently_used {
+                    self.entries.remove(&least_recently_used);
+                }
+            }
+        }
+        self.uses += 1;
+        self.entries.insert(
+            name,
+            DnsCacheEntry {
+                addrs,
+                resolved: Instant::now(),
+                last_used: self.uses,
+            },
+        );
+    }
+}
+
+#[derive(Clone)]
+pub struct CachingResolver<R> {
+    // Wraps a resolver, answering from the cache (when configured) while an entry is fresh
+    inner: R,
+    cache: Option<Arc<Mutex<DnsCache>>>,
+}
+
+impl<R> CachingResolver<R> {
+    pub fn new(inner: R, cache: Option<DnsCache>) -> CachingResolver<R> {
+        CachingResolver {
+            inner,
+            cache: cache.map(|cache| Arc::new(Mutex::new(cache))),
+        }
+    }
+}
+
+impl<R> Service<Name> for CachingResolver<R>
+where
+    R: Service<Name>,
+    R::Response: Iterator<Item = SocketAddr>,
+    R::Error: Send + 'static,
+    R::Future: Send + 'static,
+{
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = R::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let cache = self.cache.clone();
+        if let Some(addrs) = cache
+            .as_ref()
+            .and_then(|cache| cache.lock().unwrap().get(&name))
+        {
+            return Box::pin(futures::future::ready(Ok(addrs.into_iter())));
+        }
+        let resolving = self.inner.call(name.clone());
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = resolving.await?.collect();
+            if let Some(cache) = cache {
+                cache.lock().unwrap().insert(name, addrs.clone());
+            }
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct ConnectTimeout<C> {
+    // Wraps a connector, failing a connection (including resolving its address) not made in time
+    inner: C,
+    timeout: Option<Duration>, // No timeout when None
+}
+
+impl<C> ConnectTimeout<C> {
+    pub fn new(inner: C, timeout: Option<Duration>) -> ConnectTimeout<C> {
+        ConnectTimeout { inner, timeout }
+    }
+}
+
+impl<C> Service<Uri> for ConnectTimeout<C>
+where
+    C: Service<Uri>,
+    C::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    C::Future: Send + 'static,
+{
+    type Response = C::Response;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connecting = self.inner.call(uri);
+        let connect_timeout = self.timeout;
+        Box::pin(async move {
+            match connect_timeout {
+                Some(connect_timeout) => match timeout(connect_timeout, connecting).await {
+                    Ok(connected) => connected.map_err(Into::into),
+                    Err(_) => Err("Backend connect timeout elapsed".into()),
+                },
+                None => connecting.await.map_err(Into::into),
+            }
+        })
+    }
+}
+
+fn filter_address_family(addrs: Vec<SocketAddr>, address_family: AddressFamily) -> Vec<SocketAddr> {
+    // If none of the addresses are of the preferred family, fall back to whatever was resolved
+    let preferred: Vec<SocketAddr> = match address_family {
+        AddressFamily::Auto => return addrs,
+        AddressFamily::Ipv4 => addrs
+            .iter()
+            .filter(|addr| addr.is_ipv4())
+            .copied()
+            .collect(),
+        AddressFamily::Ipv6 => addrs
+            .iter()
+            .filter(|addr| addr.is_ipv6())
+            .copied()
+            .collect(),
+    };
+    if preferred.is_empty() {
+        addrs
+    } else {
+        preferred
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendError {
+    // Added to the extensions of responses generated by the client when the backend request fails
+    Connect,
+    Timeout,
+    FirstByteTimeout,
+    Protocol, // The backend's response was rejected, e.g. for ambiguous framing
+    Other,
+}
+
+#[derive(Clone)]
 pub struct Client {
-    client: HttpClient,
-    timeout: Option<u64>,
+    // Clones share the connection pools and registry
+    client: HttpClient, // Negotiates HTTP/2 or HTTP/1.1 via ALPN with HTTPS backends
+    http1_client: HttpClient, // Only offers HTTP/1.1
+    connections: Arc<ConnectionRegistry>, // Shared by both clients
+    upstream_proxy: Option<UpstreamProxy>,
+    timeout: Duration,
+    first_byte_timeout: Option<u64>,
+}
+
+fn root_cert_store(ca_path: Option<&str>) -> Result<RootCertStore, Error> {
+    let mut root_cert_store = RootCertStore::empty();
+    root_cert_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    if let Some(ca_path) = ca_path {
+        let pem = std::fs::read(ca_path)
+            .map_err(|e| Error::msg(format!("Unable to read ca_path {}: {}", ca_path, e)))?;
+        for cert in rustls_pemfile::certs(&mut pem.as_slice())? {
+            root_cert_store.add(&Certificate(cert))?;
+        }
+    }
+    Ok(root_cert_store)
 }
 
 impl Client {
-    pub fn new(timeout: Option<u64>) -> Client {
-        let client = HttpClient::new();
-        Client { client, timeout }
+    pub fn new(timeouts: Timeouts, client_config: &ClientConfig) -> Result<Client, Error> {
+        let resolver = CachingResolver::new(
+            FamilyResolver::new(
+                GaiResolver::new(),
+                client_config.address_family.unwrap_or_default(),
+            ),
+            client_config.dns_cache.as_ref().map(DnsCache::new),
+        );
+        let mut http_connector = HttpConnector::new_with_resolver(resolver);
+        http_connector.enforce_http(false);
+        let http_connector =
+            ConnectTimeout::new(http_connector, timeouts.connect.map(Duration::from_millis));
+        let upstream_proxy = client_config
+            .upstream_proxy
+            .as_ref()
+            .map(UpstreamProxy::new)
+            .transpose()?;
+        let proxy_connector = ProxyConnector::new(http_connector, upstream_proxy.clone());
+        let tls_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(root_cert_store(client_config.ca_path.as_deref())?)
+            .with_no_client_auth();
+
+        let connector = HttpsConnectorBuilder::new()
+            .with_tls_config(tls_config.clone())
+            .https_or_http()
+            .enable_http1()
+            .enable_http2()
+            .wrap_connector(proxy_connector.clone());
+        let http1_connector = HttpsConnectorBuilder::new()
+            .with_tls_config(tls_config)
+            .https_or_http()
+            .enable_http1()
+            .wrap_connector(proxy_connector);
+
+        let connections = Arc::new(ConnectionRegistry::default());
+        Ok(Client {
+            client: hyper::client::Client::builder()
+                .build(TrackedConnector::new(connector, connections.clone())),
+            http1_client: hyper::client::Client::builder()
+                .build(TrackedConnector::new(http1_connector, connections.clone())),
+            connections,
+            upstream_proxy,
+            timeout: Duration::from_millis(timeouts.request.unwrap_or(DEFAULT_REQUEST_TIMEOUT)),
+            first_byte_timeout: client_config.first_byte_timeout,
+        })
     }
 
-    pub async fn make_request(&self, req: Request<Body>) -> Response<Body> {
-        match timeout(
-            Duration::from_millis(self.timeout.unwrap_or(60)),
-            self.client.request(req),
-        )
-        .await
+    pub fn connections(&self) -> BTreeMap<String, ConnectionCounts> {
+        self.connections.counts()
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub async fn make_request(&self, req: Request<Body>, http2: bool) -> Response<Body> {
+        self.make_request_within(req, http2, self.timeout, None)
+            .await
+    }
+
+    pub async fn make_request_within(
+        &self,
+        mut req: Request<Body>,
+        http2: bool,
+        request_timeout: Duration, // In place of the client's timeout, e.g. what is left of it
+        max_connection_age: Option<Duration>, // Retire the connection once it is this old
+    ) -> Response<Body> {
+        // With HTTPS backends the protocol is whatever ALPN negotiates, and hyper
+        // only requires the request version to match for HTTP/1 connections
+        let client = if http2 {
+            &self.client
+        } else {
+            &self.http1_client
+        };
+        if req.uri().scheme() == Some(&Scheme::HTTPS) {
+            *req.version_mut() = Version::HTTP_11;
+        } else if let Some(authorization) = self
+            .upstream_proxy
+            .as_ref()
+            .and_then(UpstreamProxy::authorization)
         {
-            Ok(result) => match result {
-                Ok(response) => response,
-                Err(e) => {
-                    let error_string;
-                    let error_status;
-                    if e.is_connect() {
-                        error_string = "Cannot connect to backend";
-                        error_status = StatusCode::SERVICE_UNAVAILABLE;
-                    } else if e.is_timeout() {
-                        error_string = "Connection timeout";
-                        error_status = StatusCode::GATEWAY_TIMEOUT;
-                    } else {
-                        error_string = "Unhandled error, see logs";
-                        error_status = StatusCode::INTERNAL_SERVER_ERROR;
-                        info!("Unhandled error: {:?}", e);
+            // Plain HTTP requests are sent to the proxy itself, HTTPS requests authenticate
+            // when the tunnel is opened
+            req.headers_mut()
+                .insert(header::PROXY_AUTHORIZATION, authorization.clone());
+        }
+        let captured = max_connection_age.map(|_| capture_connection(&mut req));
+        // The first byte timeout fails fast on backends slow to start responding,
+        // None when it has elapsed
+        let sent = Instant::now();
+        let request = client.request(req);
+        let first_byte = async {
+            match self.first_byte_timeout {
+                Some(first_byte_timeout) => {
+                    timeout(Duration::from_millis(first_byte_timeout), request)
+                        .await
+                        .ok()
+                }
+                None => Some(request.await),
+            }
+        };
+        match timeout(request_timeout, first_byte).await {
+            Ok(None) => {
+                warn!("Backend slow to respond, first byte timeout elapsed");
+                let mut response = Response::new("Backend slow to respond".into());
+                *response.status_mut() = StatusCode::GATEWAY_TIMEOUT;
+                response
+                    .extensions_mut()
+                    .insert(BackendError::FirstByteTimeout);
+                response
+            }
+            Ok(Some(result)) => {
+                match result {
+                    // Both headers is ambiguous framing (a request smuggling vector), so is not forwarded
+                    Ok(response)
+                        if response.headers().contains_key(header::CONTENT_LENGTH)
+                            && response.headers().contains_key(header::TRANSFER_ENCODING) =>
+                    {
+                        warn!("Backend response has both Content-Length and Transfer-Encoding, rejecting");
+                        let mut response = Response::new("Invalid backend response framing".into());
+                        *response.status_mut() = StatusCode::BAD_GATEWAY;
+                        response.extensions_mut().insert(BackendError::Protocol);
+                        response
+                    }
+                    Ok(mut response) => {
+                        let connection_info =
+                            response.extensions().get::<Arc<ConnectionInfo>>().cloned();
+                        let upstream_connection = connection_info
+                            .as_ref()
+                            .map(|connection_info| connection_info.record_response());
+                        if let (Some(max_connection_age), Some(connection_info), Some(captured)) =
+                            (max_connection_age, &connection_info, captured)
+                        {
+                            if let Some(remaining) =
+                                connection_info.schedule_retirement(max_connection_age)
+                            {
+                                retire_connection(captured, remaining);
+                            }
+                        }
+                        let connect = connection_info.as_ref().zip(upstream_connection).map(
+                            |(connection_info, upstream_connection)| match upstream_connection {
+                                UpstreamConnection::New => connection_info.connect_duration(),
+                                UpstreamConnection::Reused => Duration::ZERO,
+                            },
+                        );
+                        // The connection is active until the body has been relayed. A body
+                        // already at its end isn't wrapped, keeping its size hint and
+                        // is_end_stream for the wrappers which follow
+                        if let Some(connection_info) = connection_info {
+                            if !response.body().is_end_stream() {
+                                let guard = StreamingGuard::new(connection_info);
+                                response = response.map(|body| body_with_guard(body, guard));
+                            }
+                        }
+                        response.extensions_mut().insert(ResponseContext {
+                            upstream_connection,
+                            connect,
+                            ttfb: Some(sent.elapsed()),
+                            ..Default::default()
+                        });
+                        response
+                    }
+                    Err(e) => {
+                        let error_string;
+                        let error_status;
+                        let backend_error;
+                        if e.is_connect() {
+                            error_string = "Cannot connect to backend";
+                            error_status = StatusCode::SERVICE_UNAVAILABLE;
+                            backend_error = BackendError::Connect;
+                        } else if e.is_timeout() {
+                            error_string = "Connection timeout";
+                            error_status = StatusCode::GATEWAY_TIMEOUT;
+                            backend_error = BackendError::Timeout;
+                        } else {
+                            error_string = "Unhandled error, see logs";
+                            error_status = StatusCode::INTERNAL_SERVER_ERROR;
+                            backend_error = BackendError::Other;
+                            info!("Unhandled error: {:?}", e);
+                        }
+                        let mut response = Response::new(error_string.into());
+                        *response.status_mut() = error_status;
+                        response.extensions_mut().insert(backend_error);
+                        response
                     }
-                    let mut response = Response::new(error_string.into());
-                    *response.status_mut() = error_status;
-                    response
                 }
-            },
+            }
             Err(_) => {
                 let mut response = Response::new("Request timeout".into());
                 *response.status_mut() = StatusCode::GATEWAY_TIMEOUT;
+                response.extensions_mut().insert(BackendError::Timeout);
                 response
             }
         }
     }
 }
 
+fn retire_connection(captured: CaptureConnection, after: Duration) {
+    // A poisoned connection isn't reused by the pool, it is closed once its responses are done
+    tokio::spawn(async move {
+        tokio::time::sleep(after).await;
+        if let Some(connected) = captured.connection_metadata().as_ref() {
+            debug!("Retiring upstream connection at max_connection_age");
+            connected.poison();
+        }
+    });
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use wiremock::matchers::{method, path};
     use wiremock::{Mock, MockServer, ResponseTemplate};
 
+    #[derive(Clone)]
+    struct StaticResolver(Vec<SocketAddr>);
+
+    impl Service<Name> for StaticResolver {
+        type Response = std::vec::IntoIter<SocketAddr>;
+        type Error = std::io::Error;
+        type Future = futures::future::Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _name: Name) -> Self::Future {
+            futures::future::ready(Ok(self.0.clone().into_iter()))
+        }
+    }
+
+    #[derive(Clone)]
+    struct CountingResolver(Arc<Mutex<Vec<String>>>);
+
+    impl Service<Name> for CountingResolver {
+        type Response = std::vec::IntoIter<SocketAddr>;
+        type Error = std::io::Error;
+        type Future = futures::future::Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, name: Name) -> Self::Future {
+            // Records the names resolved
+            self.0.lock().unwrap().push(name.to_string());
+            let addr: SocketAddr = "192.0.2.1:0".parse().unwrap();
+            futures::future::ready(Ok(vec![addr].into_iter()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dns_cache_eviction() {
+        let resolved = Arc::new(Mutex::new(Vec::new()));
+        let mut resolver = CachingResolver::new(
+            CountingResolver(resolved.clone()),
+            Some(DnsCache::new(&DnsCacheConfig {
+                max_entries: Some(2),
+                ttl: Some(60_000),
+            })),
+        );
+        for name in ["a.home", "b.home", "a.home", "c.home", "a.home", "b.home"] {
+            let addrs: Vec<SocketAddr> = resolver
+                .call(name.parse().unwrap())
+                .await
+                .unwrap()
+                .collect();
+            assert_eq!(addrs.len(), 1);
+        }
+        // a.home was used more recently than b.home, so b.home was evicted for c.home
+        assert_eq!(
+            *resolved.lock().unwrap(),
+            vec!["a.home", "b.home", "c.home", "b.home"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dns_cache_ttl() {
+        let resolved = Arc::new(Mutex::new(Vec::new()));
+        let mut resolver = CachingResolver::new(
+            CountingResolver(resolved.clone()),
+            Some(DnsCache::new(&DnsCacheConfig {
+                max_entries: None,
+                ttl: Some(50),
+            })),
+        );
+        let name: Name = "a.home".parse().unwrap();
+        resolver.call(name.clone()).await.unwrap();
+        resolver.call(name.clone()).await.unwrap();
+        assert_eq!(resolved.lock().unwrap().len(), 1);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        resolver.call(name).await.unwrap();
+        assert_eq!(resolved.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_family_resolver() {
+        let v6: SocketAddr = "[2001:db8::1]:0".parse().unwrap();
+        let v4: SocketAddr = "192.0.2.1:0".parse().unwrap();
+        let name: Name = "backend.home".parse().unwrap();
+
+        let mut resolver = FamilyResolver::new(StaticResolver(vec![v6, v4]), AddressFamily::Ipv4);
+        let addrs: Vec<SocketAddr> = resolver.call(name.clone()).await.unwrap().collect();
+        assert_eq!(addrs, vec![v4]);
+
+        let mut resolver = FamilyResolver::new(StaticResolver(vec![v6, v4]), AddressFamily::Ipv6);
+        let addrs: Vec<SocketAddr> = resolver.call(name.clone()).await.unwrap().collect();
+        assert_eq!(addrs, vec![v6]);
+
+        let mut resolver = FamilyResolver::new(StaticResolver(vec![v6, v4]), AddressFamily::Auto);
+        let addrs: Vec<SocketAddr> = resolver.call(name.clone()).await.unwrap().collect();
+        assert_eq!(addrs, vec![v6, v4]);
+
+        // Only IPv6 resolved, fall back to it even though IPv4 is preferred
+        let mut resolver = FamilyResolver::new(StaticResolver(vec![v6]), AddressFamily::Ipv4);
+        let addrs: Vec<SocketAddr> = resolver.call(name).await.unwrap().collect();
+        assert_eq!(addrs, vec![v6]);
+    }
+
     #[tokio::test]
     async fn test_client_make_request_ok() {
         let mock_server = MockServer::start().await;
@@ -67,11 +662,21 @@ mod tests {
             .mount(&mock_server)
             .await;
 
-        let client = Client::new(Some(500));
+        let client = Client::new(
+            Timeouts {
+                request: Some(500),
+                ..Default::default()
+            },
+            &ClientConfig::default(),
+        )
+        .unwrap();
         let mut request = Request::new(Body::empty());
         *request.uri_mut() = format!("{}/ok", &mock_server.uri()).parse().unwrap();
-        let response = client.make_request(request).await;
+        let response = client.make_request(request, true).await;
         assert_eq!(response.status(), 200);
+        // The empty body is relayed as it was received
+        assert!(response.body().is_end_stream());
+        assert_eq!(response.body().size_hint().exact(), Some(0));
     }
 
     #[tokio::test]
@@ -83,12 +688,229 @@ mod tests {
             .mount(&mock_server)
             .await;
 
-        let client = Client::new(Some(500)); // This will timeout before the mock server responds
+        let client = Client::new(
+            Timeouts {
+                request: Some(500),
+                ..Default::default()
+            },
+            &ClientConfig::default(),
+        )
+        .unwrap(); // This will timeout before the mock server responds
         let mut request = Request::new(Body::empty());
         *request.uri_mut() = format!("{}/delay", &mock_server.uri()).parse().unwrap();
-        let response = client.make_request(request).await;
+        let response = client.make_request(request, true).await;
         assert_eq!(response.status(), 504);
+        assert_eq!(
+            response.extensions().get::<BackendError>(),
+            Some(&BackendError::Timeout)
+        );
         let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
         assert_eq!(body, "Request timeout");
     }
+
+    #[tokio::test]
+    async fn test_client_default_timeout() {
+        // Without a configured timeout, a backend taking 500ms to respond isn't timed out
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/slow"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(500)))
+            .mount(&mock_server)
+            .await;
+
+        let client = Client::new(Timeouts::default(), &ClientConfig::default()).unwrap();
+        let mut request = Request::new(Body::empty());
+        *request.uri_mut() = format!("{}/slow", &mock_server.uri()).parse().unwrap();
+        let response = client.make_request(request, true).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(client.timeout, Duration::from_secs(60));
+    }
+
+    #[derive(Clone)]
+    struct PendingConnector;
+
+    impl Service<Uri> for PendingConnector {
+        type Response = tokio::net::TcpStream;
+        type Error = std::io::Error;
+        type Future = futures::future::Pending<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _uri: Uri) -> Self::Future {
+            // A backend which never accepts the connection
+            futures::future::pending()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_connect_timeout() {
+        // A connection not made within the connect timeout fails as a connect error,
+        // well within the request timeout
+        let connector = ConnectTimeout::new(PendingConnector, Some(Duration::from_millis(100)));
+        let client = hyper::client::Client::builder().build::<_, Body>(connector);
+        let start = std::time::Instant::now();
+        let error = timeout(
+            Duration::from_millis(5000),
+            client.get("http://backend.home/".parse().unwrap()),
+        )
+        .await
+        .unwrap()
+        .unwrap_err();
+        assert!(start.elapsed() < Duration::from_millis(2000));
+        assert!(error.is_connect());
+
+        // Which the client responds to with a 503, as it does to a refused connection
+        let address = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let client = Client::new(
+            Timeouts {
+                connect: Some(100),
+                request: Some(5000),
+            },
+            &ClientConfig::default(),
+        )
+        .unwrap();
+        let mut request = Request::new(Body::empty());
+        *request.uri_mut() = format!("http://{}/", address).parse().unwrap();
+        let response = client.make_request(request, true).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response.extensions().get::<BackendError>(),
+            Some(&BackendError::Connect)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_first_byte_timeout() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/delay"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(500)))
+            .mount(&mock_server)
+            .await;
+
+        // The response head is delayed past the first byte timeout, but within the timeout
+        let client_config = ClientConfig {
+            first_byte_timeout: Some(100),
+            ..Default::default()
+        };
+        let client = Client::new(
+            Timeouts {
+                request: Some(2000),
+                ..Default::default()
+            },
+            &client_config,
+        )
+        .unwrap();
+        let mut request = Request::new(Body::empty());
+        *request.uri_mut() = format!("{}/delay", &mock_server.uri()).parse().unwrap();
+        let start = std::time::Instant::now();
+        let response = client.make_request(request, true).await;
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(
+            response.extensions().get::<BackendError>(),
+            Some(&BackendError::FirstByteTimeout)
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "Backend slow to respond");
+    }
+
+    #[tokio::test]
+    async fn test_client_conflicting_framing() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // A backend sending both Content-Length and Transfer-Encoding
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).await.unwrap();
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+                )
+                .await
+                .unwrap();
+        });
+
+        let client = Client::new(
+            Timeouts {
+                request: Some(500),
+                ..Default::default()
+            },
+            &ClientConfig::default(),
+        )
+        .unwrap();
+        let mut request = Request::new(Body::empty());
+        *request.uri_mut() = format!("http://{}/", address).parse().unwrap();
+        let response = client.make_request(request, true).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            response.extensions().get::<BackendError>(),
+            Some(&BackendError::Protocol)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_connection_age() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // A keep-alive backend counting the connections made to it
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let connections = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let backend_connections = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                backend_connections.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    while let Ok(read) = stream.read(&mut buf).await {
+                        if read == 0 {
+                            break;
+                        }
+                        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+                        if stream.write_all(response).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        let client = Client::new(Timeouts::default(), &ClientConfig::default()).unwrap();
+        let max_connection_age = Some(Duration::from_millis(100));
+        let request = || async {
+            let mut request = Request::new(Body::empty());
+            *request.uri_mut() = format!("http://{}/", address).parse().unwrap();
+            let response = client
+                .make_request_within(request, false, client.timeout, max_connection_age)
+                .await;
+            assert_eq!(response.status(), StatusCode::OK);
+            response
+                .extensions()
+                .get::<ResponseContext>()
+                .unwrap()
+                .upstream_connection
+        };
+        let connection_count = || connections.load(std::sync::atomic::Ordering::SeqCst);
+
+        // Reused until the connection is older than the max age
+        assert_eq!(request().await, Some(UpstreamConnection::New));
+        assert_eq!(request().await, Some(UpstreamConnection::Reused));
+        assert_eq!(connection_count(), 1);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(request().await, Some(UpstreamConnection::New));
+        assert_eq!(connection_count(), 2);
+        assert_eq!(request().await, Some(UpstreamConnection::Reused));
+        assert_eq!(connection_count(), 2);
+    }
 }
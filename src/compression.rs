@@ -0,0 +1,347 @@
+// Compression of backend responses for clients sending a supporting Accept-Encoding
+use std::io::Write;
+
+use hyper::body::{Bytes, HttpBody};
+use hyper::http::{header, HeaderMap, HeaderValue, Response, StatusCode};
+use hyper::Body;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::metrics::METRICS;
+
+const DEFAULT_LEVEL: u32 = 6;
+const DEFAULT_MIN_SIZE: u64 = 1024;
+const DEFAULT_MAX_SIZE: u64 = 8 * 1024 * 1024;
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Clone)]
+pub struct CompressionConfig {
+    level: Option<u32>,    // 0-9 for gzip and 0-11 for brotli, defaults to 6
+    min_size: Option<u64>, // Smaller responses are not compressed, defaults to 1024 bytes
+    max_size: Option<u64>, // Larger responses are streamed uncompressed, defaults to 8MiB
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+pub fn accepted_encoding(headers: &HeaderMap) -> Option<Encoding> {
+    // Brotli is preferred when accepted, encodings with a q value of 0 are refused
+    let accepted: Vec<String> = headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|encoding| {
+            let mut params = encoding.split(';').map(str::trim);
+            let name = params.next()?.to_ascii_lowercase();
+            let refused = params.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (!refused).then_some(name)
+        })
+        .collect();
+    [Encoding::Brotli, Encoding::Gzip]
+        .iter()
+        .copied()
+        .find(|encoding| accepted.iter().any(|name| name == encoding.as_str()))
+}
+
+fn compress(body: &[u8], encoding: Encoding, level: u32) -> Result<Vec<u8>, std::io::Error> {
+    match encoding {
+        Encoding::Gzip => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::new(level.min(9)));
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        Encoding::Brotli => {
+            let mut compressed = Vec::new();
+            let mut encoder =
+                brotli::CompressorWriter::new(&mut compressed, 4096, level.min(11), 22);
+            encoder.write_all(body)?;
+            drop(encoder);
+            Ok(compressed)
+        }
+    }
+}
+
+pub async fn compress_response(
+    response: Response<Body>,
+    encoding: Encoding,
+    config: &CompressionConfig,
+    backend: &str,
+) -> Response<Body> {
+    // Responses already encoded, without a body, or partial content (whose ranges are of the
+    // uncompressed body) are left unchanged
+    if response.headers().contains_key(header::CONTENT_ENCODING)
+        || response.status() == StatusCode::NO_CONTENT
+        || response.status() == StatusCode::NOT_MODIFIED
+        || response.status() == StatusCode::PARTIAL_CONTENT
+    {
+        return response;
+    }
+    // Only a body of known length within min_size and max_size is buffered, others are
+    // streamed uncompressed
+    let content_length = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .or_else(|| response.body().size_hint().exact());
+    let min_size = config.min_size.unwrap_or(DEFAULT_MIN_SIZE);
+    let max_size = config.max_size.unwrap_or(DEFAULT_MAX_SIZE);
+    if !content_length.is_some_and(|content_length| (min_size..=max_size).contains(&content_length))
+    {
+        return response;
+    }
+
+    // The body is buffered to be compressed in full
+    let (mut parts, body) = response.into_parts();
+    let body: Bytes = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Error reading response body to compress: {e}");
+            let mut response = Response::new(Body::from("Error reading backend response"));
+            *response.status_mut() = StatusCode::BAD_GATEWAY;
+            return response;
+        }
+    };
+    // Compressing up to max_size is CPU bound, so it runs off the async worker threads
+    let level = config.level.unwrap_or(DEFAULT_LEVEL);
+    let uncompressed = body.clone();
+    let compressed = tokio::task::spawn_blocking(move || compress(&uncompressed, encoding, level))
+        .await
+        .map_err(std::io::Error::other)
+        .and_then(|compressed| compressed);
+    let compressed = match compressed {
+        Ok(compressed) => compressed,
+        Err(e) => {
+            warn!("Error compressing response: {e}");
+            return Response::from_parts(parts, Body::from(body));
+        }
+    };
+    METRICS
+        .compression_bytes_saved
+        .with_label_values(&[backend])
+        .inc_by(body.len().saturating_sub(compressed.len()) as u64);
+    parts.headers.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.as_str()),
+    );
+    parts
+        .headers
+        .insert(header::CONTENT_LENGTH, HeaderValue::from(compressed.len()));
+    parts.headers.append(
+        header::VARY,
+        HeaderValue::from_static(header::ACCEPT_ENCODING.as_str()),
+    );
+    // The compressed body isn't byte for byte the backend's, so a strong ETag is weakened
+    if let Some(etag) = parts.headers.get(header::ETAG) {
+        if !etag.as_bytes().starts_with(b"W/") {
+            let weak = [b"W/", etag.as_bytes()].concat();
+            if let Ok(weak) = HeaderValue::from_bytes(&weak) {
+                parts.headers.insert(header::ETAG, weak);
+            }
+        }
+    }
+    Response::from_parts(parts, Body::from(compressed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn config(level: u32, min_size: u64) -> CompressionConfig {
+        CompressionConfig {
+            level: Some(level),
+            min_size: Some(min_size),
+            max_size: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accepted_encoding() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(accepted_encoding(&headers), None);
+        headers.insert(header::ACCEPT_ENCODING, "deflate, gzip".parse().unwrap());
+        assert_eq!(accepted_encoding(&headers), Some(Encoding::Gzip));
+        headers.insert(header::ACCEPT_ENCODING, "gzip, br;q=0.8".parse().unwrap());
+        assert_eq!(accepted_encoding(&headers), Some(Encoding::Brotli));
+        headers.insert(header::ACCEPT_ENCODING, "gzip, br;q=0".parse().unwrap());
+        assert_eq!(accepted_encoding(&headers), Some(Encoding::Gzip));
+    }
+
+    #[tokio::test]
+    async fn test_compress_response() {
+        let body = "compressible ".repeat(100);
+        let mut response = Response::new(Body::from(body.clone()));
+        response
+            .headers_mut()
+            .insert(header::ETAG, "\"v1\"".parse().unwrap());
+        let response = compress_response(response, Encoding::Gzip, &config(6, 10), "test").await;
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[header::VARY], "accept-encoding");
+        assert_eq!(response.headers()[header::ETAG], "W/\"v1\"");
+        let compressed = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(compressed.len() < body.len());
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, body);
+
+        let response = compress_response(
+            Response::new(Body::from(body.clone())),
+            Encoding::Brotli,
+            &config(6, 10),
+            "test",
+        )
+        .await;
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "br");
+        let compressed = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let mut decompressed = String::new();
+        brotli::Decompressor::new(&compressed[..], 4096)
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, body);
+    }
+
+    #[tokio::test]
+    async fn test_compress_response_min_size() {
+        // Below the threshold, with and without a Content-Length
+        let response = compress_response(
+            Response::new(Body::from("tiny")),
+            Encoding::Gzip,
+            &config(6, 10),
+            "test",
+        )
+        .await;
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "tiny"
+        );
+
+        let mut response = Response::new(Body::from("tiny"));
+        response
+            .headers_mut()
+            .insert(header::CONTENT_LENGTH, "4".parse().unwrap());
+        let response = compress_response(response, Encoding::Gzip, &config(6, 10), "test").await;
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+    }
+
+    #[tokio::test]
+    async fn test_compress_response_skipped() {
+        let body = "compressible ".repeat(100);
+        let assert_uncompressed = |response: &Response<Body>| {
+            assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+            assert!(!response.headers().contains_key(header::VARY));
+        };
+
+        // Partial content
+        let mut response = Response::new(Body::from(body.clone()));
+        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+        let response = compress_response(response, Encoding::Gzip, &config(6, 10), "test").await;
+        assert_uncompressed(&response);
+
+        // Over max_size
+        let response = compress_response(
+            Response::new(Body::from(body.clone())),
+            Encoding::Gzip,
+            &CompressionConfig {
+                max_size: Some(100),
+                ..config(6, 10)
+            },
+            "test",
+        )
+        .await;
+        assert_uncompressed(&response);
+
+        // Of unknown length, the body is streamed as it is
+        let chunks =
+            futures::stream::iter(vec![Ok::<_, std::io::Error>(Bytes::from(body.clone()))]);
+        let response = compress_response(
+            Response::new(Body::wrap_stream(chunks)),
+            Encoding::Gzip,
+            &config(6, 10),
+            "test",
+        )
+        .await;
+        assert_uncompressed(&response);
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            body
+        );
+
+        // Already encoded by the backend
+        let mut response = Response::new(Body::from(body.clone()));
+        response
+            .headers_mut()
+            .insert(header::CONTENT_ENCODING, "zstd".parse().unwrap());
+        let response = compress_response(response, Encoding::Gzip, &config(6, 10), "test").await;
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "zstd");
+        assert!(!response.headers().contains_key(header::VARY));
+    }
+
+    #[tokio::test]
+    async fn test_compress_response_level() {
+        // The gzip header's extra flags record maximum (2) or fastest (4) compression
+        let body = "compressible ".repeat(100);
+        for (level, extra_flags) in [(1, 4), (9, 2)] {
+            let response = compress_response(
+                Response::new(Body::from(body.clone())),
+                Encoding::Gzip,
+                &config(level, 10),
+                "test",
+            )
+            .await;
+            let compressed = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(compressed[8], extra_flags);
+        }
+        // Level 0 stores the body uncompressed
+        let response = compress_response(
+            Response::new(Body::from(body.clone())),
+            Encoding::Gzip,
+            &config(0, 10),
+            "test",
+        )
+        .await;
+        let compressed = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(compressed.len() > body.len());
+    }
+
+    #[tokio::test]
+    async fn test_compression_bytes_saved() {
+        let bytes_saved = || {
+            METRICS
+                .compression_bytes_saved
+                .with_label_values(&["saved.home"])
+                .get()
+        };
+        let body = "compressible ".repeat(100);
+        let response = compress_response(
+            Response::new(Body::from(body.clone())),
+            Encoding::Gzip,
+            &config(6, 10),
+            "saved.home",
+        )
+        .await;
+        let compressed = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(bytes_saved(), (body.len() - compressed.len()) as u64);
+    }
+}
@@ -0,0 +1,125 @@
+// Limits on the requests in flight to each location of a backend, so that fragile backends
+// aren't sent more requests at once than they can handle
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Error;
+use hyper::{Body, Response};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::body::body_with_guard;
+
+// A backend name and one of its locations
+type LocationKey = (String, String);
+
+#[derive(Debug, Default)]
+pub struct ConcurrencyLimits {
+    // The limit and its semaphore per location
+    semaphores: Mutex<HashMap<LocationKey, (usize, Arc<Semaphore>)>>,
+}
+
+impl ConcurrencyLimits {
+    pub fn new() -> Arc<ConcurrencyLimits> {
+        Arc::new(ConcurrencyLimits::default())
+    }
+
+    fn semaphore(&self, backend: &str, location: &str, max_concurrent: usize) -> Arc<Semaphore> {
+        // A limit changed by a config reload starts a new semaphore, requests holding
+        // permits of the old one release them to it
+        let mut semaphores = self.semaphores.lock().unwrap();
+        let key = (backend.to_string(), location.to_string());
+        match semaphores.get(&key) {
+            Some((max, semaphore)) if *max == max_concurrent => semaphore.clone(),
+            _ => {
+                let semaphore = Arc::new(Semaphore::new(max_concurrent));
+                semaphores.insert(key, (max_concurrent, semaphore.clone()));
+                semaphore
+            }
+        }
+    }
+
+    pub async fn acquire(
+        &self,
+        backend: &str,
+        location: &str,
+        max_concurrent: usize,
+        wait: Option<Duration>, // How long to queue for a permit, rather than failing at once
+    ) -> Result<OwnedSemaphorePermit, Error> {
+        let semaphore = self.semaphore(backend, location, max_concurrent);
+        let permit = match wait {
+            Some(wait) => tokio::time::timeout(wait, semaphore.acquire_owned())
+                .await
+                .ok()
+                .and_then(Result::ok),
+            None => semaphore.try_acquire_owned().ok(),
+        };
+        permit.ok_or_else(|| Error::msg("Backend concurrency limit reached"))
+    }
+
+    pub fn retain_backends(&self, keep: impl Fn(&str) -> bool) {
+        self.semaphores
+            .lock()
+            .unwrap()
+            .retain(|(backend, _), _| keep(backend));
+    }
+}
+
+pub fn hold_until_complete(
+    response: Response<Body>,
+    permit: OwnedSemaphorePermit,
+) -> Response<Body> {
+    // The permit is released once the body has been streamed to the client, or the client
+    // has gone away and the body is dropped
+    response.map(|body| body_with_guard(body, permit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_acquire() {
+        let limits = ConcurrencyLimits::new();
+        let first = limits.acquire("fragile", "127.0.0.1:8000", 1, None).await;
+        assert!(first.is_ok());
+        // Locations are limited separately
+        assert!(limits
+            .acquire("fragile", "127.0.0.1:8001", 1, None)
+            .await
+            .is_ok());
+        assert!(limits
+            .acquire("fragile", "127.0.0.1:8000", 1, None)
+            .await
+            .is_err());
+        assert!(limits
+            .acquire(
+                "fragile",
+                "127.0.0.1:8000",
+                1,
+                Some(Duration::from_millis(20))
+            )
+            .await
+            .is_err());
+
+        // A queued request gets the permit once it is released
+        let queued = {
+            let limits = limits.clone();
+            tokio::spawn(async move {
+                limits
+                    .acquire("fragile", "127.0.0.1:8000", 1, Some(Duration::from_secs(2)))
+                    .await
+                    .is_ok()
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(first);
+        assert!(queued.await.unwrap());
+
+        // A changed limit takes effect straight away
+        assert!(limits
+            .acquire("fragile", "127.0.0.1:8000", 2, None)
+            .await
+            .is_ok());
+    }
+}
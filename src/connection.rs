@@ -0,0 +1,242 @@
+// Tracking of upstream connections, the hyper client adds the connection info of the
+// connection a response was received on to the response extensions
+use std::collections::{BTreeMap, HashMap};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use hyper::client::connect::{Connected, Connection};
+use hyper::service::Service;
+use hyper::Uri;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamConnection {
+    New,
+    Reused,
+}
+
+impl UpstreamConnection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UpstreamConnection::New => "new",
+            UpstreamConnection::Reused => "reused",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionInfo {
+    responses: AtomicUsize, // Responses received on the connection
+    streaming: AtomicUsize, // Response bodies still being received
+    closed: AtomicBool,
+    retiring: AtomicBool, // Scheduled to be retired by max_connection_age
+    connect: Duration,    // Time taken to establish the connection
+    established: Instant,
+}
+
+impl ConnectionInfo {
+    fn new(connect: Duration) -> ConnectionInfo {
+        ConnectionInfo {
+            responses: AtomicUsize::new(0),
+            streaming: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            retiring: AtomicBool::new(false),
+            connect,
+            established: Instant::now(),
+        }
+    }
+
+    pub fn connect_duration(&self) -> Duration {
+        self.connect
+    }
+
+    pub fn schedule_retirement(&self, max_age: Duration) -> Option<Duration> {
+        // The time left until the connection is retired, only once per connection so that it
+        // is retired by whichever request first asked for it to be
+        if self.retiring.swap(true, Ordering::SeqCst) {
+            return None;
+        }
+        Some(max_age.saturating_sub(self.established.elapsed()))
+    }
+
+    pub fn record_response(&self) -> UpstreamConnection {
+        match self.responses.fetch_add(1, Ordering::SeqCst) {
+            0 => UpstreamConnection::New,
+            _ => UpstreamConnection::Reused,
+        }
+    }
+}
+
+pub struct StreamingGuard {
+    // Marks the connection active until the response body is dropped
+    info: Arc<ConnectionInfo>,
+}
+
+impl StreamingGuard {
+    pub fn new(info: Arc<ConnectionInfo>) -> StreamingGuard {
+        info.streaming.fetch_add(1, Ordering::SeqCst);
+        StreamingGuard { info }
+    }
+}
+
+impl Drop for StreamingGuard {
+    fn drop(&mut self) {
+        self.info.streaming.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ConnectionCounts {
+    pub open: usize,
+    pub idle: usize,
+    pub active: usize, // Receiving a response body
+}
+
+#[derive(Debug, Default)]
+pub struct ConnectionRegistry {
+    // Upstream connections per backend location
+    connections: Mutex<HashMap<String, Vec<Arc<ConnectionInfo>>>>,
+}
+
+impl ConnectionRegistry {
+    fn register(&self, location: String, connect: Duration) -> Arc<ConnectionInfo> {
+        let info = Arc::new(ConnectionInfo::new(connect));
+        let mut connections = self.connections.lock().unwrap();
+        connections
+            .values_mut()
+            .for_each(|infos| infos.retain(|info| !info.closed.load(Ordering::SeqCst)));
+        connections.retain(|_, infos| !infos.is_empty());
+        connections.entry(location).or_default().push(info.clone());
+        info
+    }
+
+    pub fn counts(&self) -> BTreeMap<String, ConnectionCounts> {
+        self.connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(location, infos)| {
+                let mut counts = ConnectionCounts::default();
+                for info in infos
+                    .iter()
+                    .filter(|info| !info.closed.load(Ordering::SeqCst))
+                {
+                    counts.open += 1;
+                    if info.streaming.load(Ordering::SeqCst) > 0 {
+                        counts.active += 1;
+                    } else {
+                        counts.idle += 1;
+                    }
+                }
+                (location.clone(), counts)
+            })
+            .filter(|(_, counts)| counts.open > 0)
+            .collect()
+    }
+}
+
+#[derive(Clone)]
+pub struct TrackedConnector<C> {
+    inner: C,
+    registry: Arc<ConnectionRegistry>,
+}
+
+impl<C> TrackedConnector<C> {
+    pub fn new(inner: C, registry: Arc<ConnectionRegistry>) -> TrackedConnector<C> {
+        TrackedConnector { inner, registry }
+    }
+}
+
+impl<C> Service<Uri> for TrackedConnector<C>
+where
+    C: Service<Uri>,
+    C::Future: Send + 'static,
+{
+    type Response = TrackedConnection<C::Response>;
+    type Error = C::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let location = uri
+            .authority()
+            .map(|authority| authority.to_string())
+            .unwrap_or_default();
+        let connecting = self.inner.call(uri);
+        let registry = self.registry.clone();
+        let start = Instant::now();
+        Box::pin(async move {
+            let inner = connecting.await?;
+            Ok(TrackedConnection {
+                inner,
+                info: registry.register(location, start.elapsed()),
+            })
+        })
+    }
+}
+
+pub struct TrackedConnection<T> {
+    inner: T,
+    info: Arc<ConnectionInfo>,
+}
+
+impl<T> Drop for TrackedConnection<T> {
+    fn drop(&mut self) {
+        // The info lives on in the extensions of responses received on the connection
+        self.info.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+impl<T: Connection> Connection for TrackedConnection<T> {
+    fn connected(&self) -> Connected {
+        self.inner.connected().extra(self.info.clone())
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for TrackedConnection<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for TrackedConnection<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
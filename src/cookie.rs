@@ -0,0 +1,77 @@
+// Rewriting of the attributes of backend Set-Cookie headers, so that cookies set for an
+// internal backend domain apply to the public host
+use hyper::http::{header, HeaderMap, HeaderValue};
+use log::warn;
+
+pub fn rewrite_set_cookies(headers: &mut HeaderMap, domain: Option<&str>, path: Option<&str>) {
+    let set_cookies: Vec<HeaderValue> = headers
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .map(|value| match value.to_str() {
+            Ok(set_cookie) => {
+                let rewritten = rewrite_set_cookie(set_cookie, domain, path);
+                HeaderValue::from_str(&rewritten).unwrap_or_else(|e| {
+                    warn!("Unable to rewrite Set-Cookie: {e}");
+                    value.clone()
+                })
+            }
+            Err(_) => value.clone(),
+        })
+        .collect();
+    headers.remove(header::SET_COOKIE);
+    for set_cookie in set_cookies {
+        headers.append(header::SET_COOKIE, set_cookie);
+    }
+}
+
+fn rewrite_set_cookie(set_cookie: &str, domain: Option<&str>, path: Option<&str>) -> String {
+    // Only attributes which are present are rewritten, a cookie without a Domain is already
+    // only sent to the host which set it
+    let mut parts = set_cookie.split(';');
+    let mut rewritten = vec![parts.next().unwrap_or_default().trim().to_string()];
+    for attribute in parts.map(str::trim) {
+        let name = attribute.split('=').next().unwrap_or_default().trim();
+        let replacement = if name.eq_ignore_ascii_case("domain") {
+            domain
+        } else if name.eq_ignore_ascii_case("path") {
+            path
+        } else {
+            None
+        };
+        rewritten.push(match replacement {
+            Some(value) => format!("{}={}", name, value),
+            None => attribute.to_string(),
+        });
+    }
+    rewritten.join("; ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rewrite_set_cookie() {
+        assert_eq!(
+            rewrite_set_cookie(
+                "session=abc; Domain=backend.internal; Path=/; HttpOnly",
+                Some("app.home"),
+                None
+            ),
+            "session=abc; Domain=app.home; Path=/; HttpOnly"
+        );
+        assert_eq!(
+            rewrite_set_cookie(
+                "session=abc;domain=.backend.internal;path=/",
+                Some("app.home"),
+                Some("/internal")
+            ),
+            "session=abc; domain=app.home; path=/internal"
+        );
+        // Without a Domain the cookie is left as is
+        assert_eq!(
+            rewrite_set_cookie("theme=dark; Secure", Some("app.home"), None),
+            "theme=dark; Secure"
+        );
+    }
+}
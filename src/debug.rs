@@ -0,0 +1,94 @@
+// Route debug responses, describing the routing decision for a request rather than proxying it
+use hyper::http::{header, HeaderValue, Request, Response, StatusCode};
+use hyper::Body;
+use log::warn;
+use serde::Serialize;
+
+use super::admin::bearer_token_valid;
+use super::routing::{match_backend, Route};
+use super::{Backend, Config};
+
+const DEBUG_QUERY: &str = "__brachyura_debug=1";
+const DEBUG_HEADER: &str = "x-brachyura-debug";
+
+#[derive(Debug, PartialEq, Serialize)]
+struct RouteDebug<'a> {
+    host: &'a str,
+    backend: Option<&'a str>,
+    backend_type: Option<&'a str>,
+    location: Option<&'a str>,
+    reason: &'a str,
+}
+
+pub fn route_debug_requested(config: &Config, req: &Request<Body>) -> bool {
+    // Requires enable_route_debug and a token, the admin server's token or else the
+    // metrics_token. Without either configured, debug requests are routed as normal
+    if config.enable_route_debug != Some(true) {
+        return false;
+    }
+    let query_requested = req
+        .uri()
+        .query()
+        .is_some_and(|query| query.split('&').any(|pair| pair == DEBUG_QUERY));
+    let header_requested = req
+        .headers()
+        .get(DEBUG_HEADER)
+        .is_some_and(|value| value == "1");
+    if !(query_requested || header_requested) {
+        return false;
+    }
+
+    let token = config
+        .admin
+        .as_ref()
+        .and_then(|admin| admin.auth.as_ref())
+        .map(|auth| &auth.token)
+        .or(config.metrics_token.as_ref());
+    token.is_some_and(|token| bearer_token_valid(req, token))
+}
+
+pub fn route_debug_response(
+    backends: &[Backend],
+    host_header: &str,
+    path: &str,
+    route: Option<&Route>,
+) -> Response<Body> {
+    let route_debug = match route {
+        Some(route) => RouteDebug {
+            host: host_header,
+            backend: route.backend.name.as_deref(),
+            backend_type: route.backend.backend_type.as_deref(),
+            location: Some(&route.location),
+            reason: route.reason,
+        },
+        None => {
+            let backend = match_backend(backends, host_header, path, None);
+            RouteDebug {
+                host: host_header,
+                backend: backend.and_then(|backend| backend.name.as_deref()),
+                backend_type: backend.and_then(|backend| backend.backend_type.as_deref()),
+                location: None,
+                reason: match backend {
+                    Some(_) => "backend config is not valid",
+                    None => "no backend matches the host header",
+                },
+            }
+        }
+    };
+
+    let mut response = Response::new(Body::empty());
+    match serde_json::to_string(&route_debug) {
+        Ok(json) => {
+            *response.body_mut() = Body::from(json);
+            response.headers_mut().insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            );
+        }
+        Err(e) => {
+            warn!("Error encoding route debug: {e}");
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    }
+    response
+}
@@ -0,0 +1,171 @@
+// Tracking of in-flight backend requests, so that backends removed by a
+// config reload can be drained rather than cut off immediately
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::StreamExt;
+use hyper::body::HttpBody;
+use hyper::{Body, Response};
+use log::{info, warn};
+use tokio::sync::Notify;
+
+// Used when a backend doesn't configure a drain timeout
+pub const DEFAULT_DRAIN_TIMEOUT: u64 = 30_000;
+
+#[derive(Debug, Default)]
+pub struct InFlight {
+    count: AtomicUsize,
+    cut_off: AtomicBool,
+    notify: Notify,
+}
+
+pub struct InFlightGuard {
+    in_flight: Arc<InFlight>,
+}
+
+impl InFlight {
+    pub fn new() -> Arc<InFlight> {
+        Arc::new(InFlight::default())
+    }
+
+    pub fn start(self: &Arc<Self>) -> InFlightGuard {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard {
+            in_flight: self.clone(),
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    pub async fn drain(&self, backend_name: &str, drain_timeout: Duration) {
+        // Wait for in-flight requests to complete, cutting off any
+        // still in-flight once the drain timeout has elapsed
+        let deadline = tokio::time::Instant::now() + drain_timeout;
+        loop {
+            // Notified when the last in-flight request completes
+            let notified = self.notify.notified();
+            if self.count() == 0 {
+                info!("Backend drained: {}", backend_name);
+                return;
+            }
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                warn!(
+                    "Drain timeout for backend: {}, cutting off {} in-flight requests",
+                    backend_name,
+                    self.count()
+                );
+                self.cut_off.store(true, Ordering::SeqCst);
+                self.notify.notify_waiters();
+                return;
+            }
+        }
+    }
+}
+
+impl InFlightGuard {
+    pub async fn cut_off(&self) {
+        // Resolves once the backend has been removed and the drain timeout elapsed. The
+        // notify is shared with the drain, so other notifications are waited past
+        loop {
+            let notified = self.in_flight.notify.notified();
+            if self.in_flight.cut_off.load(Ordering::SeqCst) {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.in_flight.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.in_flight.notify.notify_waiters();
+        }
+    }
+}
+
+pub fn hold_until_drained(response: Response<Body>, guard: InFlightGuard) -> Response<Body> {
+    // The request stays in-flight until the body has been streamed to the client, or the
+    // body is dropped. A body still streaming when the backend is cut off ends with an error,
+    // so that the client doesn't take the truncated body as complete
+    response.map(|body| {
+        if body.is_end_stream() {
+            return body;
+        }
+        Body::wrap_stream(futures::stream::unfold(
+            Some((body, guard)),
+            |state| async move {
+                let (mut body, guard) = state?;
+                tokio::select! {
+                    chunk = body.next() => {
+                        let chunk = chunk?.map_err(io::Error::other);
+                        Some((chunk, Some((body, guard))))
+                    }
+                    _ = guard.cut_off() => {
+                        let error = io::Error::other("Backend removed");
+                        Some((Err(error), None))
+                    }
+                }
+            },
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_in_flight_drain() {
+        let in_flight = InFlight::new();
+        let guard = in_flight.start();
+        assert_eq!(in_flight.count(), 1);
+
+        // The request completes within the drain timeout
+        let draining = in_flight.clone();
+        let drain =
+            tokio::spawn(async move { draining.drain("test", Duration::from_secs(5)).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(guard);
+        drain.await.unwrap();
+        assert_eq!(in_flight.count(), 0);
+
+        // The request is cut off once the drain timeout elapses
+        let guard = in_flight.start();
+        let draining = in_flight.clone();
+        tokio::spawn(async move { draining.drain("test", Duration::from_millis(50)).await });
+        tokio::time::timeout(Duration::from_secs(1), guard.cut_off())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_hold_until_drained() {
+        // In-flight until the body has been streamed
+        let in_flight = InFlight::new();
+        let response = hold_until_drained(Response::new(Body::from("body")), in_flight.start());
+        assert_eq!(in_flight.count(), 1);
+        let draining = in_flight.clone();
+        let drain =
+            tokio::spawn(async move { draining.drain("test", Duration::from_secs(5)).await });
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "body");
+        tokio::time::timeout(Duration::from_secs(1), drain)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(in_flight.count(), 0);
+
+        // A body still streaming when the drain timeout elapses ends with an error
+        let in_flight = InFlight::new();
+        let (_sender, body) = Body::channel();
+        let response = hold_until_drained(Response::new(body), in_flight.start());
+        in_flight.drain("test", Duration::from_millis(50)).await;
+        assert!(hyper::body::to_bytes(response.into_body()).await.is_err());
+        assert_eq!(in_flight.count(), 0);
+    }
+}
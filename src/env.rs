@@ -0,0 +1,101 @@
+// Expansion of ${VAR} environment variables in config string values, so that paths and
+// addresses can be set at deploy time rather than committed to the config
+use anyhow::Error;
+use serde_yaml::Value;
+
+pub fn expand_env_vars(
+    value: &mut Value,
+    lookup: &impl Fn(&str) -> Option<String>,
+) -> Result<(), Error> {
+    // Only string values are expanded, not keys, so the config's structure is unchanged. The
+    // config is expanded once parsed rather than as text, so a variable can't inject YAML, but
+    // a value expanded stays a string and so can't set a number or boolean option
+    match value {
+        Value::String(string) => *string = expand(string, lookup)?,
+        Value::Sequence(values) => {
+            for value in values {
+                expand_env_vars(value, lookup)?;
+            }
+        }
+        Value::Mapping(mapping) => {
+            for (_, value) in mapping.iter_mut() {
+                expand_env_vars(value, lookup)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn expand(string: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, Error> {
+    // A $ not followed by { is left as is, and $${ escapes a literal ${
+    let mut expanded = String::new();
+    let mut rest = string;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(escaped) = rest.strip_prefix("$${") {
+            expanded.push_str("${");
+            rest = escaped;
+        } else if let Some(variable) = rest.strip_prefix("${") {
+            let end = variable.find('}').ok_or_else(|| {
+                Error::msg(format!("Unterminated ${{ in config value: {}", string))
+            })?;
+            let name = &variable[..end];
+            let value = lookup(name).ok_or_else(|| {
+                Error::msg(format!(
+                    "Environment variable {} is not set, in config value: {}",
+                    name, string
+                ))
+            })?;
+            expanded.push_str(&value);
+            rest = &variable[end + 1..];
+        } else {
+            expanded.push('$');
+            rest = &rest[1..];
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_expand() {
+        let lookup = |name: &str| (name == "BACKEND_ADDR").then(|| "10.0.0.1:8000".to_string());
+        assert_eq!(expand("${BACKEND_ADDR}", lookup).unwrap(), "10.0.0.1:8000");
+        assert_eq!(
+            expand("http://${BACKEND_ADDR}/$path", lookup).unwrap(),
+            "http://10.0.0.1:8000/$path"
+        );
+        assert_eq!(
+            expand("$${BACKEND_ADDR}", lookup).unwrap(),
+            "${BACKEND_ADDR}"
+        );
+        let error = expand("${TLS_KEY}", lookup).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Environment variable TLS_KEY is not set, in config value: ${TLS_KEY}"
+        );
+        assert!(expand("${BACKEND_ADDR", lookup).is_err());
+
+        // Strings within sequences and mappings are expanded, other values are left as they are
+        let mut value: Value = serde_yaml::from_str(
+            r#"
+            timeout: 1000
+            backends:
+              - location: ${BACKEND_ADDR}
+            "#,
+        )
+        .unwrap();
+        expand_env_vars(&mut value, &lookup).unwrap();
+        assert_eq!(value["timeout"], Value::from(1000));
+        assert_eq!(
+            value["backends"][0]["location"],
+            Value::from("10.0.0.1:8000")
+        );
+    }
+}
@@ -0,0 +1,96 @@
+// Static pages served when a backend cannot be reached
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{Context, Error};
+use hyper::body::Bytes;
+use hyper::http::{header, HeaderValue, Response, StatusCode};
+use hyper::Body;
+use serde::{Deserialize, Serialize};
+
+use super::Backend;
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Clone)]
+pub struct FallbackPageConfig {
+    pub path: String,
+    pub status: Option<u16>, // Defaults to 503
+}
+
+pub struct FallbackPage {
+    status: StatusCode,
+    content_type: &'static str,
+    body: Bytes,
+}
+
+impl FallbackPage {
+    pub fn load(config: &FallbackPageConfig) -> Result<FallbackPage, Error> {
+        let status = StatusCode::from_u16(config.status.unwrap_or(503))
+            .with_context(|| format!("Invalid fallback page status: {:?}", config.status))?;
+        let body = std::fs::read(&config.path)
+            .with_context(|| format!("Unable to read fallback page: {}", config.path))?;
+        let content_type = match Path::new(&config.path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+        {
+            Some("html") | Some("htm") => "text/html; charset=utf-8",
+            _ => "text/plain; charset=utf-8",
+        };
+        Ok(FallbackPage {
+            status,
+            content_type,
+            body: Bytes::from(body),
+        })
+    }
+
+    pub fn to_response(&self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(self.content_type),
+        );
+        response
+    }
+}
+
+pub fn load_fallback_pages(backends: &[Backend]) -> Result<HashMap<String, FallbackPage>, Error> {
+    // Fallback pages are read once at startup, keyed by backend name
+    let mut fallback_pages = HashMap::new();
+    for backend in backends {
+        if let (Some(name), Some(fallback_page)) = (&backend.name, &backend.fallback_page) {
+            fallback_pages.insert(name.clone(), FallbackPage::load(fallback_page)?);
+        }
+    }
+    Ok(fallback_pages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_fallback_page_load() {
+        let path = std::env::temp_dir().join("brachyura_test_fallback_page_load.html");
+        std::fs::write(&path, "We'll be right back").unwrap();
+        let fallback_page = FallbackPage::load(&FallbackPageConfig {
+            path: path.to_str().unwrap().to_string(),
+            status: None,
+        })
+        .unwrap();
+
+        let response = fallback_page.to_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/html; charset=utf-8"
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "We'll be right back");
+
+        assert!(FallbackPage::load(&FallbackPageConfig {
+            path: "does/not/exist.html".to_string(),
+            status: None,
+        })
+        .is_err());
+    }
+}
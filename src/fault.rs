@@ -0,0 +1,55 @@
+// Fault injection for resilience testing, only applied with enable_fault_injection
+use std::time::Duration;
+
+use hyper::http::{Response, StatusCode};
+use hyper::Body;
+use log::{debug, warn};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Clone)]
+pub struct FaultInjectionConfig {
+    pub delay: Option<DelayFault>,
+    pub abort: Option<AbortFault>,
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Clone)]
+pub struct DelayFault {
+    pub delay_ms: u64,
+    pub percentage: u32, // Percentage of requests delayed
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Clone)]
+pub struct AbortFault {
+    pub status: u16,
+    pub percentage: u32, // Percentage of requests aborted
+}
+
+fn injected(percentage: u32) -> bool {
+    rand::thread_rng().gen_range(0..100) < percentage
+}
+
+pub async fn inject_delay(config: &FaultInjectionConfig) {
+    if let Some(delay) = &config.delay {
+        if injected(delay.percentage) {
+            debug!("Injecting a delay of {}ms", delay.delay_ms);
+            tokio::time::sleep(Duration::from_millis(delay.delay_ms)).await;
+        }
+    }
+}
+
+pub fn inject_abort(config: &FaultInjectionConfig) -> Option<Response<Body>> {
+    // The response returned in place of contacting the backend
+    let abort = config.abort.as_ref()?;
+    if !injected(abort.percentage) {
+        return None;
+    }
+    let status = StatusCode::from_u16(abort.status).unwrap_or_else(|_| {
+        warn!("Invalid fault injection abort status: {}", abort.status);
+        StatusCode::INTERNAL_SERVER_ERROR
+    });
+    debug!("Injecting an abort with status {}", status);
+    let mut response = Response::new(Body::from("Fault injected"));
+    *response.status_mut() = status;
+    Some(response)
+}
@@ -0,0 +1,268 @@
+// Active health checks, probing each location of a backend on an interval
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use futures::future::join_all;
+use hyper::http::{Request, StatusCode, Uri};
+use hyper::Body;
+use log::{debug, info};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+
+use super::{Backend, ProxyState, SharedProxyConfig};
+
+const DEFAULT_HEALTH_CHECK_INTERVAL: u64 = 10_000;
+const DEFAULT_CONSECUTIVE_FAILURES: u32 = 5;
+const DEFAULT_EJECTION_COOLDOWN: u64 = 30_000;
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Clone)]
+pub struct HealthCheckConfig {
+    pub path: Option<String>,         // Defaults to /
+    pub interval: Option<u64>,        // Milliseconds between checks, defaults to 10000
+    pub expected_status: Option<u16>, // Defaults to 200
+    pub jitter: Option<u64>,          // Probes are delayed by up to this many milliseconds
+    pub concurrency: Option<usize>,   // Maximum probes in flight, defaults to unlimited
+    pub warmup_grace: Option<u64>,    // Milliseconds after starting that failures don't count
+}
+
+impl HealthCheckConfig {
+    fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval.unwrap_or(DEFAULT_HEALTH_CHECK_INTERVAL))
+    }
+}
+
+// Passive health checking, ejecting locations of load balanced backends which proxied
+// requests fail to connect to
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Clone)]
+pub struct PassiveHealthConfig {
+    pub consecutive_failures: Option<u32>, // Failures before ejection, defaults to 5
+    pub cooldown: Option<u64>, // Milliseconds a location is ejected for, defaults to 30000
+}
+
+impl PassiveHealthConfig {
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures
+            .unwrap_or(DEFAULT_CONSECUTIVE_FAILURES)
+            .max(1)
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        Duration::from_millis(self.cooldown.unwrap_or(DEFAULT_EJECTION_COOLDOWN))
+    }
+}
+
+pub fn backend_locations(backend: &Backend) -> Vec<String> {
+    match backend.backend_type.as_deref() {
+        Some("loadbalanced") => backend
+            .locations
+            .iter()
+            .chain(backend.standby_locations.iter())
+            .flatten()
+            .cloned()
+            .collect(),
+        _ => backend.location.iter().cloned().collect(),
+    }
+}
+
+pub async fn check_round<F, Fut>(
+    locations: &[String],
+    config: &HealthCheckConfig,
+    probe: F,
+) -> Vec<(String, bool)>
+where
+    F: Fn(String) -> Fut,
+    Fut: std::future::Future<Output = bool>,
+{
+    // Probes are staggered by a random delay and limited in parallelism,
+    // so that many locations aren't all probed at the same moment
+    let semaphore = config
+        .concurrency
+        .map(|concurrency| Semaphore::new(concurrency.max(1)));
+    let probes = locations.iter().map(|location| {
+        let jitter = match config.jitter {
+            Some(jitter) if jitter > 0 => rand::thread_rng().gen_range(0..jitter),
+            _ => 0,
+        };
+        let semaphore = semaphore.as_ref();
+        let probe = &probe;
+        async move {
+            tokio::time::sleep(Duration::from_millis(jitter)).await;
+            let _permit = match semaphore {
+                Some(semaphore) => Some(semaphore.acquire().await.expect("Semaphore closed")),
+                None => None,
+            };
+            (location.clone(), probe(location.clone()).await)
+        }
+    });
+    join_all(probes).await
+}
+
+pub async fn run_health_checks(
+    proxy_config: SharedProxyConfig,
+    proxy_state: Arc<RwLock<ProxyState>>,
+    backend_name: String,
+    initial: bool, // The first round counts towards readiness, not for a backend added by a reload
+) {
+    // The backend is looked up from the current config each round, so config
+    // reloads are followed and the task ends once the backend is removed
+    info!("Starting health checks for backend: {}", backend_name);
+    let started = Instant::now();
+    let mut initial = initial;
+    loop {
+        let current_config = proxy_config.read().unwrap().clone();
+        let backend = match current_config
+            .config
+            .backends
+            .iter()
+            .find(|backend| backend.name.as_ref() == Some(&backend_name))
+        {
+            Some(backend) => backend,
+            None => break,
+        };
+        let health_check = match &backend.health_check {
+            Some(health_check) => health_check,
+            None => break,
+        };
+        let scheme = backend.scheme.as_deref().unwrap_or("http");
+        let path = health_check.path.as_deref().unwrap_or("/");
+        let expected_status = health_check.expected_status.unwrap_or(200);
+        let http2 = backend.http2.unwrap_or(true);
+
+        let results = check_round(&backend_locations(backend), health_check, |location| {
+            let client = &current_config.client;
+            async move {
+                let uri = Uri::builder()
+                    .scheme(scheme)
+                    .authority(location.as_str())
+                    .path_and_query(path)
+                    .build();
+                let req = match uri {
+                    Ok(uri) => Request::get(uri).body(Body::empty()).unwrap(),
+                    Err(_) => return false,
+                };
+                let status = client.make_request(req, http2).await.status();
+                status == StatusCode::from_u16(expected_status).unwrap_or(StatusCode::OK)
+            }
+        })
+        .await;
+        // Failures during the warmup grace give backends which are still starting time to
+        // come up, rather than marking them unhealthy
+        let warming_up = health_check
+            .warmup_grace
+            .is_some_and(|grace| started.elapsed() < Duration::from_millis(grace));
+        for (location, healthy) in results {
+            debug!(
+                "Health check for backend: {} location: {} healthy: {}",
+                backend_name, location, healthy
+            );
+            if warming_up && !healthy {
+                continue;
+            }
+            record_health_check(&proxy_state, &backend_name, location, healthy);
+        }
+        if initial {
+            complete_initial_health_check(&proxy_state);
+            initial = false;
+        }
+        tokio::time::sleep(health_check.interval()).await;
+    }
+    if initial {
+        complete_initial_health_check(&proxy_state);
+    }
+    info!("Stopping health checks for backend: {}", backend_name);
+}
+
+fn record_health_check(
+    proxy_state: &Arc<RwLock<ProxyState>>,
+    backend_name: &str,
+    location: String,
+    healthy: bool,
+) {
+    // Locations failing their latest health check are skipped by load balancing
+    proxy_state
+        .read()
+        .unwrap()
+        .health
+        .lock()
+        .unwrap()
+        .entry(backend_name.to_string())
+        .or_default()
+        .insert(location, healthy);
+}
+
+fn complete_initial_health_check(proxy_state: &Arc<RwLock<ProxyState>>) {
+    // Readiness waits for every health checked backend to complete a round
+    let _ = proxy_state
+        .read()
+        .unwrap()
+        .initial_health_checks
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |pending| {
+            pending.checked_sub(1)
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    fn test_locations() -> Vec<String> {
+        (0..10).map(|i| format!("127.0.0.1:{}", 8000 + i)).collect()
+    }
+
+    #[tokio::test]
+    async fn test_check_round_jitter() {
+        let config = HealthCheckConfig {
+            path: None,
+            interval: Some(1000),
+            expected_status: None,
+            jitter: Some(500),
+            concurrency: None,
+            warmup_grace: None,
+        };
+        let start_times = Mutex::new(Vec::new());
+        let results = check_round(&test_locations(), &config, |_| {
+            start_times.lock().unwrap().push(Instant::now());
+            async { true }
+        })
+        .await;
+        assert_eq!(results.len(), 10);
+
+        // The probes are spread across the jitter rather than simultaneous
+        let start_times = start_times.into_inner().unwrap();
+        let first = start_times.iter().min().unwrap();
+        let last = start_times.iter().max().unwrap();
+        assert!(
+            last.duration_since(*first) > Duration::from_millis(100),
+            "probes started within {:?}",
+            last.duration_since(*first)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_round_concurrency() {
+        let config = HealthCheckConfig {
+            path: None,
+            interval: None,
+            expected_status: None,
+            jitter: None,
+            concurrency: Some(2),
+            warmup_grace: None,
+        };
+        let in_flight = AtomicUsize::new(0);
+        let max_in_flight = AtomicUsize::new(0);
+        check_round(&test_locations(), &config, |_| async {
+            let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            max_in_flight.fetch_max(current, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            true
+        })
+        .await;
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 2);
+    }
+}
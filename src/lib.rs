@@ -1,29 +1,100 @@
-use anyhow::{Error, Result};
+use anyhow::{Context, Error, Result};
 use axum::{
-    extract::Extension,
+    extract::{ConnectInfo, Extension},
+    handler::Handler,
     http::{uri::Uri, Request, Response},
     routing::get,
     Router,
 };
-use axum_server::tls_rustls::RustlsConfig;
+use axum_server::tls_rustls::RustlsAcceptor;
 use env_logger::Env;
-use hyper::http::{header, header::HeaderName, HeaderValue};
+use hyper::body::HttpBody;
+use hyper::http::{
+    header,
+    header::HeaderName,
+    uri::{Authority, Scheme},
+    HeaderMap, HeaderValue,
+};
 use hyper::{Body, Method, StatusCode, Version};
+use ipnet::IpNet;
 use log::{debug, info, warn};
+use regex::RegexSet;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::convert::Infallible;
-use std::env;
-use std::net::{SocketAddr, SocketAddrV4, ToSocketAddrs};
-use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::{Infallible, TryFrom};
+use std::net::{IpAddr, SocketAddr, SocketAddrV4, ToSocketAddrs};
+use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::OwnedSemaphorePermit;
 
+mod access_log;
+mod admin;
+mod auth;
+mod body;
+mod cache;
+mod capture;
 mod client;
+mod compression;
+mod concurrency;
+mod connection;
+mod cookie;
+mod debug;
+mod drain;
+mod env;
+mod fallback;
+mod fault;
+mod health;
+mod limits;
+mod listener;
 mod metrics;
+mod reload;
+mod retry;
 mod routing;
-use crate::client::Client;
-use crate::metrics::{encode_metrics, record_metrics};
-use crate::routing::router;
+mod telemetry;
+mod tls;
+mod upstream_proxy;
+mod well_known;
+use crate::access_log::{AccessLog, ResponseContext};
+use crate::admin::{bearer_token_valid, run_admin_server, AdminConfig};
+use crate::auth::{bearer_token, JwtConfig, JwtVerifier, OnInvalidToken};
+use crate::cache::{cacheable_request, stale_if_error, ResponseCache, StaleIfErrorConfig};
+use crate::capture::{load_captures, Capture, CaptureConfig, Direction};
+use crate::client::{BackendError, Client, ClientConfig, Timeouts};
+use crate::compression::{accepted_encoding, compress_response, CompressionConfig};
+use crate::concurrency::{hold_until_complete, ConcurrencyLimits};
+use crate::cookie::rewrite_set_cookies;
+use crate::debug::{route_debug_requested, route_debug_response};
+use crate::drain::{hold_until_drained, InFlight, InFlightGuard};
+use crate::env::expand_env_vars;
+use crate::fallback::{load_fallback_pages, FallbackPage, FallbackPageConfig};
+use crate::fault::{inject_abort, inject_delay, FaultInjectionConfig};
+use crate::health::{run_health_checks, HealthCheckConfig, PassiveHealthConfig};
+use crate::limits::{limit_request_body_time, limit_response_body};
+use crate::listener::{
+    ConnectionId, ConnectionIdAcceptor, ConnectionLimitAcceptor, KeepaliveAcceptor, KeepaliveConfig,
+};
+use crate::metrics::{
+    encode_metrics, observe_request_size, observe_response_size, record_metrics,
+    record_proxy_error, InFlightRequest, MetricsConfig, ProxyError,
+};
+#[cfg(unix)]
+use crate::reload::reload_on_sighup;
+use crate::reload::Reloader;
+use crate::retry::{
+    buffer_body, clone_request, retry_delay, retryable_error, retryable_request,
+    RetriesExhaustedResponse, RetryBudget, RetryBudgetConfig,
+};
+use crate::routing::{
+    host_matches, peek_router, record_connect_result, record_health, record_latency,
+    retry_location, router, strip_path_prefix, trailing_slash_redirect,
+};
+use crate::telemetry::{init_tracing, RequestSpan, TracingConfig};
+use crate::tls::{
+    reload_certificates, rustls_config, ClientCertAcceptor, ClientCertificate, HandshakeAcceptor,
+    SniAcceptor, SniHostname, TlsConfig,
+};
+use crate::well_known::WellKnown;
 
 #[allow(clippy::declare_interior_mutable_const)]
 const HOP_BY_HOP_HEADERS: [HeaderName; 8] = [
@@ -37,20 +108,122 @@ const HOP_BY_HOP_HEADERS: [HeaderName; 8] = [
     header::PROXY_AUTHENTICATE,
 ];
 
+// Headers set by the proxy, which aren't removed when a client names them in Connection
+#[allow(clippy::declare_interior_mutable_const)]
+const PROXY_MANAGED_HEADERS: [HeaderName; 8] = [
+    HeaderName::from_static("x-forwarded-for"),
+    HeaderName::from_static("x-forwarded-proto"),
+    HeaderName::from_static("x-forwarded-host"),
+    header::VIA,
+    HeaderName::from_static("x-no-proxy"),
+    HeaderName::from_static("x-client-cert"),
+    HeaderName::from_static("x-client-cert-subject"),
+    HeaderName::from_static("x-proxy-connection-id"),
+];
+
+// The methods routed to the proxy handler
+const ALLOWED_METHODS: &str = "GET, HEAD, POST, PUT";
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 struct Config {
     listen: SocketAddrV4,
-    tls: HashMap<String, String>,
-    timeout: Option<u64>,
+    listen_http: Option<SocketAddrV4>, // An optional plaintext listener, alongside the TLS one
+    tls: TlsConfig,
+    timeout: Option<u64>,         // The request_timeout, if that isn't set
+    connect_timeout: Option<u64>, // Milliseconds to establish a backend connection
+    request_timeout: Option<u64>, // Milliseconds for a whole backend request, defaults to 60000
+    request_deadline: Option<u64>,
+    request_body_timeout: Option<u64>, // Milliseconds the client can stall sending the body for
+    access_log: Option<bool>,
+    via_header: Option<ViaHeader>,
+    via_pseudonym: Option<String>, // Defaults to brachyura
+    http10: Option<Http10>,
+    forward_client_cert: Option<bool>, // Forward the client certificate as X-Client-Cert
+    forward_connection_id: Option<bool>, // Forward the client connection as X-Proxy-Connection-Id
+    fold_request_headers: Option<Vec<String>>, // Repeated headers joined into a single header
+    split_request_headers: Option<Vec<String>>, // List headers split into a header per value
+    max_header_count: Option<usize>,
+    max_header_value_bytes: Option<usize>, // The longest value of any single header
+    max_uri_length: Option<usize>,
+    collapse_slashes: Option<bool>, // Collapse repeated slashes in paths before routing
+    blocked_paths: Option<Vec<String>>, // Regexes of paths refused with a 403 for every host
+    strict_hostname_validation: Option<bool>, // Reject hosts which aren't valid hostnames
+    sni_host_fallback: Option<bool>, // Route HTTP/2 requests without a host by the TLS SNI
+    reject_get_body: Option<bool>,
+    keepalive: Option<KeepaliveConfig>,
+    max_connections_per_ip: Option<usize>,
+    enable_route_debug: Option<bool>,
+    enable_fault_injection: Option<bool>, // Required for backend fault_injection to apply
+    wait_for_initial_health: Option<bool>, // /readyz is 503 until the first health checks complete
+    favicon_path: Option<String>,         // Served for /favicon.ico rather than proxying
+    robots_txt: Option<String>,           // Served for /robots.txt rather than proxying
+    admin: Option<AdminConfig>,
+    #[serde(default)]
+    client: ClientConfig,
+    internal_endpoints: Option<InternalEndpoints>,
+    metrics_token: Option<String>, // Required as a bearer token to scrape /metrics
+    local_zone: Option<String>,    // The zone the proxy runs in
+    jwt: Option<JwtConfig>,        // Verification of tokens for backends with match_claims
+    #[serde(default)]
+    retry_budget: RetryBudgetConfig,
+    #[serde(default)]
+    metrics: MetricsConfig,
+    tracing: Option<TracingConfig>, // OpenTelemetry spans, with the otel feature
     backends: Vec<Backend>,
 }
 
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct InternalEndpoints {
+    allow_hosts: Option<Vec<String>>,
+    allow_cidrs: Option<Vec<IpNet>>,
+}
+
 #[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Clone)]
 pub struct Backend {
     name: Option<String>,
     location: Option<String>,
     backend_type: Option<String>,
     locations: Option<Vec<String>>,
+    standby_locations: Option<Vec<String>>, // Used only once all of the locations are unhealthy
+    path_prefix: Option<String>, // Only requests with the path prefix, the longest prefix wins
+    trailing_slash: Option<TrailingSlash>,
+    strip_prefix: Option<bool>, // Remove the path_prefix from the forwarded path
+    weights: Option<Vec<u32>>,  // Relative weight of each of the locations, in the same order
+    match_claims: Option<HashMap<String, String>>, // Claims required of the request's JWT
+    allowed_methods: Option<Vec<String>>, // Other methods are refused, defaults to all
+    stale_if_error: Option<StaleIfErrorConfig>,
+    fallback_page: Option<FallbackPageConfig>,
+    slo_target_ms: Option<u64>,
+    drain_timeout: Option<u64>,
+    max_response_bytes: Option<u64>,
+    location_zones: Option<HashMap<String, String>>, // Zone of each location
+    path_encoding: Option<PathEncoding>,
+    default_content_type: Option<String>, // Set on responses without a Content-Type
+    preserve_host: Option<bool>, // Forward the original host as Host, also for HTTP/2 requests
+    set_request_headers: Option<HashMap<String, String>>, // An empty value removes the header
+    set_response_headers: Option<HashMap<String, String>>,
+    rewrite_cookie_domain: Option<bool>, // Set-Cookie domains are rewritten to the public host
+    rewrite_cookie_path: Option<String>, // And their paths to this path
+    normalize_empty_200_to_204: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_status_remap")]
+    status_remap: Option<HashMap<u16, u16>>, // Backend statuses replaced for the client
+    scheme: Option<String>,               // http (the default) or https
+    http2: Option<bool>, // Offer HTTP/2 via ALPN to HTTPS backends, defaults to true
+    retries: Option<u32>, // Retries of requests failing to reach the backend, defaults to 0
+    retry_buffer_bytes: Option<u64>, // Bodies up to this size are buffered to be retried
+    retry_backoff: Option<u64>, // Milliseconds before the first retry, doubling each retry
+    retry_jitter: Option<u64>, // Up to this many random milliseconds added to the backoff
+    shared_request_timeout: Option<bool>, // The request_timeout bounds all attempts together
+    retries_exhausted_response: Option<RetriesExhaustedResponse>,
+    max_concurrent: Option<usize>, // Requests in flight to each location at once
+    max_concurrent_wait: Option<u64>, // Milliseconds to queue at the limit, rather than a 503
+    max_connection_age: Option<u64>, // Milliseconds before upstream connections are retired
+    health_check: Option<HealthCheckConfig>,
+    passive_health: Option<PassiveHealthConfig>,
+    fault_injection: Option<FaultInjectionConfig>, // For testing only
+    preserve_header_order: Option<bool>,           // Keep the response header order as received
+    compression: Option<CompressionConfig>,
+    capture: Option<CaptureConfig>, // Record request and response bodies to a sink
     #[serde(flatten)]
     extras: HashMap<String, String>,
 }
@@ -58,55 +231,526 @@ pub struct Backend {
 struct ProxyConfig {
     config: Config,
     client: Client,
+    response_caches: HashMap<String, ResponseCache>, // Per backend with stale_if_error
+    fallback_pages: HashMap<String, FallbackPage>,
+    captures: HashMap<String, Capture>,
+    well_known: WellKnown,
+    jwt: Option<JwtVerifier>,
+    retry_budget: RetryBudget,                 // Shared by all backends
+    in_flight: HashMap<String, Arc<InFlight>>, // In-flight requests per backend
+    blocked_paths: RegexSet,
 }
 impl ProxyConfig {
-    fn new(config: Config, client: Client) -> ProxyConfig {
-        ProxyConfig { config, client }
+    fn new(config: Config, client: Client) -> Result<ProxyConfig, Error> {
+        let fallback_pages = load_fallback_pages(&config.backends)?;
+        let captures = load_captures(&config.backends)?;
+        for backend in &config.backends {
+            if let Some(retries_exhausted_response) = &backend.retries_exhausted_response {
+                retries_exhausted_response.status()?;
+            }
+            validate_set_headers(backend)?;
+            // A limit of 0 would refuse every request to the backend
+            if backend.max_concurrent == Some(0) {
+                return Err(Error::msg("max_concurrent must be at least 1"));
+            }
+            for (from, to) in backend.status_remap.iter().flatten() {
+                for status in [from, to] {
+                    StatusCode::from_u16(*status)
+                        .with_context(|| format!("Invalid status_remap status: {}", status))?;
+                }
+            }
+        }
+        let well_known =
+            WellKnown::load(config.favicon_path.as_deref(), config.robots_txt.as_deref())?;
+        let jwt = config.jwt.as_ref().map(JwtVerifier::load).transpose()?;
+        let blocked_paths = RegexSet::new(config.blocked_paths.iter().flatten())?;
+        validate_fold_headers(&config)?;
+        // The plaintext listener has no TLS, so it can't require client certificates
+        if config.listen_http.is_some() && config.tls.client_ca_path.is_some() {
+            return Err(Error::msg(
+                "listen_http can't be used with tls.client_ca_path, as plaintext clients can't present a certificate",
+            ));
+        }
+        let mut response_caches = HashMap::new();
+        for backend in &config.backends {
+            if let Some(stale_if_error) = &backend.stale_if_error {
+                response_caches
+                    .entry(backend.name.clone().unwrap_or_default())
+                    .or_insert_with(|| ResponseCache::new(stale_if_error));
+            }
+        }
+        let in_flight = config
+            .backends
+            .iter()
+            .filter_map(|backend| backend.name.clone())
+            .map(|name| (name, InFlight::new()))
+            .collect();
+        Ok(ProxyConfig {
+            retry_budget: RetryBudget::new(&config.retry_budget),
+            config,
+            client,
+            response_caches,
+            fallback_pages,
+            captures,
+            well_known,
+            jwt,
+            in_flight,
+            blocked_paths,
+        })
     }
 }
 
+// The current proxy config, replaced when the config is reloaded
+type SharedProxyConfig = Arc<RwLock<Arc<ProxyConfig>>>;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ViaHeader {
+    Append, // Add the proxy to the Via header of requests and responses
+    Strip,  // Remove any Via header
+    #[default]
+    Off, // Leave the Via header unchanged
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Http10 {
+    #[default]
+    Allow, // Proxy HTTP/1.0 requests, recorded as HTTP/1.0 in the Via header
+    Reject,  // Respond to HTTP/1.0 requests with a 505
+    Upgrade, // Handle HTTP/1.0 requests as HTTP/1.1 from the start
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PathEncoding {
+    #[default]
+    Preserve, // Forward the path exactly as received
+    Normalize, // Decode unreserved characters and uppercase percent-encodings
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrailingSlash {
+    #[default]
+    Strict, // A path_prefix of /api/ doesn't match /api
+    Ignore,   // /api and /api/ match either prefix
+    Redirect, // As ignore, but the prefix in the other form is redirected to the configured form
+}
+
 #[derive(Debug)]
 pub struct BackendState {
-    rr_count: isize, // Round robin counter
+    rr_count: AtomicIsize,                             // Round robin counter
+    current_weights: Mutex<HashMap<String, i64>>,      // Smooth weighted round robin state
+    latencies: Mutex<HashMap<String, f64>>,            // Moving average latency (ms) per location
+    unhealthy_until: Mutex<HashMap<String, Instant>>,  // Locations which recently failed to respond
+    consecutive_failures: Mutex<HashMap<String, u32>>, // Connection failures per location
+}
+
+impl BackendState {
+    fn new() -> BackendState {
+        BackendState {
+            rr_count: AtomicIsize::new(-1),
+            current_weights: Mutex::new(HashMap::new()),
+            latencies: Mutex::new(HashMap::new()),
+            unhealthy_until: Mutex::new(HashMap::new()),
+            consecutive_failures: Mutex::new(HashMap::new()),
+        }
+    }
 }
+
 pub struct ProxyState {
     backends: HashMap<String, Option<BackendState>>,
+    initial_health_checks: AtomicUsize, // Backends yet to complete their first health checks
+    health: Mutex<BTreeMap<String, BTreeMap<String, bool>>>, // Health check result per location
+    concurrency_limits: Arc<ConcurrencyLimits>,
 }
 
 impl ProxyState {
     fn new(config: &Config) -> ProxyState {
+        let initial_health_checks = config
+            .backends
+            .iter()
+            .filter(|backend| backend.name.is_some() && backend.health_check.is_some())
+            .count();
+        let mut proxy_state = ProxyState {
+            backends: HashMap::new(),
+            initial_health_checks: AtomicUsize::new(initial_health_checks),
+            health: Mutex::new(BTreeMap::new()),
+            concurrency_limits: ConcurrencyLimits::new(),
+        };
+        proxy_state.update(config);
+        proxy_state
+    }
+
+    fn update(&mut self, config: &Config) {
+        // Replace the backends state for a new config, keeping the existing
+        // state of any load balanced backends which are still configured
         let mut backends: HashMap<String, Option<BackendState>> = HashMap::new();
 
         for backend_config in &config.backends {
-            if backend_config.backend_type.as_deref() == Some("loadbalanced")
-                && backend_config.name.is_some()
-            {
-                backends.insert(
-                    backend_config.name.clone().unwrap(),
-                    Some(BackendState { rr_count: -1 }),
-                );
-            } else if backend_config.name.is_some() {
-                backends.insert(backend_config.name.clone().unwrap(), None);
+            let name = match &backend_config.name {
+                Some(name) => name.clone(),
+                None => continue,
+            };
+            // Backends sharing a name (matching different claims) share the state
+            if backend_config.backend_type.as_deref() == Some("loadbalanced") {
+                if let Some(Some(_)) = backends.get(&name) {
+                    continue;
+                }
+                let backend_state = match self.backends.remove(&name) {
+                    Some(Some(backend_state)) => backend_state,
+                    _ => BackendState::new(),
+                };
+                backends.insert(name, Some(backend_state));
+            } else {
+                backends.entry(name).or_insert(None);
             }
         }
-        ProxyState { backends }
+        self.backends = backends;
+
+        // Results are dropped for backends no longer health checked
+        self.health.get_mut().unwrap().retain(|name, _| {
+            config.backends.iter().any(|backend| {
+                backend.name.as_ref() == Some(name) && backend.health_check.is_some()
+            })
+        });
+        self.concurrency_limits.retain_backends(|name| {
+            config.backends.iter().any(|backend| {
+                backend.name.as_deref() == Some(name) && backend.max_concurrent.is_some()
+            })
+        });
+    }
+}
+
+impl Config {
+    fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            connect: self.connect_timeout,
+            request: self.request_timeout.or(self.timeout),
+        }
     }
 }
 
-async fn read_proxy_config_yaml(yaml_path: String) -> Result<Config, serde_yaml::Error> {
-    let deserialized: Config =
-        serde_yaml::from_reader(std::fs::File::open(yaml_path).expect("Unable to read config"))?;
+async fn read_proxy_config(config_path: String) -> Result<Config, Error> {
+    read_proxy_config_with_env(config_path, |name| std::env::var(name).ok()).await
+}
+
+async fn read_proxy_config_with_env(
+    config_path: String,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<Config, Error> {
+    // TOML for a .toml file, otherwise YAML (.yaml or .yml), with ${NAME} given by lookup
+    let contents = std::fs::read_to_string(&config_path)
+        .map_err(|e| anyhow::Error::msg(format!("Unable to read config {}: {}", config_path, e)))?;
+    let format = if config_path.ends_with(".toml") {
+        "TOML"
+    } else {
+        "YAML"
+    };
+    let parse_error = |e: &dyn std::fmt::Display| {
+        anyhow::Error::msg(format!(
+            "Unable to parse {} config {}: {}",
+            format, config_path, e
+        ))
+    };
+    // Both are parsed into a YAML value, for environment variables to be expanded
+    let mut value: serde_yaml::Value = match format {
+        "TOML" => {
+            let value: toml::Value = toml::from_str(&contents).map_err(|e| parse_error(&e))?;
+            serde_yaml::to_value(value).map_err(|e| parse_error(&e))?
+        }
+        _ => serde_yaml::from_str(&contents).map_err(|e| parse_error(&e))?,
+    };
+    expand_env_vars(&mut value, &lookup)?;
+    let deserialized: Config = serde_yaml::from_value(value).map_err(|e| parse_error(&e))?;
     Ok(deserialized)
 }
 
-async fn adjust_proxied_headers(req: &mut Request<Body>) -> Result<(), Error> {
+fn set_headers(
+    headers: &mut HeaderMap,
+    set_headers: &HashMap<String, String>,
+) -> Result<(), Error> {
+    // Headers are replaced, or removed when set to an empty value
+    for (name, value) in set_headers {
+        let name = HeaderName::from_bytes(name.as_bytes())?;
+        if value.is_empty() {
+            headers.remove(name);
+        } else {
+            headers.insert(name, HeaderValue::from_str(value)?);
+        }
+    }
+    Ok(())
+}
+
+fn validate_set_headers(backend: &Backend) -> Result<(), Error> {
+    // The Host and x-no-proxy headers are required to route the request and avoid loops
+    if let Some(set_request_headers) = &backend.set_request_headers {
+        if let Some(name) = set_request_headers.keys().find(|name| {
+            name.eq_ignore_ascii_case("host") || name.eq_ignore_ascii_case("x-no-proxy")
+        }) {
+            return Err(Error::msg(format!(
+                "The {} header can't be set by set_request_headers",
+                name
+            )));
+        }
+    }
+    for set in backend
+        .set_request_headers
+        .iter()
+        .chain(backend.set_response_headers.iter())
+    {
+        set_headers(&mut HeaderMap::new(), set)?;
+    }
+    Ok(())
+}
+
+fn deserialize_status_remap<'de, D>(deserializer: D) -> Result<Option<HashMap<u16, u16>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    // TOML keys are always strings, so the statuses remapped may be numbers or strings
+    let status_remap: Option<HashMap<serde_yaml::Value, u16>> = Option::deserialize(deserializer)?;
+    status_remap
+        .map(|status_remap| {
+            status_remap
+                .into_iter()
+                .map(|(from, to)| {
+                    let status = match &from {
+                        serde_yaml::Value::Number(status) => status.as_u64(),
+                        serde_yaml::Value::String(status) => status.parse().ok(),
+                        _ => None,
+                    };
+                    status
+                        .and_then(|status| u16::try_from(status).ok())
+                        .map(|status| (status, to))
+                        .ok_or_else(|| {
+                            serde::de::Error::custom(format!(
+                                "Invalid status_remap status: {:?}",
+                                from
+                            ))
+                        })
+                })
+                .collect()
+        })
+        .transpose()
+}
+
+fn header_list_separator(name: &HeaderName) -> &'static str {
+    // Cookie pairs are separated by semicolons rather than the usual commas
+    if name == header::COOKIE {
+        "; "
+    } else {
+        ", "
+    }
+}
+
+fn fold_headers(headers: &mut HeaderMap, names: &[String]) -> Result<(), Error> {
+    // Joins the values of each repeated header into a single header
+    for name in names {
+        let name = HeaderName::from_bytes(name.as_bytes())?;
+        // Joined as bytes, so values with obs-text (non UTF-8 bytes) are kept
+        let values: Vec<&[u8]> = headers
+            .get_all(&name)
+            .iter()
+            .map(HeaderValue::as_bytes)
+            .collect();
+        if values.len() > 1 {
+            let separator = header_list_separator(&name).as_bytes();
+            let folded = HeaderValue::from_bytes(&values.join(separator))?;
+            headers.insert(name, folded);
+        }
+    }
+    Ok(())
+}
+
+fn split_headers(headers: &mut HeaderMap, names: &[String]) -> Result<(), Error> {
+    // Splits list headers into a header per value, which is only correct for headers
+    // whose values can't themselves contain the separator
+    for name in names {
+        let name = HeaderName::from_bytes(name.as_bytes())?;
+        let separator = header_list_separator(&name).as_bytes()[0];
+        let values: Vec<HeaderValue> = headers
+            .get_all(&name)
+            .iter()
+            .flat_map(|value| value.as_bytes().split(move |byte| *byte == separator))
+            .map(<[u8]>::trim_ascii)
+            .filter(|value| !value.is_empty())
+            .map(HeaderValue::from_bytes)
+            .collect::<Result<_, _>>()?;
+        if values.is_empty() {
+            continue;
+        }
+        headers.remove(&name);
+        for value in values {
+            headers.append(&name, value);
+        }
+    }
+    Ok(())
+}
+
+fn validate_fold_headers(config: &Config) -> Result<(), Error> {
+    let fold = config.fold_request_headers.as_deref().unwrap_or_default();
+    let split = config.split_request_headers.as_deref().unwrap_or_default();
+    if let Some(name) = fold
+        .iter()
+        .find(|name| split.iter().any(|split| split.eq_ignore_ascii_case(name)))
+    {
+        return Err(Error::msg(format!(
+            "The {} header can't be both folded and split",
+            name
+        )));
+    }
+    // Cookie values may contain commas, and RFC 6265 (5.4) requires a single Cookie header
+    if split
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(header::COOKIE.as_str()))
+    {
+        return Err(Error::msg("The cookie header can't be split"));
+    }
+    fold_headers(&mut HeaderMap::new(), fold)?;
+    split_headers(&mut HeaderMap::new(), split)
+}
+
+fn restore_header_order(headers: &mut HeaderMap, order: &[HeaderName]) {
+    // Removing headers from a HeaderMap can move others, so the map is rebuilt with the
+    // headers in their original order followed by any added by the proxy. Repeated
+    // headers are kept together, as hyper groups the values of each name
+    let mut original = std::mem::take(headers);
+    for name in order {
+        for value in original.get_all(name) {
+            headers.append(name, value.clone());
+        }
+        original.remove(name);
+    }
+    let mut current_name = None;
+    for (name, value) in original {
+        if let Some(name) = name {
+            current_name = Some(name);
+        }
+        if let Some(name) = &current_name {
+            headers.append(name, value);
+        }
+    }
+}
+
+fn adjust_via_header(headers: &mut HeaderMap, version: Version, config: &Config) {
+    match config.via_header.unwrap_or_default() {
+        ViaHeader::Append => {
+            let protocol = match version {
+                Version::HTTP_10 => "1.0",
+                Version::HTTP_2 => "2",
+                _ => "1.1",
+            };
+            let pseudonym = config.via_pseudonym.as_deref().unwrap_or("brachyura");
+            // Appended to the list of any existing Via headers as a single header
+            let mut via: Vec<&str> = headers
+                .get_all(header::VIA)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .collect();
+            let proxy_via = format!("{} {}", protocol, pseudonym);
+            via.push(&proxy_via);
+            match HeaderValue::from_str(&via.join(", ")) {
+                Ok(via) => {
+                    headers.insert(header::VIA, via);
+                }
+                Err(e) => warn!("Invalid via_pseudonym: {e}"),
+            }
+        }
+        ViaHeader::Strip => {
+            headers.remove(header::VIA);
+        }
+        ViaHeader::Off => {}
+    }
+}
+
+async fn adjust_proxied_headers(
+    req: &mut Request<Body>,
+    config: &Config,
+    client_addr: SocketAddr,
+    original_host: Option<&str>,
+) -> Result<(), Error> {
     // Adjust headers for a request which is being proxied downstream
+    let version = req.version();
 
-    // Remove hop by hop headers
+    // Remove hop by hop headers, including any the client named in its Connection header
+    // (e.g. HTTP/1.0 clients sending Connection: keep-alive with a Keep-Alive header). This is
+    // done before the proxy adds its own headers, and host and the headers the proxy manages
+    // are never removed, so that a client can't strip them by naming them
+    let connection_headers: Vec<HeaderName> = req
+        .headers()
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .filter(|name| name != header::HOST && !PROXY_MANAGED_HEADERS.contains(name))
+        .collect();
+    for h in connection_headers {
+        req.headers_mut().remove(h);
+    }
     for h in HOP_BY_HOP_HEADERS {
         req.headers_mut().remove(h);
     }
 
+    // Extend any existing X-Forwarded-For chain with the client address
+    let forwarded_for = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .chain(std::iter::once(client_addr.ip().to_string().as_str()))
+        .collect::<Vec<&str>>()
+        .join(", ");
+    req.headers_mut()
+        .insert("x-forwarded-for", HeaderValue::from_str(&forwarded_for)?);
+    // The scheme of the listener which received the request, the TLS listener unless set
+    let proto = req
+        .extensions()
+        .get::<Scheme>()
+        .map_or("https", Scheme::as_str)
+        .to_string();
+    req.headers_mut()
+        .insert("x-forwarded-proto", HeaderValue::from_str(&proto)?);
+    if let Some(original_host) = original_host {
+        req.headers_mut()
+            .insert("x-forwarded-host", HeaderValue::from_str(original_host)?);
+    }
+    adjust_via_header(req.headers_mut(), version, config);
+
+    // Any client supplied header is replaced, so that backends can trust it
+    if config.forward_client_cert == Some(true) {
+        req.headers_mut().remove("x-client-cert");
+        req.headers_mut().remove("x-client-cert-subject");
+        let client_cert = req.extensions().get::<ClientCertificate>();
+        let escaped_pem = client_cert.and_then(ClientCertificate::escaped_pem);
+        let subject = client_cert.and_then(ClientCertificate::subject);
+        if let Some(escaped_pem) = escaped_pem {
+            req.headers_mut()
+                .insert("x-client-cert", HeaderValue::from_str(&escaped_pem)?);
+        }
+        if let Some(subject) = subject {
+            req.headers_mut()
+                .insert("x-client-cert-subject", HeaderValue::from_str(&subject)?);
+        }
+    }
+
+    if config.forward_connection_id == Some(true) {
+        req.headers_mut().remove("x-proxy-connection-id");
+        if let Some(connection_id) = req.extensions().get::<ConnectionId>().copied() {
+            req.headers_mut()
+                .insert("x-proxy-connection-id", HeaderValue::from(connection_id.0));
+        }
+    }
+
+    if let Some(fold_request_headers) = &config.fold_request_headers {
+        fold_headers(req.headers_mut(), fold_request_headers)?;
+    }
+    if let Some(split_request_headers) = &config.split_request_headers {
+        split_headers(req.headers_mut(), split_request_headers)?;
+    }
+
     // Append a no-proxy header to avoid loops
     req.headers_mut()
         .insert("x-no-proxy", HeaderValue::from_static("true"));
@@ -114,7 +758,7 @@ async fn adjust_proxied_headers(req: &mut Request<Body>) -> Result<(), Error> {
     Ok(())
 }
 
-fn get_host_header(req: &Request<Body>) -> Result<&str, Error> {
+fn get_host_header(req: &Request<Body>, sni_fallback: bool) -> Result<&str, Error> {
     match req.version() {
         Version::HTTP_09 | Version::HTTP_10 | Version::HTTP_11 => Ok(req
             .headers()
@@ -126,17 +770,52 @@ fn get_host_header(req: &Request<Body>) -> Result<&str, Error> {
                 ))
             })?
             .to_str()?),
-        _ => Ok(req
-            .uri()
-            .authority()
-            .ok_or_else(|| {
-                anyhow::Error::msg(format!(
-                    "Unable to parse host header, version: {:?}",
-                    req.version()
-                ))
-            })
-            .unwrap()
-            .as_ref()),
+        // HTTP/2 requests have an authority, though clients may send a host header instead
+        _ => {
+            if let Some(authority) = req.uri().authority() {
+                return Ok(authority.as_str());
+            }
+            if let Some(host) = req.headers().get("host") {
+                return Ok(host.to_str()?);
+            }
+            req.extensions()
+                .get::<SniHostname>()
+                .and_then(|sni_hostname| sni_hostname.0.as_deref())
+                .filter(|_| sni_fallback)
+                .ok_or_else(|| {
+                    anyhow::Error::msg(format!(
+                        "Unable to parse host header, version: {:?}",
+                        req.version()
+                    ))
+                })
+        }
+    }
+}
+
+fn has_body(req: &Request<Body>) -> bool {
+    // A request has a body if it is chunked or has a non zero (or invalid) content length
+    let content_length = req.headers().get(header::CONTENT_LENGTH);
+    req.headers().contains_key(header::TRANSFER_ENCODING)
+        || content_length.is_some_and(|content_length| content_length != "0")
+}
+
+fn record_location_health(
+    proxy_state: &Arc<RwLock<ProxyState>>,
+    backend: &Backend,
+    location: &str,
+    backend_error: Option<&BackendError>,
+) {
+    if let (Some(_), Some(name)) = (&backend.location_zones, &backend.name) {
+        // Locations failing to respond are avoided by zone aware selection
+        record_health(proxy_state, name, location, backend_error.is_none());
+    }
+    if let (Some(passive_health), Some(name)) = (&backend.passive_health, &backend.name) {
+        // Invalid responses count against a location as connection failures do
+        let connected = !matches!(
+            backend_error,
+            Some(BackendError::Connect) | Some(BackendError::Protocol)
+        );
+        record_connect_result(proxy_state, name, location, connected, passive_health);
     }
 }
 
@@ -146,15 +825,345 @@ fn host_header_set(host_header: String) -> bool {
     host_header.to_socket_addrs().is_err()
 }
 
-fn bad_request_handler(mut response: Response<Body>, message: String) -> Response<Body> {
+fn valid_hostname(host: &str) -> bool {
+    // RFC 1123 hostnames (letters, digits and hyphens) or IP addresses, so underscores are invalid
+    let host = strip_port(host);
+    if let Some(address) = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+    {
+        return address.parse::<std::net::Ipv6Addr>().is_ok();
+    }
+    host.split('.').all(|label| {
+        (1..=63).contains(&label.len())
+            && label
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-')
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+    })
+}
+
+fn strip_port(host: &str) -> &str {
+    // Remove any port from a host / authority, taking care with bracketed IPv6 addresses
+    match host.rfind(':') {
+        Some(index) if !host[index..].contains(']') => &host[..index],
+        _ => host,
+    }
+}
+
+fn collapse_slashes(path: &str) -> String {
+    // Repeated slashes are replaced with a single slash, e.g. /api//users becomes /api/users
+    let mut collapsed = String::with_capacity(path.len());
+    for c in path.chars() {
+        if !(c == '/' && collapsed.ends_with('/')) {
+            collapsed.push(c);
+        }
+    }
+    collapsed
+}
+
+fn canonical_path(path: &str) -> String {
+    // The path as a backend may interpret it, percent-decoded with repeated slashes collapsed
+    // and dot segments resolved, e.g. /a/..//%2egit becomes /.git. Used to match paths, so
+    // that differently encoded forms of a path can't avoid a match
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = match (bytes[i], bytes.get(i + 1..i + 3)) {
+            (b'%', Some(hex)) => std::str::from_utf8(hex)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match byte {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    let decoded = collapse_slashes(&String::from_utf8_lossy(&decoded));
+    if !decoded.starts_with('/') {
+        return decoded;
+    }
+    let mut segments = Vec::new();
+    for segment in decoded[1..].split('/') {
+        match segment {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    // A final dot segment leaves the path ending in a slash
+    if decoded.ends_with("/.") || decoded.ends_with("/..") {
+        segments.push("");
+    }
+    format!("/{}", segments.join("/"))
+}
+
+fn normalize_percent_encoding(path: &str) -> Result<String, Error> {
+    // RFC 3986 normalization, reserved characters such as %2F remain encoded
+    let bytes = path.as_bytes();
+    let mut normalized = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let decoded = match (bytes[i], bytes.get(i + 1..i + 3)) {
+            (b'%', Some(hex)) => std::str::from_utf8(hex)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match decoded {
+            Some(byte) if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) => {
+                normalized.push(byte);
+                i += 3;
+            }
+            Some(byte) => {
+                normalized.extend_from_slice(format!("%{:02X}", byte).as_bytes());
+                i += 3;
+            }
+            None => {
+                normalized.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    Ok(String::from_utf8(normalized)?)
+}
+
+fn internal_endpoint_allowed(config: &Config, host_header: &str, client_ip: IpAddr) -> bool {
+    // Internal endpoints are open to any x-no-proxy request unless an allowlist is configured,
+    // in which case the request must match an allowed host or come from an allowed CIDR
+    let internal_endpoints = match &config.internal_endpoints {
+        Some(internal_endpoints) => internal_endpoints,
+        None => return true,
+    };
+
+    let host = strip_port(host_header);
+    let host_allowed = internal_endpoints
+        .allow_hosts
+        .iter()
+        .flatten()
+        .any(|allowed_host| allowed_host.eq_ignore_ascii_case(host));
+    let cidr_allowed = internal_endpoints
+        .allow_cidrs
+        .iter()
+        .flatten()
+        .any(|allowed_cidr| allowed_cidr.contains(&client_ip));
+
+    host_allowed || cidr_allowed
+}
+
+fn bad_request_handler(response: Response<Body>, message: String) -> Response<Body> {
+    error_handler(response, StatusCode::BAD_REQUEST, message)
+}
+
+fn error_handler(
+    mut response: Response<Body>,
+    status: StatusCode,
+    message: String,
+) -> Response<Body> {
     *response.body_mut() = Body::from(message);
-    *response.status_mut() = StatusCode::BAD_REQUEST;
+    *response.status_mut() = status;
+    response
+}
+
+fn status_handler(mut response: Response<Body>) -> Response<Body> {
+    *response.body_mut() = Body::from("The proxy is running");
+    response
+}
+
+fn readyz_handler(
+    mut response: Response<Body>,
+    config: &Config,
+    proxy_state: &Arc<RwLock<ProxyState>>,
+) -> Response<Body> {
+    let pending = proxy_state
+        .read()
+        .unwrap()
+        .initial_health_checks
+        .load(Ordering::SeqCst);
+    if config.wait_for_initial_health == Some(true) && pending > 0 {
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        *response.body_mut() = Body::from("Waiting for initial health checks");
+    } else {
+        *response.body_mut() = Body::from("Ready");
+    }
+    response
+}
+
+fn health_handler(
+    mut response: Response<Body>,
+    proxy_state: &Arc<RwLock<ProxyState>>,
+) -> Response<Body> {
+    // The latest health check result of each location, by backend
+    let health: BTreeMap<String, BTreeMap<String, &str>> = proxy_state
+        .read()
+        .unwrap()
+        .health
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, locations)| {
+            let locations = locations
+                .iter()
+                .map(|(location, healthy)| (location.clone(), if *healthy { "up" } else { "down" }))
+                .collect();
+            (name.clone(), locations)
+        })
+        .collect();
+    match serde_json::to_string(&health) {
+        Ok(body) => {
+            response.headers_mut().insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            );
+            *response.body_mut() = Body::from(body);
+            response
+        }
+        Err(e) => error_handler(
+            response,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error encoding health: {e}"),
+        ),
+    }
+}
+
+fn metrics_handler(mut response: Response<Body>) -> Response<Body> {
+    match encode_metrics() {
+        Ok(encoded_metrics) => {
+            *response.body_mut() = Body::from(encoded_metrics);
+        }
+        Err(e) => {
+            warn!("Error encoding metrics: {e}");
+            *response.body_mut() = Body::from(format!("Error encoding metrics: {e}"));
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    }
     response
 }
 
 async fn proxy_handler(
-    Extension(proxy_config): Extension<Arc<ProxyConfig>>,
-    Extension(proxy_state): Extension<Arc<Mutex<ProxyState>>>,
+    Extension(shared_proxy_config): Extension<SharedProxyConfig>,
+    Extension(proxy_state): Extension<Arc<RwLock<ProxyState>>>,
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    mut req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    // The request is handled using the config at the time it was received,
+    // even if the config is reloaded while it is in-flight
+    let proxy_config = shared_proxy_config.read().unwrap().clone();
+
+    let request_span = proxy_config
+        .config
+        .tracing
+        .is_some()
+        .then(|| RequestSpan::start(&mut req));
+
+    let body_stall = match proxy_config.config.request_body_timeout {
+        Some(request_body_timeout) => {
+            let (timed_req, body_stall) =
+                limit_request_body_time(req, Duration::from_millis(request_body_timeout));
+            req = timed_req;
+            Some(body_stall)
+        }
+        None => None,
+    };
+
+    let access_log =
+        (proxy_config.config.access_log == Some(true)).then(|| AccessLog::start(&req, client_addr));
+
+    // The deadline bounds the handling of the whole request, regardless of the backend timeout
+    let response = match proxy_config.config.request_deadline {
+        Some(request_deadline) => {
+            let handling = handle_request(proxy_config, proxy_state, client_addr, req);
+            match tokio::time::timeout(Duration::from_millis(request_deadline), handling).await {
+                Ok(response) => response,
+                Err(_) => {
+                    record_proxy_error(ProxyError::Timeout);
+                    Ok(error_handler(
+                        Response::new(Body::empty()),
+                        StatusCode::GATEWAY_TIMEOUT,
+                        "Request deadline exceeded".to_string(),
+                    ))
+                }
+            }
+        }
+        None => handle_request(proxy_config, proxy_state, client_addr, req).await,
+    };
+    // Whatever the backend responded, e.g. an error on its side of the incomplete request
+    let response = match (response, &body_stall) {
+        (Ok(_), Some(body_stall)) if body_stall.stalled() => Ok(error_handler(
+            Response::new(Body::empty()),
+            StatusCode::REQUEST_TIMEOUT,
+            "Request body timeout".to_string(),
+        )),
+        (response, _) => response,
+    };
+
+    if let (Some(request_span), Ok(response)) = (request_span, &response) {
+        request_span.end(response);
+    }
+
+    match (&access_log, response) {
+        (Some(access_log), Ok(response)) => Ok(access_log.log(response)),
+        (_, response) => response,
+    }
+}
+
+async fn send_request(
+    proxy_config: &ProxyConfig,
+    in_flight: Option<&InFlightGuard>,
+    req: Request<Body>,
+    http2: bool,
+    request_timeout: Duration,
+    max_connection_age: Option<Duration>,
+) -> Response<Body> {
+    // Requests to a backend removed by a config reload are cut off once it has drained
+    let client = &proxy_config.client;
+    let request = client.make_request_within(req, http2, request_timeout, max_connection_age);
+    match in_flight {
+        Some(in_flight) => tokio::select! {
+            response = request => response,
+            _ = in_flight.cut_off() => error_handler(
+                Response::new(Body::empty()),
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Backend removed".to_string(),
+            ),
+        },
+        None => request.await,
+    }
+}
+
+async fn acquire_location_permit(
+    proxy_state: &Arc<RwLock<ProxyState>>,
+    backend: &Backend,
+    location: &str,
+) -> Result<Option<OwnedSemaphorePermit>, Error> {
+    let max_concurrent = match backend.max_concurrent {
+        Some(max_concurrent) => max_concurrent,
+        None => return Ok(None),
+    };
+    let concurrency_limits = proxy_state.read().unwrap().concurrency_limits.clone();
+    let wait = backend.max_concurrent_wait.map(Duration::from_millis);
+    let name = backend.name.as_deref().unwrap_or_default();
+    concurrency_limits
+        .acquire(name, location, max_concurrent, wait)
+        .await
+        .map(Some)
+}
+
+async fn handle_request(
+    proxy_config: Arc<ProxyConfig>,
+    proxy_state: Arc<RwLock<ProxyState>>,
+    client_addr: SocketAddr,
     mut req: Request<Body>,
 ) -> Result<Response<Body>, Infallible> {
     let start = Instant::now();
@@ -172,46 +1181,202 @@ async fn proxy_handler(
     match req.version() {
         Version::HTTP_10 | Version::HTTP_11 | Version::HTTP_2 => {}
         _ => {
+            record_proxy_error(ProxyError::BadVersion);
             return Ok(bad_request_handler(
                 response,
                 format!("Unsupported HTTP version: {:?}", req.version()),
-            ))
+            ));
+        }
+    }
+    // hyper keeps responses to HTTP/1.0 clients in HTTP/1.0 form whatever the mode, closing
+    // the connection unless the client asked for keep-alive
+    if req.version() == Version::HTTP_10 {
+        match proxy_config.config.http10.unwrap_or_default() {
+            Http10::Allow => {}
+            Http10::Reject => {
+                record_proxy_error(ProxyError::BadVersion);
+                return Ok(error_handler(
+                    response,
+                    StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+                    "HTTP/1.0 is not supported".to_string(),
+                ));
+            }
+            Http10::Upgrade => *req.version_mut() = Version::HTTP_11,
+        }
+    }
+
+    // Asterisk-form (OPTIONS *) applies to the proxy itself rather than a backend
+    if req.uri() == "*" {
+        if req.method() != Method::OPTIONS {
+            return Ok(bad_request_handler(
+                response,
+                format!("Asterisk-form is not valid for {} requests", req.method()),
+            ));
+        }
+        response
+            .headers_mut()
+            .insert(header::ALLOW, HeaderValue::from_static(ALLOWED_METHODS));
+        return Ok(response);
+    }
+    // Requests without a path (e.g. authority-form CONNECT) cannot be proxied
+    if req.uri().path_and_query().is_none() {
+        return Ok(bad_request_handler(
+            response,
+            format!("Unsupported request target: {}", req.uri()),
+        ));
+    }
+
+    // Before blocked paths are matched, so that repeated slashes can't avoid them
+    if proxy_config.config.collapse_slashes == Some(true) && req.uri().path().contains("//") {
+        let path = collapse_slashes(req.uri().path());
+        let path_and_query = match req.uri().query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        };
+        let mut parts = req.uri().clone().into_parts();
+        parts.path_and_query = Some(path_and_query.parse().expect("Unable to collapse slashes"));
+        *req.uri_mut() = Uri::from_parts(parts).expect("Unable to collapse slashes");
+    }
+
+    // Blocked before routing, so that no backend receives them. Matched against the canonical
+    // path, so that encoded forms such as /%2egit or /a/../.git are also blocked
+    if proxy_config
+        .blocked_paths
+        .is_match(&canonical_path(req.uri().path()))
+    {
+        return Ok(error_handler(
+            response,
+            StatusCode::FORBIDDEN,
+            "Forbidden".to_string(),
+        ));
+    }
+
+    if let Some(max_uri_length) = proxy_config.config.max_uri_length {
+        // The path and query, as with HTTP/2 the URI also includes the scheme and authority
+        let uri_length = req
+            .uri()
+            .path_and_query()
+            .map_or(0, |uri| uri.as_str().len());
+        if uri_length > max_uri_length {
+            return Ok(error_handler(
+                response,
+                StatusCode::URI_TOO_LONG,
+                format!("URI too long, the limit is {}", max_uri_length),
+            ));
+        }
+    }
+
+    if let Some(max_header_count) = proxy_config.config.max_header_count {
+        if req.headers().len() > max_header_count {
+            return Ok(error_handler(
+                response,
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                format!("Too many headers, the limit is {}", max_header_count),
+            ));
+        }
+    }
+
+    if let Some(max_header_value_bytes) = proxy_config.config.max_header_value_bytes {
+        let oversized = req
+            .headers()
+            .iter()
+            .find(|(_, value)| value.len() > max_header_value_bytes);
+        if let Some((name, _)) = oversized {
+            return Ok(error_handler(
+                response,
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                format!(
+                    "Header {} too large, the limit is {} bytes",
+                    name, max_header_value_bytes
+                ),
+            ));
         }
     }
 
+    if proxy_config.config.reject_get_body == Some(true)
+        && matches!(req.method(), &Method::GET | &Method::HEAD)
+        && has_body(&req)
+    {
+        return Ok(bad_request_handler(
+            response,
+            format!("{} requests must not have a body", req.method()),
+        ));
+    }
+
+    if let Some(response) = proxy_config
+        .well_known
+        .response(req.method(), req.uri().path())
+    {
+        return Ok(response);
+    }
+
     // Extract the host header
-    let host_header_str = match get_host_header(&req) {
+    let sni_fallback = proxy_config.config.sni_host_fallback == Some(true);
+    let host_header_str = match get_host_header(&req, sni_fallback) {
         Ok(host_header_str) => host_header_str,
         Err(e) => {
+            record_proxy_error(ProxyError::NoHost);
             return Ok(bad_request_handler(
                 response,
                 format!("Unable to parse host header: {:?}", e),
-            ))
+            ));
         }
     };
+    if proxy_config.config.strict_hostname_validation == Some(true)
+        && !valid_hostname(host_header_str)
+    {
+        return Ok(bad_request_handler(
+            response,
+            format!("Invalid hostname: {}", host_header_str),
+        ));
+    }
     let host_header_set = host_header_set(host_header_str.to_string());
 
     let no_proxy = req.headers().contains_key("x-no-proxy");
+    let internal_allowed =
+        internal_endpoint_allowed(&proxy_config.config, host_header_str, client_addr.ip());
 
     match (req.method(), req.uri().path(), no_proxy, host_header_set) {
+        // Internal endpoints requested from a host / address outside of the allowlist,
+        // respond as though they don't exist
+        (&Method::GET, "/status" | "/metrics" | "/readyz" | "/health", true, _)
+            if !internal_allowed =>
+        {
+            debug!(
+                "Internal endpoint request not allowed from: {}",
+                client_addr
+            );
+            *response.status_mut() = StatusCode::NOT_FOUND;
+        }
+
         // Proxy internal endpoints
+        (&Method::GET, "/readyz", true, _) => {
+            response = readyz_handler(response, &proxy_config.config, &proxy_state);
+        }
         (&Method::GET, "/status", true, _) => {
-            *response.body_mut() = Body::from("The proxy is running");
+            response = status_handler(response);
         }
-        (&Method::GET, "/metrics", true, _) => match encode_metrics() {
-            Ok(encoded_metrics) => {
-                *response.body_mut() = Body::from(encoded_metrics);
-            }
-            Err(e) => {
-                warn!("Error encoding metrics: {e}");
-                *response.body_mut() = Body::from(format!("Error encoding metrics: {e}"));
-                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        (&Method::GET, "/health", true, _) => {
+            response = health_handler(response, &proxy_state);
+        }
+        (&Method::GET, "/metrics", true, _) => match &proxy_config.config.metrics_token {
+            Some(metrics_token) if !bearer_token_valid(&req, metrics_token) => {
+                debug!(
+                    "Metrics request without a valid token from: {}",
+                    client_addr
+                );
+                *response.status_mut() = StatusCode::UNAUTHORIZED;
+                response
+                    .headers_mut()
+                    .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
             }
+            _ => response = metrics_handler(response),
         },
 
         // A non internal request, but the host header has not been defined
         (_, _, false, false) => {
             debug!("Host header not defined");
+            record_proxy_error(ProxyError::NoHost);
             *response.body_mut() = Body::from("Host header not defined");
             *response.status_mut() = StatusCode::NOT_FOUND;
         }
@@ -220,39 +1385,219 @@ async fn proxy_handler(
         _ => {
             debug!("Standard request proxy");
 
-            let backend_location = router(
+            // Tokens are only verified for hosts with backends matching on claims
+            let claims_routed = proxy_config.config.backends.iter().any(|backend| {
+                backend
+                    .name
+                    .as_deref()
+                    .is_some_and(|name| host_matches(name, host_header_str))
+                    && backend.match_claims.is_some()
+            });
+            let claims = match (&proxy_config.jwt, bearer_token(&req)) {
+                (Some(jwt), Some(token)) if claims_routed => match jwt.verify(token) {
+                    Ok(claims) => Some(claims),
+                    Err(e) => {
+                        debug!("Invalid JWT: {e}");
+                        None
+                    }
+                },
+                _ => None,
+            };
+            let on_invalid = proxy_config
+                .config
+                .jwt
+                .as_ref()
+                .and_then(|jwt| jwt.on_invalid)
+                .unwrap_or_default();
+            if claims_routed && claims.is_none() && on_invalid == OnInvalidToken::Reject {
+                *response.status_mut() = StatusCode::UNAUTHORIZED;
+                response
+                    .headers_mut()
+                    .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+                return Ok(response);
+            }
+
+            // Routed by the canonical path, as blocked paths are, so that an encoded form of
+            // a path_prefix is routed to the same backend
+            let path = canonical_path(req.uri().path());
+
+            // Before routing, so that a debug request doesn't change the location selected for
+            // the next request
+            if route_debug_requested(&proxy_config.config, &req) {
+                let route = peek_router(
+                    &proxy_config.config.backends,
+                    proxy_state.clone(),
+                    host_header_str,
+                    &path,
+                    proxy_config.config.local_zone.as_deref(),
+                    claims.as_ref(),
+                );
+                return Ok(route_debug_response(
+                    &proxy_config.config.backends,
+                    host_header_str,
+                    &path,
+                    route.as_ref(),
+                ));
+            }
+
+            let route = router(
                 &proxy_config.config.backends,
                 proxy_state.clone(),
                 host_header_str,
+                &path,
+                proxy_config.config.local_zone.as_deref(),
+                claims.as_ref(),
             );
+            let routing = start.elapsed();
 
-            match backend_location {
+            match route {
                 None => {
+                    record_proxy_error(ProxyError::NoBackend);
                     *response.status_mut() = StatusCode::NOT_FOUND;
                 }
-                Some(backend_location) => {
+                Some(route) => {
+                    if let Some(allowed_methods) = &route.backend.allowed_methods {
+                        if !allowed_methods
+                            .iter()
+                            .any(|allowed| allowed.eq_ignore_ascii_case(req.method().as_str()))
+                        {
+                            *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+                            match HeaderValue::from_str(&allowed_methods.join(", ").to_uppercase())
+                            {
+                                Ok(allow) => {
+                                    response.headers_mut().insert(header::ALLOW, allow);
+                                }
+                                Err(e) => warn!("Invalid allowed methods: {e}"),
+                            }
+                            return Ok(response);
+                        }
+                    }
+
+                    if let Some(location) = trailing_slash_redirect(route.backend, req.uri()) {
+                        *response.status_mut() = StatusCode::PERMANENT_REDIRECT;
+                        match HeaderValue::from_str(&location) {
+                            Ok(location) => {
+                                response.headers_mut().insert(header::LOCATION, location);
+                            }
+                            Err(e) => warn!("Invalid redirect location: {e}"),
+                        }
+                        return Ok(response);
+                    }
+
                     // Proxy to backend
+                    let mut backend_location = route.location;
 
-                    // Scheme currently hardcoded to http (given this is a TLS terminating proxy)
-                    let scheme = "http";
+                    let scheme = route.backend.scheme.as_deref().unwrap_or("http");
+                    let http2 = route.backend.http2.unwrap_or(true);
+
+                    // The path and query are forwarded byte for byte unless normalization is configured
+                    let path_and_query = req
+                        .uri()
+                        .path_and_query()
+                        .expect("Unable to extract path and query")
+                        .clone();
+                    let stripped_path =
+                        match (&route.backend.path_prefix, route.backend.strip_prefix) {
+                            (Some(path_prefix), Some(true)) => {
+                                strip_path_prefix(path_prefix, path_and_query.path())
+                            }
+                            _ => None,
+                        };
+                    let path_and_query = match stripped_path {
+                        Some(path) => match path_and_query.query() {
+                            Some(query) => format!("{}?{}", path, query),
+                            None => path,
+                        }
+                        .parse()
+                        .expect("Unable to strip path prefix"),
+                        None => path_and_query,
+                    };
+                    let path_and_query = match route.backend.path_encoding.unwrap_or_default() {
+                        PathEncoding::Preserve => path_and_query,
+                        PathEncoding::Normalize => {
+                            let normalized = normalize_percent_encoding(path_and_query.path())
+                                .and_then(|path| {
+                                    Ok(match path_and_query.query() {
+                                        Some(query) => format!("{}?{}", path, query),
+                                        None => path,
+                                    }
+                                    .parse()?)
+                                });
+                            match normalized {
+                                Ok(path_and_query) => path_and_query,
+                                Err(e) => {
+                                    return Ok(bad_request_handler(
+                                        response,
+                                        format!("Unable to normalize path: {}", e),
+                                    ));
+                                }
+                            }
+                        }
+                    };
 
-                    let uri = Uri::builder()
+                    let mut uri = Uri::builder()
                         .scheme(scheme)
                         .authority(backend_location.clone())
-                        .path_and_query(
-                            req.uri()
-                                .path_and_query()
-                                .expect("Unable to extract path and query")
-                                .clone(),
-                        )
+                        .path_and_query(path_and_query)
                         .build()
                         .expect("Unable to extract URI");
 
+                    let record_request_metrics =
+                        !proxy_config.config.metrics.is_excluded(req.uri().path());
+
+                    // HEAD responses have no body to compress
+                    let encoding = match (&route.backend.compression, req.method()) {
+                        (Some(_), &Method::HEAD) | (None, _) => None,
+                        (Some(_), _) => accepted_encoding(req.headers()),
+                    };
+
+                    // Only GET responses are cached for stale-if-error, and not for requests
+                    // with credentials
+                    let cache_key = match (&route.backend.stale_if_error, req.method()) {
+                        (Some(_), &Method::GET) if cacheable_request(req.headers()) => {
+                            Some(format!(
+                                "{}{}",
+                                route.backend.name.as_deref().unwrap_or_default(),
+                                uri.path_and_query().map_or("/", |p| p.as_str())
+                            ))
+                        }
+                        _ => None,
+                    };
+
                     // Simply take the existing request and mutate the uri and headers
+                    let original_host =
+                        get_host_header(&req, sni_fallback).ok().map(str::to_string);
                     *req.uri_mut() = uri.clone();
-                    adjust_proxied_headers(&mut req)
-                        .await
-                        .expect("Unable to adjust headers");
+                    if let Err(e) = adjust_proxied_headers(
+                        &mut req,
+                        &proxy_config.config,
+                        client_addr,
+                        original_host.as_deref(),
+                    )
+                    .await
+                    {
+                        warn!("Unable to adjust proxied headers: {e}");
+                        return Ok(bad_request_handler(
+                            response,
+                            format!("Unable to adjust headers: {}", e),
+                        ));
+                    }
+                    // HTTP/2 requests have no Host header, so the backend would otherwise be sent
+                    // its own authority from the URI
+                    if let (Some(true), Some(original_host)) =
+                        (route.backend.preserve_host, &original_host)
+                    {
+                        match HeaderValue::from_str(original_host) {
+                            Ok(host) => {
+                                req.headers_mut().insert(header::HOST, host);
+                            }
+                            Err(e) => warn!("Invalid original host: {e}"),
+                        }
+                    }
+                    if let Some(set_request_headers) = &route.backend.set_request_headers {
+                        set_headers(req.headers_mut(), set_request_headers)
+                            .expect("Invalid set_request_headers");
+                    }
 
                     // If the backend scheme is http, adjust the original request HTTP version to 1
                     // (It seems that the HTTP2 implementation requires TLS)
@@ -260,16 +1605,350 @@ async fn proxy_handler(
                         *req.version_mut() = Version::HTTP_11;
                     }
 
-                    response = proxy_config.client.make_request(req).await;
+                    let in_flight = route
+                        .backend
+                        .name
+                        .as_ref()
+                        .and_then(|name| proxy_config.in_flight.get(name))
+                        .map(|in_flight| in_flight.start());
+                    let backend_name = route.backend.name.as_deref().unwrap_or_default();
+                    let _in_flight_metric =
+                        record_request_metrics.then(|| InFlightRequest::start(backend_name));
+                    if record_request_metrics {
+                        req = observe_request_size(req, backend_name);
+                    }
+
+                    let fault_injection = route
+                        .backend
+                        .fault_injection
+                        .as_ref()
+                        .filter(|_| proxy_config.config.enable_fault_injection == Some(true));
+                    if let Some(fault_injection) = fault_injection {
+                        inject_delay(fault_injection).await;
+                    }
+                    let aborted = fault_injection.and_then(inject_abort);
+
+                    // Upgrade is currently stripped as a hop-by-hop header, so is never forwarded
+                    let upgrade_requested = req.headers().contains_key(header::UPGRADE);
+                    let max_retries = route.backend.retries.unwrap_or(0);
+                    let retry_body = if max_retries > 0 && retryable_request(&req) {
+                        let limit = route.backend.retry_buffer_bytes.unwrap_or(0);
+                        let (buffered_req, retry_body) = buffer_body(req, limit).await;
+                        req = buffered_req;
+                        retry_body
+                    } else {
+                        None
+                    };
+                    let retry_request =
+                        retry_body.map(|body| (clone_request(&req, body.clone()), body));
+                    proxy_config.retry_budget.record_request();
+
+                    // Retries are not captured, only the first attempt's request body
+                    let exchange = route
+                        .backend
+                        .name
+                        .as_ref()
+                        .and_then(|name| proxy_config.captures.get(name))
+                        .map(|capture| capture.start(&req));
+                    if let Some(exchange) = &exchange {
+                        let body = std::mem::take(req.body_mut());
+                        *req.body_mut() = exchange.tee(body, Direction::Request);
+                    }
+
+                    let request_timeout = proxy_config.client.request_timeout();
+                    let max_connection_age =
+                        route.backend.max_connection_age.map(Duration::from_millis);
+                    let first_attempt = Instant::now();
+                    let mut permit = None;
+                    response = match aborted {
+                        Some(aborted) => aborted,
+                        None => {
+                            match acquire_location_permit(
+                                &proxy_state,
+                                route.backend,
+                                &backend_location,
+                            )
+                            .await
+                            {
+                                Ok(location_permit) => {
+                                    permit = location_permit;
+                                    send_request(
+                                        &proxy_config,
+                                        in_flight.as_ref(),
+                                        req,
+                                        http2,
+                                        request_timeout,
+                                        max_connection_age,
+                                    )
+                                    .await
+                                }
+                                Err(e) => error_handler(
+                                    Response::new(Body::empty()),
+                                    StatusCode::SERVICE_UNAVAILABLE,
+                                    e.to_string(),
+                                ),
+                            }
+                        }
+                    };
+                    let mut retries_exhausted = false;
+                    if let Some((retry_request, retry_body)) = retry_request {
+                        let mut tried = vec![backend_location.clone()];
+                        let mut locations_exhausted = false;
+                        while tried.len() <= max_retries as usize
+                            && retryable_error(response.extensions().get::<BackendError>())
+                        {
+                            // Failed requests of load balanced backends are retried on the
+                            // next location, not on one already tried
+                            let next_location = match retry_location(route.backend, &tried) {
+                                Some(next_location) => next_location,
+                                None => {
+                                    debug!("Every location has been tried: {}", uri);
+                                    locations_exhausted = true;
+                                    break;
+                                }
+                            };
+                            let delay = retry_delay(
+                                route.backend.retry_backoff,
+                                route.backend.retry_jitter,
+                                tried.len() as u32,
+                                &mut rand::thread_rng(),
+                            );
+                            // A backoff outlasting the request deadline fails the request now
+                            let remaining = proxy_config.config.request_deadline.map(|deadline| {
+                                Duration::from_millis(deadline).saturating_sub(start.elapsed())
+                            });
+                            if remaining.is_some_and(|remaining| delay >= remaining) {
+                                debug!("Retry backoff exceeds the request deadline: {}", uri);
+                                break;
+                            }
+                            // With a shared timeout each retry has what is left after the
+                            // attempts so far and the backoff
+                            let attempt_timeout = match route.backend.shared_request_timeout {
+                                Some(true) => request_timeout
+                                    .saturating_sub(first_attempt.elapsed())
+                                    .saturating_sub(delay),
+                                _ => request_timeout,
+                            };
+                            if attempt_timeout.is_zero() {
+                                debug!("Request timeout used by earlier attempts: {}", uri);
+                                break;
+                            }
+                            if !proxy_config.retry_budget.try_retry() {
+                                debug!("Retry budget exhausted, not retrying: {}", uri);
+                                break;
+                            }
+                            tokio::time::sleep(delay).await;
+                            record_location_health(
+                                &proxy_state,
+                                route.backend,
+                                &backend_location,
+                                response.extensions().get::<BackendError>(),
+                            );
+                            drop(permit.take());
+                            match acquire_location_permit(
+                                &proxy_state,
+                                route.backend,
+                                &next_location,
+                            )
+                            .await
+                            {
+                                Ok(location_permit) => permit = location_permit,
+                                Err(e) => {
+                                    debug!("{}, not retrying: {}", e, uri);
+                                    break;
+                                }
+                            }
+                            backend_location = next_location;
+                            let mut uri_parts = uri.clone().into_parts();
+                            uri_parts.authority = backend_location.parse().ok();
+                            uri = Uri::from_parts(uri_parts).expect("Unable to build retry URI");
+                            debug!("Retrying request: {} attempt: {}", uri, tried.len() + 1);
+                            tried.push(backend_location.clone());
+                            let mut req = clone_request(&retry_request, retry_body.clone());
+                            *req.uri_mut() = uri.clone();
+                            response = send_request(
+                                &proxy_config,
+                                in_flight.as_ref(),
+                                req,
+                                http2,
+                                attempt_timeout,
+                                max_connection_age,
+                            )
+                            .await;
+                        }
+                        retries_exhausted = (tried.len() > max_retries as usize
+                            || locations_exhausted)
+                            && retryable_error(response.extensions().get::<BackendError>());
+                    }
+                    // Backends removed by a reload are drained of requests still streaming a body
+                    if let Some(in_flight) = in_flight {
+                        response = hold_until_drained(response, in_flight);
+                    }
+                    // The location's permit is held until the response body has been streamed
+                    if let Some(permit) = permit {
+                        response = hold_until_complete(response, permit);
+                    }
+                    // A protocol switch the client didn't ask for can't be relayed
+                    if response.status() == StatusCode::SWITCHING_PROTOCOLS && !upgrade_requested {
+                        warn!("Unexpected 101 response from: {}", uri);
+                        response = error_handler(
+                            Response::new(Body::empty()),
+                            StatusCode::BAD_GATEWAY,
+                            "Unexpected protocol switch from backend".to_string(),
+                        );
+                    }
+                    let remapped = route
+                        .backend
+                        .status_remap
+                        .as_ref()
+                        .and_then(|status_remap| status_remap.get(&response.status().as_u16()))
+                        .and_then(|status| StatusCode::from_u16(*status).ok());
+                    if let Some(status) = remapped {
+                        debug!("Remapping status {} to {}", response.status(), status);
+                        *response.status_mut() = status;
+                    }
+                    let header_order: Option<Vec<HeaderName>> =
+                        (route.backend.preserve_header_order == Some(true))
+                            .then(|| response.headers().keys().cloned().collect());
+                    let version = response.version();
+                    adjust_via_header(response.headers_mut(), version, &proxy_config.config);
+                    let mut response_context = response
+                        .extensions_mut()
+                        .remove::<ResponseContext>()
+                        .unwrap_or_default();
+                    response_context.location = Some(backend_location.clone());
+                    response_context.routing = Some(routing);
+                    response_context.host = route.backend.name.clone();
+                    response_context.path_prefix = route.backend.path_prefix.clone();
+                    if let Some(max_response_bytes) = route.backend.max_response_bytes {
+                        response = limit_response_body(response, max_response_bytes);
+                    }
+                    // The backend's failure, which is still recorded when a cached response is
+                    // served in its place
+                    let backend_error = response.extensions().get::<BackendError>().copied();
+                    if let (Some(cache_key), Some(response_cache)) = (
+                        cache_key,
+                        proxy_config
+                            .response_caches
+                            .get(route.backend.name.as_deref().unwrap_or_default()),
+                    ) {
+                        response = stale_if_error(
+                            response_cache,
+                            cache_key,
+                            response,
+                            route.backend.name.as_deref().unwrap_or_default(),
+                        )
+                        .await;
+                    }
+                    // Only bodies known to be empty (e.g. a zero Content-Length) are rewritten
+                    if route.backend.normalize_empty_200_to_204 == Some(true)
+                        && response.status() == StatusCode::OK
+                        && response.body().is_end_stream()
+                    {
+                        *response.status_mut() = StatusCode::NO_CONTENT;
+                        response.headers_mut().remove(header::CONTENT_LENGTH);
+                    }
+                    let cookie_domain = match route.backend.rewrite_cookie_domain {
+                        Some(true) => original_host
+                            .as_deref()
+                            .and_then(|host| host.parse::<Authority>().ok()),
+                        _ => None,
+                    };
+                    let cookie_path = route.backend.rewrite_cookie_path.as_deref();
+                    if cookie_domain.is_some() || cookie_path.is_some() {
+                        rewrite_set_cookies(
+                            response.headers_mut(),
+                            cookie_domain.as_ref().map(Authority::host),
+                            cookie_path,
+                        );
+                    }
+                    if let Some(default_content_type) = &route.backend.default_content_type {
+                        if !response.headers().contains_key(header::CONTENT_TYPE) {
+                            match HeaderValue::from_str(default_content_type) {
+                                Ok(value) => {
+                                    response.headers_mut().insert(header::CONTENT_TYPE, value);
+                                }
+                                Err(e) => warn!("Invalid default_content_type: {e}"),
+                            }
+                        }
+                    }
+                    record_location_health(
+                        &proxy_state,
+                        route.backend,
+                        &backend_location,
+                        backend_error.as_ref(),
+                    );
+                    // Once the retries are done, so that a request is only counted once
+                    if let Some(proxy_error) =
+                        ProxyError::from_backend_error(backend_error.as_ref())
+                    {
+                        record_proxy_error(proxy_error);
+                    }
+                    if let (Some(slo_target_ms), Some(name)) =
+                        (route.backend.slo_target_ms, &route.backend.name)
+                    {
+                        // Failures are recorded as well over the target, so that the location is
+                        // avoided until it recovers
+                        let latency = if backend_error.is_some() {
+                            Duration::from_millis(slo_target_ms * 2).max(start.elapsed())
+                        } else {
+                            start.elapsed()
+                        };
+                        record_latency(&proxy_state, name, &backend_location, latency);
+                    }
+                    // The backend could not be reached, serve the fallback page if configured,
+                    // otherwise a distinct response if the request failed after all its retries
+                    if response.extensions().get::<BackendError>().is_some() {
+                        let fallback_page = route
+                            .backend
+                            .name
+                            .as_ref()
+                            .and_then(|name| proxy_config.fallback_pages.get(name));
+                        match (fallback_page, &route.backend.retries_exhausted_response) {
+                            (Some(fallback_page), _) => response = fallback_page.to_response(),
+                            (None, Some(retries_exhausted_response)) if retries_exhausted => {
+                                response = retries_exhausted_response.to_response();
+                            }
+                            _ => {}
+                        }
+                    }
+                    if let (Some(encoding), Some(compression_config)) =
+                        (encoding, &route.backend.compression)
+                    {
+                        response = compress_response(
+                            response,
+                            encoding,
+                            compression_config,
+                            route.backend.name.as_deref().unwrap_or_default(),
+                        )
+                        .await;
+                    }
+                    if let Some(set_response_headers) = &route.backend.set_response_headers {
+                        set_headers(response.headers_mut(), set_response_headers)
+                            .expect("Invalid set_response_headers");
+                    }
+                    if let Some(header_order) = &header_order {
+                        restore_header_order(response.headers_mut(), header_order);
+                    }
+                    if let Some(exchange) = exchange {
+                        exchange.set_status(response.status());
+                        let body = std::mem::take(response.body_mut());
+                        *response.body_mut() = exchange.tee(body, Direction::Response);
+                    }
+                    response.extensions_mut().insert(response_context);
                     debug!(
                         "Proxied response from: {} | Status: {}",
                         uri,
                         response.status()
                     );
                     // Record metrics
-                    if let Err(e) = record_metrics(&response, backend_location, start.elapsed()) {
-                        warn!("Error recording metrics: {e}")
-                    };
+                    if record_request_metrics {
+                        if let Err(e) = record_metrics(&response, backend_location, start.elapsed())
+                        {
+                            warn!("Error recording metrics: {e}")
+                        };
+                        response = observe_response_size(response, backend_name);
+                    }
                 }
             }
         }
@@ -281,50 +1960,132 @@ async fn proxy_handler(
 pub async fn run_server(config_path: String) {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
 
-    let config = read_proxy_config_yaml(config_path)
+    let config = read_proxy_config(config_path.clone())
         .await
-        .expect("Error loading yaml proxy config");
+        .expect("Error loading proxy config");
 
     let listen_address = SocketAddr::from(config.listen);
 
-    let client = client::Client::new(config.timeout);
+    // The tracing endpoint is set at startup, a config reload doesn't change it
+    if let Some(tracing_config) = &config.tracing {
+        init_tracing(tracing_config).expect("Error starting tracing");
+    }
 
-    let proxy_state = Arc::new(Mutex::new(ProxyState::new(&config)));
+    let client = client::Client::new(config.timeouts(), &config.client)
+        .expect("Error loading client config");
 
-    let proxy_config = Arc::new(ProxyConfig::new(config, client));
+    let proxy_state = Arc::new(RwLock::new(ProxyState::new(&config)));
 
-    let current_dir = env::current_dir().unwrap();
-    let tls_config = RustlsConfig::from_pem_file(
-        current_dir.join(
-            proxy_config
-                .config
-                .tls
-                .get("cert_path")
-                .expect("Unable to read cert_path"),
-        ),
-        current_dir.join(
-            proxy_config
-                .config
-                .tls
-                .get("key_path")
-                .expect("Unable to read key_path"),
-        ),
-    )
-    .await
-    .expect("TLS config error");
+    let proxy_config =
+        Arc::new(ProxyConfig::new(config, client).expect("Error loading proxy config"));
+    let shared_proxy_config = Arc::new(RwLock::new(proxy_config.clone()));
+
+    let reloader = Reloader::new(
+        config_path,
+        shared_proxy_config.clone(),
+        proxy_state.clone(),
+    );
+    #[cfg(unix)]
+    reload_on_sighup(reloader.clone()).expect("Unable to listen for SIGHUP");
+    if let Some(admin_config) = proxy_config.config.admin.clone() {
+        let handle = axum_server::Handle::new();
+        let mut admin_server =
+            tokio::spawn(run_admin_server(admin_config, reloader, handle.clone()));
+        // As with the plaintext listener, failing to bind the admin listener fails startup
+        tokio::select! {
+            _ = handle.listening() => {}
+            result = &mut admin_server => {
+                result
+                    .expect("Admin server panicked")
+                    .expect("Error starting admin server");
+            }
+        }
+        tokio::spawn(async move {
+            if let Ok(Err(e)) = admin_server.await {
+                warn!("Admin server stopped: {}", e);
+            }
+        });
+    }
+
+    // Backends health checked by a later config reload are started by the reload
+    for backend in &proxy_config.config.backends {
+        if let (Some(name), Some(_)) = (&backend.name, &backend.health_check) {
+            tokio::spawn(run_health_checks(
+                shared_proxy_config.clone(),
+                proxy_state.clone(),
+                name.clone(),
+                true,
+            ));
+        }
+    }
+
+    let tls_config = rustls_config(&proxy_config.config.tls).expect("TLS config error");
+    // Certificates are reloaded from the TLS config at startup, a config reload doesn't change it
+    tokio::spawn(reload_certificates(
+        proxy_config.config.tls.clone(),
+        tls_config.clone(),
+    ));
 
     let app = Router::new()
         .route(
             "/*path",
             get(proxy_handler).post(proxy_handler).put(proxy_handler),
         )
-        .layer(Extension(proxy_config))
+        // Requests not matching a path, e.g. OPTIONS *
+        .fallback(proxy_handler.into_service())
+        .layer(Extension(shared_proxy_config))
         .layer(Extension(proxy_state));
 
+    // The plaintext listener shares the router, and so the proxy config and state
+    if let Some(listen_http) = proxy_config.config.listen_http {
+        let http_app = app.clone().layer(Extension(Scheme::HTTP));
+        let acceptor = ConnectionIdAcceptor::new(ConnectionLimitAcceptor::new(
+            proxy_config.config.max_connections_per_ip,
+            KeepaliveAcceptor::new(proxy_config.config.keepalive.clone()),
+        ));
+        info!(
+            "Reverse proxy listening for plaintext HTTP on {}",
+            listen_http
+        );
+        let handle = axum_server::Handle::new();
+        let mut http_server = tokio::spawn(
+            axum_server::bind(SocketAddr::from(listen_http))
+                .handle(handle.clone())
+                .acceptor(acceptor)
+                .serve(http_app.into_make_service_with_connect_info::<SocketAddr>()),
+        );
+        // Startup waits for the socket to be bound, so that failing to bind it fails startup
+        tokio::select! {
+            _ = handle.listening() => {}
+            result = &mut http_server => {
+                result
+                    .expect("Plaintext axum server panicked")
+                    .expect("Error starting plaintext axum server");
+            }
+        }
+        tokio::spawn(async move {
+            if let Ok(Err(e)) = http_server.await {
+                warn!("Plaintext listener stopped: {}", e);
+            }
+        });
+    }
+    let app = app.layer(Extension(Scheme::HTTPS));
+
     info!("Reverse proxy listening on {}", listen_address);
 
-    axum_server::bind_rustls(listen_address, tls_config)
-        .serve(app.into_make_service())
+    let acceptor = ConnectionIdAcceptor::new(SniAcceptor::new(ClientCertAcceptor::new(
+        HandshakeAcceptor::new(
+            &proxy_config.config.tls,
+            RustlsAcceptor::new(tls_config).acceptor(ConnectionLimitAcceptor::new(
+                proxy_config.config.max_connections_per_ip,
+                KeepaliveAcceptor::new(proxy_config.config.keepalive.clone()),
+            )),
+        ),
+    )));
+
+    axum_server::bind(listen_address)
+        .acceptor(acceptor)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .expect("Error starting axum server");
 }
@@ -337,28 +2098,164 @@ mod tests {
     };
 
     use super::*;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
 
     #[tokio::test]
     async fn test_read_config_yaml() {
-        let data = read_proxy_config_yaml("config.yaml".to_string())
-            .await
-            .unwrap();
+        let data = read_proxy_config("config.yaml".to_string()).await.unwrap();
         assert_eq!(
             data.backends[0].name.as_ref().unwrap(),
             &String::from("test.home")
         );
     }
 
+    #[tokio::test]
+    async fn test_read_config_toml() {
+        // The TOML example is equivalent to the YAML one
+        let toml = read_proxy_config("config.toml".to_string()).await.unwrap();
+        let yaml = read_proxy_config("config.yaml".to_string()).await.unwrap();
+        assert_eq!(toml, yaml);
+
+        let config_path = std::env::temp_dir().join("brachyura_test_invalid.toml");
+        std::fs::write(&config_path, "listen = ").unwrap();
+        let error = read_proxy_config(config_path.to_str().unwrap().to_string())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().starts_with("Unable to parse TOML config"));
+    }
+
+    #[tokio::test]
+    async fn test_read_config_env_vars() {
+        let config_path = std::env::temp_dir().join("brachyura_test_env.yaml");
+        std::fs::write(
+            &config_path,
+            r#"
+            listen: "127.0.0.1:4000"
+            tls:
+              key_path: "${BRACHYURA_TEST_TLS_KEY}"
+            backends:
+              - name: "env.home"
+                location: ${BRACHYURA_TEST_BACKEND_ADDR}
+            "#,
+        )
+        .unwrap();
+        let config_path = config_path.to_str().unwrap().to_string();
+
+        let tls_key = |name: &str| {
+            (name == "BRACHYURA_TEST_TLS_KEY").then(|| "/etc/brachyura/tls.key".to_string())
+        };
+        let error = read_proxy_config_with_env(config_path.clone(), tls_key)
+            .await
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Environment variable BRACHYURA_TEST_BACKEND_ADDR is not set"));
+
+        let lookup = |name: &str| match name {
+            "BRACHYURA_TEST_BACKEND_ADDR" => Some("10.0.0.1:8000".to_string()),
+            _ => tls_key(name),
+        };
+        let config = read_proxy_config_with_env(config_path, lookup)
+            .await
+            .unwrap();
+        assert_eq!(
+            config.tls.key_path.as_deref(),
+            Some("/etc/brachyura/tls.key")
+        );
+        assert_eq!(
+            config.backends[0].location.as_deref(),
+            Some("10.0.0.1:8000")
+        );
+    }
+
     #[tokio::test]
     async fn test_adjust_proxied_headers() {
         let mut req = Request::new(Body::from("test"));
         req.headers_mut().insert(HOST, "test_host".parse().unwrap());
         req.headers_mut()
             .insert(PROXY_AUTHENTICATE, "true".parse().unwrap());
-        adjust_proxied_headers(&mut req).await.unwrap();
-        assert!(req.headers().iter().count() == 2);
+        let config: Config = serde_yaml::from_str(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {}
+            backends: []
+            "#,
+        )
+        .unwrap();
+        adjust_proxied_headers(
+            &mut req,
+            &config,
+            "192.168.1.10:50000".parse().unwrap(),
+            Some("test_host"),
+        )
+        .await
+        .unwrap();
+        assert!(req.headers().iter().count() == 5);
         assert!(req.headers().contains_key(HOST));
         assert!(req.headers().contains_key("x-no-proxy"));
+        assert_eq!(req.headers()["x-forwarded-for"], "192.168.1.10");
+        assert_eq!(req.headers()["x-forwarded-proto"], "https");
+        assert_eq!(req.headers()["x-forwarded-host"], "test_host");
+    }
+
+    #[tokio::test]
+    async fn test_x_forwarded_for_chain() {
+        let mut req = Request::new(Body::empty());
+        req.headers_mut()
+            .append("x-forwarded-for", "203.0.113.1".parse().unwrap());
+        req.headers_mut()
+            .append("x-forwarded-for", "10.0.0.1, 10.0.0.2".parse().unwrap());
+        let config: Config = serde_yaml::from_str(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {}
+            backends: []
+            "#,
+        )
+        .unwrap();
+        adjust_proxied_headers(&mut req, &config, "[::1]:50000".parse().unwrap(), None)
+            .await
+            .unwrap();
+        let forwarded_for: Vec<&HeaderValue> =
+            req.headers().get_all("x-forwarded-for").iter().collect();
+        assert_eq!(forwarded_for, vec!["203.0.113.1, 10.0.0.1, 10.0.0.2, ::1"]);
+        assert!(!req.headers().contains_key("x-forwarded-host"));
+    }
+
+    #[tokio::test]
+    async fn test_x_forwarded_proto_plaintext() {
+        // Requests received by the plaintext listener carry its scheme
+        let mut req = Request::new(Body::empty());
+        req.extensions_mut().insert(Scheme::HTTP);
+        let config: Config = serde_yaml::from_str(
+            r#"
+            listen: "127.0.0.1:4000"
+            listen_http: "127.0.0.1:4080"
+            tls: {}
+            backends: []
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.listen_http, Some("127.0.0.1:4080".parse().unwrap()));
+        adjust_proxied_headers(&mut req, &config, "[::1]:50000".parse().unwrap(), None)
+            .await
+            .unwrap();
+        assert_eq!(req.headers()["x-forwarded-proto"], "http");
+
+        // Client certificates can't be required with a plaintext listener
+        let config: Config = serde_yaml::from_str(
+            r#"
+            listen: "127.0.0.1:4000"
+            listen_http: "127.0.0.1:4080"
+            tls:
+              client_ca_path: "../certs/client-ca.crt"
+            backends: []
+            "#,
+        )
+        .unwrap();
+        let client = Client::new(config.timeouts(), &config.client).unwrap();
+        assert!(ProxyConfig::new(config, client).is_err());
     }
 
     #[tokio::test]
@@ -366,25 +2263,707 @@ mod tests {
         // Not host headers
         let host_header_string = String::from("localhost:4000");
         let test_match = host_header_set(host_header_string);
-        assert_eq!(test_match, false);
+        assert!(!test_match);
 
         let host_header_string = String::from("127.0.0.1:4000");
         let test_match = host_header_set(host_header_string);
-        assert_eq!(test_match, false);
+        assert!(!test_match);
 
         let host_header_string = String::from("[::1]:4000");
         let test_match = host_header_set(host_header_string);
-        assert_eq!(test_match, false);
+        assert!(!test_match);
 
         let host_header_string = String::from("192.168.1.100:1");
         let test_match = host_header_set(host_header_string);
-        assert_eq!(test_match, false);
+        assert!(!test_match);
 
         // Is a host header
         let host_header_string = String::from("test.home");
         let test_match = host_header_set(host_header_string);
-        assert_eq!(test_match, true);
+        assert!(test_match);
+    }
+    fn test_proxy(yaml: &str) -> (SharedProxyConfig, Arc<RwLock<ProxyState>>) {
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        let client = Client::new(config.timeouts(), &config.client).unwrap();
+        let proxy_state = Arc::new(RwLock::new(ProxyState::new(&config)));
+        let proxy_config = Arc::new(ProxyConfig::new(config, client).unwrap());
+        (Arc::new(RwLock::new(proxy_config)), proxy_state)
+    }
+
+    async fn test_request(
+        proxy: &(SharedProxyConfig, Arc<RwLock<ProxyState>>),
+        client_addr: &str,
+        req: Request<Body>,
+    ) -> Response<Body> {
+        proxy_handler(
+            Extension(proxy.0.clone()),
+            Extension(proxy.1.clone()),
+            ConnectInfo(client_addr.parse().unwrap()),
+            req,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_strip_port() {
+        assert_eq!(strip_port("admin.internal:4000"), "admin.internal");
+        assert_eq!(strip_port("admin.internal"), "admin.internal");
+        assert_eq!(strip_port("[::1]:4000"), "[::1]");
+        assert_eq!(strip_port("[::1]"), "[::1]");
+    }
+
+    #[tokio::test]
+    async fn test_internal_endpoints_allowlist() {
+        let proxy = test_proxy(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {}
+            internal_endpoints:
+              allow_hosts: ["admin.internal"]
+              allow_cidrs: ["10.0.0.0/8"]
+            backends: []
+            "#,
+        );
+        let metrics_request = |host: &str| {
+            Request::builder()
+                .uri("/metrics")
+                .header(HOST, host)
+                .header("x-no-proxy", "true")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // Allowed host
+        let response = test_request(
+            &proxy,
+            "127.0.0.1:50000",
+            metrics_request("admin.internal:4000"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Allowed CIDR
+        let response =
+            test_request(&proxy, "10.1.2.3:50000", metrics_request("localhost:4000")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Neither the host or the client address is allowed
+        let response =
+            test_request(&proxy, "127.0.0.1:50000", metrics_request("localhost:4000")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_token() {
+        let proxy = test_proxy(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {}
+            timeout: 2000
+            metrics_token: "scrape-secret"
+            backends: []
+            "#,
+        );
+        let metrics_request = |authorization: Option<&str>| {
+            let mut request = Request::builder()
+                .uri("/metrics")
+                .header(HOST, "localhost:4000")
+                .header("x-no-proxy", "true");
+            if let Some(authorization) = authorization {
+                request = request.header(header::AUTHORIZATION, authorization);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        let response = test_request(&proxy, "127.0.0.1:50000", metrics_request(None)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+        let response = test_request(
+            &proxy,
+            "127.0.0.1:50000",
+            metrics_request(Some("Bearer wrong-secret")),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = test_request(
+            &proxy,
+            "127.0.0.1:50000",
+            metrics_request(Some("Bearer scrape-secret")),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Only the metrics require the token
+        let request = Request::builder()
+            .uri("/status")
+            .header(HOST, "localhost:4000")
+            .header("x-no-proxy", "true")
+            .body(Body::empty())
+            .unwrap();
+        let response = test_request(&proxy, "127.0.0.1:50000", request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_exclude_paths() {
+        let mock_server = MockServer::start().await;
+        let backend_location = mock_server.address().to_string();
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            metrics:
+              exclude_paths: ["/healthz"]
+            backends:
+              - name: "metrics.home"
+                location: "{}"
+            "#,
+            backend_location
+        ));
+        let request = |path: &str| {
+            Request::builder()
+                .uri(path)
+                .header(HOST, "metrics.home")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // Counted by delta, as the address may have been used by an earlier test's backend
+        let request_count = || {
+            crate::metrics::METRICS
+                .http_request_counter
+                .with_label_values(&["404", &backend_location, "metrics.home", ""])
+                .get()
+        };
+        let initial_count = request_count();
+
+        test_request(&proxy, "127.0.0.1:50000", request("/healthz")).await;
+        assert_eq!(request_count(), initial_count);
+
+        test_request(&proxy, "127.0.0.1:50000", request("/test")).await;
+        assert_eq!(request_count(), initial_count + 1);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_error_metrics() {
+        let closed_location = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            backends:
+              - name: "closed.home"
+                location: "{}"
+            "#,
+            closed_location
+        ));
+        let proxy_error_count = |reason: &str| {
+            crate::metrics::METRICS
+                .proxy_errors
+                .with_label_values(&[reason])
+                .get()
+        };
+
+        // Other tests may be counted at the same time, so the counts are only checked to increase
+        let cases = vec![
+            ("no_host", Request::builder().uri("/")),
+            (
+                "no_host",
+                Request::builder().uri("/").header(HOST, "127.0.0.1:4000"),
+            ),
+            (
+                "no_backend",
+                Request::builder().uri("/").header(HOST, "unknown.home"),
+            ),
+            (
+                "bad_version",
+                Request::builder().uri("/").version(Version::HTTP_3),
+            ),
+            (
+                "connect_failed",
+                Request::builder().uri("/").header(HOST, "closed.home"),
+            ),
+        ];
+        for (reason, request) in cases {
+            let initial_count = proxy_error_count(reason);
+            let request = request.body(Body::empty()).unwrap();
+            test_request(&proxy, "127.0.0.1:50000", request).await;
+            assert!(proxy_error_count(reason) > initial_count, "{}", reason);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metrics_host_labels() {
+        let mock_server = MockServer::start().await;
+        let backend_location = mock_server.address().to_string();
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            backends:
+              - name: "shop.home"
+                location: "{0}"
+              - name: "shop.home"
+                path_prefix: "/api/"
+                location: "{0}"
+              - name: "blog.home"
+                location: "{0}"
+            "#,
+            backend_location
+        ));
+        let request = |host: &str, path: &str| {
+            Request::builder()
+                .uri(path)
+                .header(HOST, host)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let request_count = |host: &str, path_prefix: &str| {
+            crate::metrics::METRICS
+                .http_request_counter
+                .with_label_values(&["404", &backend_location, host, path_prefix])
+                .get()
+        };
+        let initial_counts = [
+            request_count("shop.home", ""),
+            request_count("shop.home", "/api/"),
+            request_count("blog.home", ""),
+        ];
+
+        // The backends share a location, but are counted separately
+        test_request(&proxy, "127.0.0.1:50000", request("shop.home", "/")).await;
+        test_request(&proxy, "127.0.0.1:50000", request("shop.home", "/api/cart")).await;
+        test_request(&proxy, "127.0.0.1:50000", request("shop.home", "/api/cart")).await;
+        test_request(&proxy, "127.0.0.1:50000", request("blog.home", "/")).await;
+        assert_eq!(request_count("shop.home", ""), initial_counts[0] + 1);
+        assert_eq!(request_count("shop.home", "/api/"), initial_counts[1] + 2);
+        assert_eq!(request_count("blog.home", ""), initial_counts[2] + 1);
+    }
+
+    #[tokio::test]
+    async fn test_stale_if_error() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("fresh"))
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&mock_server)
+            .await;
+        let request = || {
+            Request::builder()
+                .uri("/page")
+                .header(HOST, "stale.home")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            backends:
+              - name: "stale.home"
+                location: "{}"
+                stale_if_error:
+                  max_stale: 60
+            "#,
+            mock_server.address()
+        ));
+        let response = test_request(&proxy, "127.0.0.1:50000", request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The backend is now failing, the cached response is served instead
+        let response = test_request(&proxy, "127.0.0.1:50000", request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "fresh");
+
+        // Backend unreachable
+        drop(mock_server);
+        let response = test_request(&proxy, "127.0.0.1:50000", request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_stale_if_error_max_stale() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("fresh"))
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&mock_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            backends:
+              - name: "stale.home"
+                location: "{}"
+                stale_if_error:
+                  max_stale: 0
+            "#,
+            mock_server.address()
+        ));
+        let request = || {
+            Request::builder()
+                .uri("/page")
+                .header(HOST, "stale.home")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        test_request(&proxy, "127.0.0.1:50000", request()).await;
+        let response = test_request(&proxy, "127.0.0.1:50000", request()).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_stale_if_error_records_backend_error() {
+        // Nothing listens on port 1, so connections are refused
+        let proxy = test_proxy(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {}
+            timeout: 2000
+            backends:
+              - name: "stale-health.home"
+                backend_type: "loadbalanced"
+                locations:
+                  - "127.0.0.1:1"
+                stale_if_error:
+                  max_stale: 60
+                passive_health:
+                  consecutive_failures: 2
+            "#,
+        );
+        let proxy_config = proxy.0.read().unwrap().clone();
+        proxy_config.response_caches["stale-health.home"]
+            .store(
+                "stale-health.home/page".to_string(),
+                Response::new(Body::from("cached")),
+            )
+            .await
+            .unwrap();
+        let request = || {
+            Request::builder()
+                .uri("/page")
+                .header(HOST, "stale-health.home")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let connect_failures = || {
+            crate::metrics::METRICS
+                .proxy_errors
+                .with_label_values(&["connect_failed"])
+                .get()
+        };
+
+        // Connect failures answered from the cache still count against the location
+        let initial_failures = connect_failures();
+        for _ in 0..2 {
+            let response = test_request(&proxy, "127.0.0.1:50000", request()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                hyper::body::to_bytes(response.into_body()).await.unwrap(),
+                "cached"
+            );
+        }
+        assert!(connect_failures() >= initial_failures + 2);
+        let proxy_state = proxy.1.read().unwrap();
+        let backend_state = proxy_state.backends["stale-health.home"].as_ref().unwrap();
+        assert!(backend_state
+            .unhealthy_until
+            .lock()
+            .unwrap()
+            .contains_key("127.0.0.1:1"));
+    }
+
+    #[tokio::test]
+    async fn test_fallback_page() {
+        let path = std::env::temp_dir().join("brachyura_test_fallback_page.html");
+        std::fs::write(&path, "We'll be right back").unwrap();
+
+        // Nothing is listening on either location
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            backends:
+              - name: "fallback.home"
+                backend_type: "loadbalanced"
+                locations:
+                  - "127.0.0.1:1"
+                  - "127.0.0.1:2"
+                fallback_page:
+                  path: "{}"
+                  status: 503
+            "#,
+            path.to_str().unwrap()
+        ));
+        let response = test_request(
+            &proxy,
+            "127.0.0.1:50000",
+            Request::builder()
+                .uri("/")
+                .header(HOST, "fallback.home")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "We'll be right back");
+    }
+
+    #[tokio::test]
+    async fn test_max_header_count() {
+        let proxy = test_proxy(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {}
+            max_header_count: 4
+            backends: []
+            "#,
+        );
+        let request = |extra_headers: usize| {
+            let mut builder = Request::builder()
+                .uri("/status")
+                .header(HOST, "localhost:4000")
+                .header("x-no-proxy", "true");
+            for i in 0..extra_headers {
+                builder = builder.header(format!("x-extra-{}", i), "value");
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let response = test_request(&proxy, "127.0.0.1:50000", request(2)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = test_request(&proxy, "127.0.0.1:50000", request(3)).await;
+        assert_eq!(
+            response.status(),
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_header_value_bytes() {
+        let proxy = test_proxy(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {}
+            max_header_value_bytes: 64
+            backends: []
+            "#,
+        );
+        let request = |cookie: String| {
+            Request::builder()
+                .uri("/status")
+                .header(HOST, "localhost:4000")
+                .header("x-no-proxy", "true")
+                .header("user-agent", "test")
+                .header(header::COOKIE, cookie)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = test_request(&proxy, "127.0.0.1:50000", request("a".repeat(64))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = test_request(&proxy, "127.0.0.1:50000", request("a".repeat(65))).await;
+        assert_eq!(
+            response.status(),
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "Header cookie too large, the limit is 64 bytes");
+    }
+
+    fn drain_test_config(mock_server: &MockServer, drain_timeout: u64, with_drain: bool) -> String {
+        let drain_backend = format!(
+            r#"
+              - name: "drain.home"
+                location: "{}"
+                drain_timeout: {}"#,
+            mock_server.address(),
+            drain_timeout
+        );
+        format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            backends:
+              - name: "remaining.home"
+                location: "{}"{}
+            "#,
+            mock_server.address(),
+            if with_drain {
+                drain_backend.as_str()
+            } else {
+                ""
+            }
+        )
+    }
+
+    async fn reload_during_request(drain_timeout: u64) -> (StatusCode, StatusCode) {
+        // Reload the config, removing the backend while a request to it is in flight,
+        // returning the status of the in-flight request and a request after the reload
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(500)))
+            .mount(&mock_server)
+            .await;
+        let config_path = std::env::temp_dir().join(format!(
+            "brachyura_test_reload_{}.yaml",
+            mock_server.address().port()
+        ));
+        let proxy = test_proxy(&drain_test_config(&mock_server, drain_timeout, true));
+        let reloader = Reloader::new(
+            config_path.to_str().unwrap().to_string(),
+            proxy.0.clone(),
+            proxy.1.clone(),
+        );
+        let request = || {
+            Request::builder()
+                .uri("/")
+                .header(HOST, "drain.home")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let in_flight_proxy = (proxy.0.clone(), proxy.1.clone());
+        let in_flight = tokio::spawn(async move {
+            test_request(&in_flight_proxy, "127.0.0.1:50000", request()).await
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        std::fs::write(
+            &config_path,
+            drain_test_config(&mock_server, drain_timeout, false),
+        )
+        .unwrap();
+        reloader.reload().await.unwrap();
+
+        let after_reload = test_request(&proxy, "127.0.0.1:50000", request()).await;
+        (in_flight.await.unwrap().status(), after_reload.status())
+    }
+
+    #[tokio::test]
+    async fn test_reload_drains_removed_backend() {
+        let (in_flight_status, after_reload_status) = reload_during_request(5000).await;
+        assert_eq!(in_flight_status, StatusCode::OK);
+        assert_eq!(after_reload_status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_reload_drain_timeout() {
+        let (in_flight_status, _) = reload_during_request(50).await;
+        assert_eq!(in_flight_status, StatusCode::SERVICE_UNAVAILABLE);
     }
+
+    #[tokio::test]
+    async fn test_reload_invalid_config() {
+        let config_path = std::env::temp_dir().join("brachyura_test_reload_invalid.yaml");
+        std::fs::write(&config_path, "listen: [").unwrap();
+        let proxy = test_proxy(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {}
+            backends:
+              - name: "test.home"
+                location: "127.0.0.1:8000"
+            "#,
+        );
+        let reloader = Reloader::new(
+            config_path.to_str().unwrap().to_string(),
+            proxy.0.clone(),
+            proxy.1.clone(),
+        );
+        assert!(reloader.reload().await.is_err());
+        assert_eq!(proxy.0.read().unwrap().config.backends.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_reload_starts_health_checks() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        let proxy = test_proxy(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {}
+            backends:
+              - name: "test.home"
+                location: "127.0.0.1:8000"
+            "#,
+        );
+        let config_path = std::env::temp_dir().join("brachyura_test_reload_health.yaml");
+        std::fs::write(
+            &config_path,
+            format!(
+                r#"
+                listen: "127.0.0.1:4000"
+                tls: {{}}
+                timeout: 2000
+                backends:
+                  - name: "test.home"
+                    location: "127.0.0.1:8000"
+                  - name: "added.home"
+                    location: "{}"
+                    health_check:
+                      interval: 50
+                "#,
+                mock_server.address()
+            ),
+        )
+        .unwrap();
+        let reloader = Reloader::new(
+            config_path.to_str().unwrap().to_string(),
+            proxy.0.clone(),
+            proxy.1.clone(),
+        );
+        reloader.reload().await.unwrap();
+
+        // The backend added with a health check is checked without a restart
+        let health = || async {
+            let request = Request::builder()
+                .uri("/health")
+                .header(HOST, "localhost:4000")
+                .header("x-no-proxy", "true")
+                .body(Body::empty())
+                .unwrap();
+            let response = test_request(&proxy, "127.0.0.1:50000", request).await;
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+        let mut checked = false;
+        for _ in 0..50 {
+            if health().await["added.home"][mock_server.address().to_string()] == "up" {
+                checked = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(checked);
+    }
+
     #[tokio::test]
     async fn test_get_host_header() {
         // HTTP 1
@@ -395,9 +2974,3156 @@ mod tests {
             .header(HOST, "test.home")
             .body(Body::from("test"))
             .unwrap();
-        let host_header = get_host_header(&request);
+        let host_header = get_host_header(&request, false);
         assert_eq!(host_header.unwrap(), "test.home");
 
-        //TODO Test and HTTP2 request
+        // HTTP 2, the authority is preferred over a host header, then the SNI if enabled
+        let request = |uri: &str, host: Option<&str>| {
+            let mut builder = Request::builder().version(Version::HTTP_2).uri(uri);
+            if let Some(host) = host {
+                builder = builder.header(HOST, host);
+            }
+            let mut request = builder.body(Body::empty()).unwrap();
+            request
+                .extensions_mut()
+                .insert(SniHostname(Some("sni.home".to_string())));
+            request
+        };
+        let authority = request("https://authority.home/test", Some("test.home"));
+        assert_eq!(get_host_header(&authority, true).unwrap(), "authority.home");
+        let host = request("/test", Some("test.home"));
+        assert_eq!(get_host_header(&host, true).unwrap(), "test.home");
+        let sni = request("/test", None);
+        assert_eq!(get_host_header(&sni, true).unwrap(), "sni.home");
+        assert!(get_host_header(&sni, false).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sni_host_fallback() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("sni"))
+            .mount(&mock_server)
+            .await;
+        let config = |sni_host_fallback: bool| {
+            format!(
+                r#"
+                listen: "127.0.0.1:4000"
+                tls: {{}}
+                timeout: 2000
+                sni_host_fallback: {}
+                backends:
+                  - name: "other.home"
+                    location: "127.0.0.1:1"
+                  - name: "sni.home"
+                    location: "{}"
+                "#,
+                sni_host_fallback,
+                mock_server.address()
+            )
+        };
+        // An HTTP/2 request without an authority or host header
+        let request = || {
+            let mut request = Request::builder()
+                .version(Version::HTTP_2)
+                .uri("/")
+                .body(Body::empty())
+                .unwrap();
+            request
+                .extensions_mut()
+                .insert(SniHostname(Some("sni.home".to_string())));
+            request
+        };
+
+        let proxy = test_proxy(&config(true));
+        let response = test_request(&proxy, "127.0.0.1:50000", request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "sni"
+        );
+
+        let proxy = test_proxy(&config(false));
+        let response = test_request(&proxy, "127.0.0.1:50000", request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_route_debug() {
+        let proxy = test_proxy(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {}
+            enable_route_debug: true
+            metrics_token: "secret"
+            backends:
+              - name: "debug.home"
+                location: "127.0.0.1:8000"
+              - name: "lb.home"
+                backend_type: "loadbalanced"
+                locations: ["127.0.0.1:8001", "127.0.0.1:8002"]
+            "#,
+        );
+        let request = |uri: &str, host: &str| {
+            Request::builder()
+                .uri(uri)
+                .header(HOST, host)
+                .header(header::AUTHORIZATION, "Bearer secret")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = test_request(
+            &proxy,
+            "127.0.0.1:50000",
+            request("/path?__brachyura_debug=1", "debug.home"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let route_debug: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            route_debug,
+            serde_json::json!({
+                "host": "debug.home",
+                "backend": "debug.home",
+                "backend_type": null,
+                "location": "127.0.0.1:8000",
+                "reason": "single location",
+            })
+        );
+
+        // No backend matches the host
+        let response = test_request(
+            &proxy,
+            "127.0.0.1:50000",
+            request("/?__brachyura_debug=1", "missing.home"),
+        )
+        .await;
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let route_debug: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(route_debug["reason"], "no backend matches the host header");
+
+        // Debug requests show the next round robin location without advancing to it
+        for _ in 0..2 {
+            let response = test_request(
+                &proxy,
+                "127.0.0.1:50000",
+                request("/?__brachyura_debug=1", "lb.home"),
+            )
+            .await;
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let route_debug: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(route_debug["location"], "127.0.0.1:8001");
+        }
+        let proxy_config = proxy.0.read().unwrap().clone();
+        let route = router(
+            &proxy_config.config.backends,
+            proxy.1.clone(),
+            "lb.home",
+            "/",
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(route.location, "127.0.0.1:8001");
+
+        // Without a token configured, debug requests are routed as normal
+        let proxy = test_proxy(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {}
+            enable_route_debug: true
+            backends: []
+            "#,
+        );
+        let response = test_request(
+            &proxy,
+            "127.0.0.1:50000",
+            request("/?__brachyura_debug=1", "debug.home"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_route_debug_requires_admin_token() {
+        let proxy = test_proxy(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {}
+            enable_route_debug: true
+            admin:
+              listen: "127.0.0.1:9000"
+              auth:
+                token: "secret"
+            backends: []
+            "#,
+        );
+        let request = |token: &str| {
+            Request::builder()
+                .uri("/")
+                .header(HOST, "debug.home")
+                .header("x-brachyura-debug", "1")
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // Without the admin token the request is routed as normal
+        let response = test_request(&proxy, "127.0.0.1:50000", request("wrong")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = test_request(&proxy, "127.0.0.1:50000", request("secret")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let route_debug: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(route_debug["reason"], "no backend matches the host header");
+    }
+
+    #[tokio::test]
+    async fn test_max_response_bytes() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("x".repeat(1000)))
+            .mount(&mock_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            backends:
+              - name: "limited.home"
+                location: "{}"
+                max_response_bytes: 100
+            "#,
+            mock_server.address()
+        ));
+        let request = Request::builder()
+            .uri("/")
+            .header(HOST, "limited.home")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = test_request(&proxy, "127.0.0.1:50000", request).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_reject_get_body() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        let config = |reject_get_body: bool| {
+            format!(
+                r#"
+                listen: "127.0.0.1:4000"
+                tls: {{}}
+                timeout: 2000
+                reject_get_body: {}
+                backends:
+                  - name: "body.home"
+                    location: "{}"
+                "#,
+                reject_get_body,
+                mock_server.address()
+            )
+        };
+        let request = || {
+            Request::builder()
+                .uri("/")
+                .header(HOST, "body.home")
+                .header(header::CONTENT_LENGTH, "4")
+                .body(Body::from("body"))
+                .unwrap()
+        };
+
+        let proxy = test_proxy(&config(true));
+        let response = test_request(&proxy, "127.0.0.1:50000", request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let proxy = test_proxy(&config(false));
+        let response = test_request(&proxy, "127.0.0.1:50000", request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    async fn tls_backend() -> (SocketAddr, std::path::PathBuf) {
+        // An HTTPS backend offering HTTP/2 and HTTP/1.1 via ALPN, responding with the
+        // request version. Returns its address and the path of its CA certificate
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let ca_path =
+            std::env::temp_dir().join(format!("brachyura_test_ca_{}.pem", rand::random::<u32>()));
+        std::fs::write(&ca_path, cert.serialize_pem().unwrap()).unwrap();
+
+        let mut server_config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![rustls::Certificate(cert.serialize_der().unwrap())],
+                rustls::PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let stream = acceptor.accept(stream).await.unwrap();
+                    let service = hyper::service::service_fn(|req: Request<Body>| async move {
+                        Ok::<_, Infallible>(Response::new(Body::from(format!(
+                            "{:?}",
+                            req.version()
+                        ))))
+                    });
+                    let _ = hyper::server::conn::Http::new()
+                        .serve_connection(stream, service)
+                        .await;
+                });
+            }
+        });
+        (address, ca_path)
+    }
+
+    #[tokio::test]
+    async fn test_https_backend_alpn() {
+        let (address, ca_path) = tls_backend().await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            client:
+              address_family: ipv4
+              ca_path: "{}"
+            backends:
+              - name: "h2.home"
+                location: "localhost:{}"
+                scheme: "https"
+              - name: "http1.home"
+                location: "localhost:{}"
+                scheme: "https"
+                http2: false
+            "#,
+            ca_path.display(),
+            address.port(),
+            address.port()
+        ));
+        let request = |host: &str| {
+            Request::builder()
+                .uri("/")
+                .header(HOST, host)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // The upstream connection negotiated HTTP/2
+        let response = test_request(&proxy, "127.0.0.1:50000", request("h2.home")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "HTTP/2.0");
+
+        // HTTP/2 not offered
+        let response = test_request(&proxy, "127.0.0.1:50000", request("http1.home")).await;
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "HTTP/1.1");
+    }
+
+    #[tokio::test]
+    async fn test_request_deadline() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(1000)))
+            .mount(&mock_server)
+            .await;
+        // The backend timeout alone would allow the request to complete
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 5000
+            request_deadline: 200
+            backends:
+              - name: "deadline.home"
+                location: "{}"
+            "#,
+            mock_server.address()
+        ));
+        let request = Request::builder()
+            .uri("/")
+            .header(HOST, "deadline.home")
+            .body(Body::empty())
+            .unwrap();
+
+        let start = Instant::now();
+        let response = test_request(&proxy, "127.0.0.1:50000", request).await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(start.elapsed() < Duration::from_millis(1000));
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "Request deadline exceeded");
+    }
+
+    #[tokio::test]
+    async fn test_request_body_timeout() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(201))
+            .mount(&mock_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 5000
+            request_body_timeout: 200
+            backends:
+              - name: "upload.home"
+                location: "{}"
+            "#,
+            mock_server.address()
+        ));
+        let request = |body: Body| {
+            Request::builder()
+                .method(Method::POST)
+                .uri("/upload")
+                .header(HOST, "upload.home")
+                .body(body)
+                .unwrap()
+        };
+
+        // The client sends part of the body, then stalls
+        let (mut sender, body) = Body::channel();
+        sender.send_data("part of the upload".into()).await.unwrap();
+        let start = Instant::now();
+        let response = test_request(&proxy, "127.0.0.1:50000", request(body)).await;
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+        assert!(start.elapsed() < Duration::from_millis(1000));
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "Request body timeout");
+        drop(sender);
+
+        // A body sent without stalling is proxied
+        let response = test_request(&proxy, "127.0.0.1:50000", request(Body::from("upload"))).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_normalize_percent_encoding() {
+        assert_eq!(normalize_percent_encoding("/a%2fb").unwrap(), "/a%2Fb");
+        assert_eq!(normalize_percent_encoding("/%41%7e%2D").unwrap(), "/A~-");
+        assert_eq!(normalize_percent_encoding("/a%2").unwrap(), "/a%2");
+        assert_eq!(normalize_percent_encoding("/a%zzb").unwrap(), "/a%zzb");
+        // Bytes which aren't percent-encodings are kept as they are, not widened to chars
+        assert_eq!(
+            normalize_percent_encoding("/caf\u{e9}").unwrap(),
+            "/caf\u{e9}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_canonical_path() {
+        assert_eq!(canonical_path("/%2egit/config"), "/.git/config");
+        assert_eq!(canonical_path("//.git//config"), "/.git/config");
+        assert_eq!(canonical_path("/a/../.git/config"), "/.git/config");
+        assert_eq!(canonical_path("/a/b/%2e%2e/./c"), "/a/c");
+        assert_eq!(canonical_path("/../a"), "/a");
+        assert_eq!(canonical_path("/a/.."), "/");
+        assert_eq!(canonical_path("/a/b/."), "/a/b/");
+        assert_eq!(canonical_path("/"), "/");
+        assert_eq!(canonical_path("*"), "*");
+    }
+
+    #[tokio::test]
+    async fn test_collapse_slashes() {
+        assert_eq!(collapse_slashes("/api//users"), "/api/users");
+        assert_eq!(collapse_slashes("///api///users//"), "/api/users/");
+        assert_eq!(collapse_slashes("/"), "/");
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(wiremock::matchers::path("/api/users"))
+            .and(wiremock::matchers::query_param("page", "2"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            collapse_slashes: true
+            backends:
+              - name: "slashes.home"
+                location: "{}"
+                path_prefix: "/api"
+            "#,
+            mock_server.address()
+        ));
+
+        let request = Request::get("//api//users?page=2")
+            .header(HOST, "slashes.home")
+            .body(Body::empty())
+            .unwrap();
+        let response = test_request(&proxy, "127.0.0.1:50000", request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_percent_encoded_path() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            backends:
+              - name: "preserve.home"
+                location: "{}"
+              - name: "normalize.home"
+                location: "{}"
+                path_encoding: normalize
+            "#,
+            mock_server.address(),
+            mock_server.address()
+        ));
+        let request = |uri: &str, host: &str| {
+            Request::builder()
+                .uri(uri)
+                .header(HOST, host)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        test_request(
+            &proxy,
+            "127.0.0.1:50000",
+            request("/a%2Fb", "preserve.home"),
+        )
+        .await;
+        test_request(
+            &proxy,
+            "127.0.0.1:50000",
+            request("/a%2fb%41?q=%2f", "normalize.home"),
+        )
+        .await;
+
+        let received = mock_server.received_requests().await.unwrap();
+        assert_eq!(received[0].url.path(), "/a%2Fb");
+        assert_eq!(received[1].url.path(), "/a%2FbA");
+        assert_eq!(received[1].url.query(), Some("q=%2f"));
+    }
+
+    #[tokio::test]
+    async fn test_default_content_type() {
+        let mock_server = MockServer::start().await;
+        Mock::given(wiremock::matchers::path("/untyped"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes("untyped"))
+            .mount(&mock_server)
+            .await;
+        Mock::given(wiremock::matchers::path("/typed"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw("typed", "text/html"))
+            .mount(&mock_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            backends:
+              - name: "typed.home"
+                location: "{}"
+                default_content_type: "application/octet-stream"
+            "#,
+            mock_server.address()
+        ));
+        let request = |uri: &str| {
+            Request::builder()
+                .uri(uri)
+                .header(HOST, "typed.home")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = test_request(&proxy, "127.0.0.1:50000", request("/untyped")).await;
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/octet-stream"
+        );
+
+        let response = test_request(&proxy, "127.0.0.1:50000", request("/typed")).await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/html");
+    }
+
+    #[tokio::test]
+    async fn test_access_log_connection_reuse() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("ok"))
+            .mount(&mock_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            access_log: true
+            backends:
+              - name: "reuse.home"
+                location: "{}"
+            "#,
+            mock_server.address()
+        ));
+        let request = || {
+            Request::builder()
+                .uri("/")
+                .header(HOST, "reuse.home")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let mut lines = vec![];
+        for _ in 0..2 {
+            let req = request();
+            let access_log = AccessLog::start(&req, "127.0.0.1:50000".parse().unwrap());
+            let response = test_request(&proxy, "127.0.0.1:50000", req).await;
+            let (sender, receiver) = std::sync::mpsc::channel();
+            let response = access_log.log_with(response, move |line| sender.send(line).unwrap());
+            // The connection is returned to the pool once the body has been read
+            hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let line: serde_json::Value = serde_json::from_str(&receiver.recv().unwrap()).unwrap();
+            lines.push(line);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        assert_eq!(lines[0]["upstream_connection"], "new");
+        assert_eq!(lines[1]["upstream_connection"], "reused");
+        assert_eq!(lines[1]["location"], mock_server.address().to_string());
+        assert_eq!(lines[1]["status"], 200);
+    }
+
+    #[tokio::test]
+    async fn test_via_header() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).insert_header("via", "1.1 backend"))
+            .mount(&mock_server)
+            .await;
+        let config = |via: &str| {
+            format!(
+                r#"
+                listen: "127.0.0.1:4000"
+                tls: {{}}
+                timeout: 2000
+                {}
+                backends:
+                  - name: "via.home"
+                    location: "{}"
+                "#,
+                via,
+                mock_server.address()
+            )
+        };
+        let request = || {
+            Request::builder()
+                .uri("/")
+                .header(HOST, "via.home")
+                .header(header::VIA, "1.1 client-proxy")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let via = |headers: &HeaderMap| -> Vec<String> {
+            headers
+                .get_all(header::VIA)
+                .iter()
+                .map(|value| value.to_str().unwrap().to_string())
+                .collect()
+        };
+
+        // Append
+        let proxy = test_proxy(&config(
+            "via_header: append\n                via_pseudonym: edge",
+        ));
+        let response = test_request(&proxy, "127.0.0.1:50000", request()).await;
+        assert_eq!(via(response.headers()), vec!["1.1 backend, 1.1 edge"]);
+        let received = mock_server.received_requests().await.unwrap();
+        let received_via: Vec<&str> = received[0].headers[&"via".parse().unwrap()]
+            .iter()
+            .map(|value| value.as_str())
+            .collect();
+        assert_eq!(received_via, vec!["1.1 client-proxy", "1.1 edge"]);
+
+        // Strip
+        let proxy = test_proxy(&config("via_header: strip"));
+        let response = test_request(&proxy, "127.0.0.1:50000", request()).await;
+        assert!(via(response.headers()).is_empty());
+        let received = mock_server.received_requests().await.unwrap();
+        assert!(!received[1].headers.contains_key(&"via".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_normalize_empty_200_to_204() {
+        let mock_server = MockServer::start().await;
+        Mock::given(wiremock::matchers::path("/empty"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        Mock::given(wiremock::matchers::path("/body"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("body"))
+            .mount(&mock_server)
+            .await;
+        let config = |normalize: bool| {
+            format!(
+                r#"
+                listen: "127.0.0.1:4000"
+                tls: {{}}
+                timeout: 2000
+                backends:
+                  - name: "empty.home"
+                    location: "{}"
+                    normalize_empty_200_to_204: {}
+                "#,
+                mock_server.address(),
+                normalize
+            )
+        };
+        let request = |uri: &str| {
+            Request::builder()
+                .uri(uri)
+                .header(HOST, "empty.home")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let proxy = test_proxy(&config(true));
+        let response = test_request(&proxy, "127.0.0.1:50000", request("/empty")).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = test_request(&proxy, "127.0.0.1:50000", request("/body")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let proxy = test_proxy(&config(false));
+        let response = test_request(&proxy, "127.0.0.1:50000", request("/empty")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_options_asterisk() {
+        let proxy = test_proxy(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {}
+            backends: []
+            "#,
+        );
+        let request = |method: Method| {
+            Request::builder()
+                .method(method)
+                .uri("*")
+                .header(HOST, "test.home")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = test_request(&proxy, "127.0.0.1:50000", request(Method::OPTIONS)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ALLOW], ALLOWED_METHODS);
+
+        let response = test_request(&proxy, "127.0.0.1:50000", request(Method::GET)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_robots_txt() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("backend"))
+            .mount(&mock_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            robots_txt: "User-agent: *\nDisallow: /\n"
+            backends:
+              - name: "robots.home"
+                location: "{}"
+            "#,
+            mock_server.address()
+        ));
+        let request = |path: &str| {
+            Request::builder()
+                .uri(path)
+                .header(HOST, "robots.home")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = test_request(&proxy, "127.0.0.1:50000", request("/robots.txt")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "User-agent: *\nDisallow: /\n");
+        assert!(mock_server.received_requests().await.unwrap().is_empty());
+
+        // The favicon isn't configured, so is proxied
+        let response = test_request(&proxy, "127.0.0.1:50000", request("/favicon.ico")).await;
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "backend");
+        assert_eq!(mock_server.received_requests().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_max_uri_length() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            max_uri_length: 32
+            backends:
+              - name: "uri.home"
+                location: "{}"
+            "#,
+            mock_server.address()
+        ));
+        let request = |path: &str| {
+            Request::builder()
+                .uri(path)
+                .header(HOST, "uri.home")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = test_request(&proxy, "127.0.0.1:50000", request("/short?a=1")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let long_path = format!("/{}", "a".repeat(32));
+        let response = test_request(&proxy, "127.0.0.1:50000", request(&long_path)).await;
+        assert_eq!(response.status(), StatusCode::URI_TOO_LONG);
+        assert_eq!(mock_server.received_requests().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_retry_budget() {
+        // A backend closing every connection without responding, counting the attempts
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let attempts = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let backend_attempts = attempts.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                backend_attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                drop(stream);
+            }
+        });
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            retry_budget:
+              percent: 10
+              min_retries: 2
+            backends:
+              - name: "retry.home"
+                location: "{}"
+                retries: 3
+            "#,
+            address
+        ));
+
+        for _ in 0..50 {
+            let request = Request::builder()
+                .uri("/")
+                .header(HOST, "retry.home")
+                .body(Body::empty())
+                .unwrap();
+            let response = test_request(&proxy, "127.0.0.1:50000", request).await;
+            assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        // Without the budget each request would be attempted 4 times
+        let retries = attempts.load(std::sync::atomic::Ordering::SeqCst) - 50;
+        assert!(retries >= 2, "{} retries", retries);
+        assert!(retries <= 2 + 5, "{} retries", retries);
+    }
+
+    #[tokio::test]
+    async fn test_retry_backoff() {
+        // A backend closing every connection without responding, recording the attempts
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let attempts = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let backend_attempts = attempts.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                backend_attempts.lock().unwrap().push(Instant::now());
+                drop(stream);
+            }
+        });
+        let config = |request_deadline: u64| {
+            format!(
+                r#"
+                listen: "127.0.0.1:4000"
+                tls: {{}}
+                timeout: 2000
+                request_deadline: {}
+                backends:
+                  - name: "backoff.home"
+                    location: "{}"
+                    retries: 2
+                    retry_backoff: 100
+                    retry_jitter: 20
+                "#,
+                request_deadline, address
+            )
+        };
+        let request = || {
+            Request::builder()
+                .uri("/")
+                .header(HOST, "backoff.home")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let proxy = test_proxy(&config(5000));
+        let response = test_request(&proxy, "127.0.0.1:50000", request()).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let times = attempts.lock().unwrap().clone();
+        assert_eq!(times.len(), 3);
+        assert!(times[1] - times[0] >= Duration::from_millis(100));
+        assert!(times[2] - times[1] >= Duration::from_millis(200));
+
+        // The second retry's backoff would outlast the deadline, so it isn't attempted
+        attempts.lock().unwrap().clear();
+        let proxy = test_proxy(&config(250));
+        let response = test_request(&proxy, "127.0.0.1:50000", request()).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(attempts.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_switching_protocols() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // A backend switching protocols in response to a plain GET
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).await.unwrap();
+            stream
+                .write_all(
+                    b"HTTP/1.1 101 Switching Protocols\r\nConnection: upgrade\r\nUpgrade: websocket\r\n\r\n",
+                )
+                .await
+                .unwrap();
+        });
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            backends:
+              - name: "upgrade.home"
+                location: "{}"
+            "#,
+            address
+        ));
+        let request = Request::builder()
+            .uri("/")
+            .header(HOST, "upgrade.home")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = test_request(&proxy, "127.0.0.1:50000", request).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_forward_client_cert() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            forward_client_cert: true
+            backends:
+              - name: "cert.home"
+                location: "{}"
+            "#,
+            mock_server.address()
+        ));
+        // A CA signing both the proxy's certificate and the client's
+        let mut ca_params = rcgen::CertificateParams::new(vec![]);
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(ca_params).unwrap();
+        let server = rcgen::Certificate::from_params(rcgen::CertificateParams::new(vec![
+            "cert.home".to_string(),
+        ]))
+        .unwrap();
+        let mut cert_params = rcgen::CertificateParams::new(vec!["client.home".to_string()]);
+        cert_params.distinguished_name = rcgen::DistinguishedName::new();
+        cert_params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "device-1");
+        let client = rcgen::Certificate::from_params(cert_params).unwrap();
+        let client_cert = client.serialize_der_with_signer(&ca).unwrap();
+        let temp_path = |name: &str, contents: String| {
+            let path = std::env::temp_dir().join(name);
+            std::fs::write(&path, contents).unwrap();
+            path.to_string_lossy().to_string()
+        };
+        let tls_config = TlsConfig {
+            cert_path: Some(temp_path(
+                "brachyura_test_forward_cert.crt",
+                server.serialize_pem_with_signer(&ca).unwrap(),
+            )),
+            key_path: Some(temp_path(
+                "brachyura_test_forward_cert.key",
+                server.serialize_private_key_pem(),
+            )),
+            client_ca_path: Some(temp_path(
+                "brachyura_test_forward_cert_ca.pem",
+                ca.serialize_pem().unwrap(),
+            )),
+            ..Default::default()
+        };
+
+        // The proxy is served over a TLS connection, with the acceptor adding the verified
+        // certificate of the connection to each request
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let acceptor =
+            ClientCertAcceptor::new(RustlsAcceptor::new(rustls_config(&tls_config).unwrap()));
+        let service = hyper::service::service_fn(move |req| {
+            let proxy = proxy.clone();
+            async move { Ok::<_, Infallible>(test_request(&proxy, "127.0.0.1:50000", req).await) }
+        });
+        tokio::spawn(async move {
+            use axum_server::accept::Accept;
+            let (stream, service) = acceptor.accept(server_io, service).await.unwrap();
+            hyper::server::conn::Http::new()
+                .serve_connection(stream, service)
+                .await
+        });
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots
+            .add(&rustls::Certificate(ca.serialize_der().unwrap()))
+            .unwrap();
+        let client_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_single_cert(
+                vec![rustls::Certificate(client_cert.clone())],
+                rustls::PrivateKey(client.serialize_private_key_der()),
+            )
+            .unwrap();
+        let stream = tokio_rustls::TlsConnector::from(Arc::new(client_config))
+            .connect(
+                rustls::ServerName::try_from("cert.home").unwrap(),
+                client_io,
+            )
+            .await
+            .unwrap();
+        let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        // Certificate headers sent by the client are replaced
+        let request = Request::builder()
+            .uri("/")
+            .header(HOST, "cert.home")
+            .header("x-client-cert", "spoofed")
+            .header("x-client-cert-subject", "CN=spoofed")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let requests = mock_server.received_requests().await.unwrap();
+        let header = requests[0].headers[&"x-client-cert".parse().unwrap()].as_str();
+        let mut pem = Vec::new();
+        let mut bytes = header.bytes();
+        while let Some(byte) = bytes.next() {
+            if byte == b'%' {
+                let hex: String = bytes.by_ref().take(2).map(char::from).collect();
+                pem.push(u8::from_str_radix(&hex, 16).unwrap());
+            } else {
+                pem.push(byte);
+            }
+        }
+        let certs = rustls_pemfile::certs(&mut pem.as_slice()).unwrap();
+        assert_eq!(certs, vec![client_cert]);
+        assert_eq!(
+            requests[0].headers[&"x-client-cert-subject".parse().unwrap()].as_str(),
+            "CN=device-1"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_cert_subject_escaped() {
+        // A UTF8String CN is percent encoded rather than failing the request
+        let mut cert_params = rcgen::CertificateParams::new(vec!["client.home".to_string()]);
+        cert_params.distinguished_name = rcgen::DistinguishedName::new();
+        cert_params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "café 50%");
+        let client_cert = rcgen::Certificate::from_params(cert_params)
+            .unwrap()
+            .serialize_der()
+            .unwrap();
+        let mut req = Request::new(Body::empty());
+        req.extensions_mut()
+            .insert(ClientCertificate(Some(rustls::Certificate(client_cert))));
+        let config: Config = serde_yaml::from_str(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {}
+            forward_client_cert: true
+            backends: []
+            "#,
+        )
+        .unwrap();
+        adjust_proxied_headers(&mut req, &config, "[::1]:50000".parse().unwrap(), None)
+            .await
+            .unwrap();
+        assert_eq!(req.headers()["x-client-cert-subject"], "CN=caf%C3%A9 50%25");
+    }
+
+    #[tokio::test]
+    async fn test_valid_hostname() {
+        assert!(valid_hostname("test.home"));
+        assert!(valid_hostname("test-1.home:4000"));
+        assert!(valid_hostname("127.0.0.1:4000"));
+        assert!(valid_hostname("[::1]:4000"));
+        assert!(!valid_hostname("my_service.home"));
+        assert!(!valid_hostname("-test.home"));
+        assert!(!valid_hostname("test..home"));
+    }
+
+    #[tokio::test]
+    async fn test_strict_hostname_validation() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        let proxy = |strict: bool| {
+            test_proxy(&format!(
+                r#"
+                listen: "127.0.0.1:4000"
+                tls: {{}}
+                timeout: 2000
+                strict_hostname_validation: {}
+                backends:
+                  - name: "my_service.home"
+                    location: "{}"
+                "#,
+                strict,
+                mock_server.address()
+            ))
+        };
+        let request = || {
+            Request::builder()
+                .uri("/")
+                .header(HOST, "my_service.home")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // Lenient, the underscore host matches the backend of the same name
+        let response = test_request(&proxy(false), "127.0.0.1:50000", request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Strict, the host is rejected
+        let response = test_request(&proxy(true), "127.0.0.1:50000", request()).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(mock_server.received_requests().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_fault_injection_delay() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        let proxy = |enabled: bool| {
+            test_proxy(&format!(
+                r#"
+                listen: "127.0.0.1:4000"
+                tls: {{}}
+                timeout: 2000
+                enable_fault_injection: {}
+                backends:
+                  - name: "fault.home"
+                    location: "{}"
+                    fault_injection:
+                      delay:
+                        delay_ms: 200
+                        percentage: 100
+                "#,
+                enabled,
+                mock_server.address()
+            ))
+        };
+        let request = || {
+            Request::builder()
+                .uri("/")
+                .header(HOST, "fault.home")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let start = Instant::now();
+        let response = test_request(&proxy(true), "127.0.0.1:50000", request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        // Not applied unless fault injection is enabled
+        let start = Instant::now();
+        let response = test_request(&proxy(false), "127.0.0.1:50000", request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(start.elapsed() < Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn test_fault_injection_abort() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            enable_fault_injection: true
+            backends:
+              - name: "abort.home"
+                location: "{}"
+                fault_injection:
+                  abort:
+                    status: 500
+                    percentage: 100
+            "#,
+            mock_server.address()
+        ));
+
+        for _ in 0..5 {
+            let request = Request::builder()
+                .uri("/")
+                .header(HOST, "abort.home")
+                .body(Body::empty())
+                .unwrap();
+            let response = test_request(&proxy, "127.0.0.1:50000", request).await;
+            assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        assert!(mock_server.received_requests().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_preserve_header_order() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).await.unwrap();
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nVia: 1.1 upstream\r\nX-Zeta: 1\r\nX-Alpha: 2\r\nSet-Cookie: a=1\r\nX-Middle: 3\r\nContent-Length: 2\r\n\r\nok",
+                )
+                .await
+                .unwrap();
+        });
+        // Stripping the Via header removes the first header, reordering the others
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            via_header: strip
+            backends:
+              - name: "order.home"
+                location: "{}"
+                preserve_header_order: true
+            "#,
+            address
+        ));
+        let request = Request::builder()
+            .uri("/")
+            .header(HOST, "order.home")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = test_request(&proxy, "127.0.0.1:50000", request).await;
+        let names: Vec<&str> = response
+            .headers()
+            .keys()
+            .map(|name| name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "x-zeta",
+                "x-alpha",
+                "set-cookie",
+                "x-middle",
+                "content-length"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restore_header_order() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-first", HeaderValue::from_static("1"));
+        headers.append("x-second", HeaderValue::from_static("2"));
+        headers.append("x-second", HeaderValue::from_static("3"));
+        headers.insert("x-third", HeaderValue::from_static("4"));
+        let order: Vec<HeaderName> = headers.keys().cloned().collect();
+
+        headers.remove("x-first");
+        headers.insert("x-added", HeaderValue::from_static("5"));
+        restore_header_order(&mut headers, &order);
+        let headers: Vec<(&str, &str)> = headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.to_str().unwrap()))
+            .collect();
+        assert_eq!(
+            headers,
+            vec![
+                ("x-second", "2"),
+                ("x-second", "3"),
+                ("x-third", "4"),
+                ("x-added", "5")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_initial_health() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(200)))
+            .mount(&mock_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            wait_for_initial_health: true
+            backends:
+              - name: "health.home"
+                location: "{}"
+                health_check:
+                  path: "/healthz"
+            "#,
+            mock_server.address()
+        ));
+        let readyz = || async {
+            let request = Request::builder()
+                .uri("/readyz")
+                .header(HOST, "localhost:4000")
+                .header("x-no-proxy", "true")
+                .body(Body::empty())
+                .unwrap();
+            test_request(&proxy, "127.0.0.1:50000", request)
+                .await
+                .status()
+        };
+        assert_eq!(readyz().await, StatusCode::SERVICE_UNAVAILABLE);
+
+        // Still not ready while the first health check is in progress
+        tokio::spawn(run_health_checks(
+            proxy.0.clone(),
+            proxy.1.clone(),
+            "health.home".to_string(),
+            true,
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(readyz().await, StatusCode::SERVICE_UNAVAILABLE);
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(readyz().await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_default_scheme_downgrade() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // A plaintext backend returning the request line it received
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let read = stream.read(&mut buf).await.unwrap();
+            let request = String::from_utf8_lossy(&buf[..read]).to_string();
+            let request_line = request.lines().next().unwrap().to_string();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                request_line.len(),
+                request_line
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        // No scheme configured, so the backend is plain http
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            backends:
+              - name: "plain.home"
+                location: "{}"
+            "#,
+            address
+        ));
+        let request = Request::builder()
+            .uri("https://plain.home/path")
+            .version(Version::HTTP_2)
+            .body(Body::empty())
+            .unwrap();
+
+        // The HTTP/2 client request is forwarded as HTTP/1.1
+        let response = test_request(&proxy, "127.0.0.1:50000", request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "GET /path HTTP/1.1");
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let mock_server = MockServer::start().await;
+        Mock::given(wiremock::matchers::path("/large"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("large ".repeat(100)))
+            .mount(&mock_server)
+            .await;
+        Mock::given(wiremock::matchers::path("/small"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("small"))
+            .mount(&mock_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            backends:
+              - name: "compressed.home"
+                location: "{}"
+                compression:
+                  level: 9
+                  min_size: 100
+            "#,
+            mock_server.address()
+        ));
+        let request = |uri: &str, accept_encoding: &str| {
+            Request::builder()
+                .uri(uri)
+                .header(HOST, "compressed.home")
+                .header(header::ACCEPT_ENCODING, accept_encoding)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = test_request(&proxy, "127.0.0.1:50000", request("/large", "gzip")).await;
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(body.len() < 600);
+
+        // Below min_size
+        let response = test_request(&proxy, "127.0.0.1:50000", request("/small", "gzip")).await;
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            "small"
+        );
+
+        // Encoding not accepted by the client
+        let response = test_request(&proxy, "127.0.0.1:50000", request("/large", "identity")).await;
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+    }
+
+    #[tokio::test]
+    async fn test_match_claims() {
+        let premium_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("premium"))
+            .mount(&premium_server)
+            .await;
+        let default_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("default"))
+            .mount(&default_server)
+            .await;
+        let config = |on_invalid: &str| {
+            format!(
+                r#"
+                listen: "127.0.0.1:4000"
+                tls: {{}}
+                timeout: 2000
+                jwt:
+                  secret: "jwt-secret"
+                  on_invalid: {}
+                backends:
+                  - name: "api.home"
+                    location: "{}"
+                    match_claims:
+                      tier: "premium"
+                  - name: "api.home"
+                    location: "{}"
+                "#,
+                on_invalid,
+                premium_server.address(),
+                default_server.address()
+            )
+        };
+        let token = |tier: &str, secret: &[u8]| {
+            let expiry = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+                + 60;
+            jsonwebtoken::encode(
+                &jsonwebtoken::Header::default(),
+                &serde_json::json!({"tier": tier, "exp": expiry}),
+                &jsonwebtoken::EncodingKey::from_secret(secret),
+            )
+            .unwrap()
+        };
+        let request = |token: Option<String>| {
+            let mut builder = Request::builder().uri("/").header(HOST, "api.home");
+            if let Some(token) = token {
+                builder = builder.header(header::AUTHORIZATION, format!("Bearer {}", token));
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+        let body = |response: Response<Body>| async {
+            hyper::body::to_bytes(response.into_body()).await.unwrap()
+        };
+
+        let proxy = test_proxy(&config("default"));
+        let premium = request(Some(token("premium", b"jwt-secret")));
+        let response = test_request(&proxy, "127.0.0.1:50000", premium).await;
+        assert_eq!(body(response).await, "premium");
+        let basic = request(Some(token("basic", b"jwt-secret")));
+        let response = test_request(&proxy, "127.0.0.1:50000", basic).await;
+        assert_eq!(body(response).await, "default");
+        // An invalid or missing token is routed to the default backend
+        let forged = request(Some(token("premium", b"wrong-secret")));
+        let response = test_request(&proxy, "127.0.0.1:50000", forged).await;
+        assert_eq!(body(response).await, "default");
+        let response = test_request(&proxy, "127.0.0.1:50000", request(None)).await;
+        assert_eq!(body(response).await, "default");
+
+        // Or rejected
+        let proxy = test_proxy(&config("reject"));
+        let forged = request(Some(token("premium", b"wrong-secret")));
+        let response = test_request(&proxy, "127.0.0.1:50000", forged).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+        let response = test_request(&proxy, "127.0.0.1:50000", request(None)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let basic = request(Some(token("basic", b"jwt-secret")));
+        let response = test_request(&proxy, "127.0.0.1:50000", basic).await;
+        assert_eq!(body(response).await, "default");
+    }
+
+    #[tokio::test]
+    async fn test_passive_health_ejection() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        // Nothing listens on port 1, so connections are refused
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            backends:
+              - name: "ejection.home"
+                backend_type: "loadbalanced"
+                locations:
+                  - "{}"
+                  - "127.0.0.1:1"
+                passive_health:
+                  consecutive_failures: 2
+                  cooldown: 200
+            "#,
+            mock_server.address()
+        ));
+        let request = || {
+            Request::builder()
+                .uri("/")
+                .header(HOST, "ejection.home")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let statuses = |count: usize| {
+            let proxy = &proxy;
+            async move {
+                let mut statuses = Vec::new();
+                for _ in 0..count {
+                    let response = test_request(proxy, "127.0.0.1:50000", request()).await;
+                    statuses.push(response.status());
+                }
+                statuses
+            }
+        };
+
+        // Two failures eject the location, then only the healthy location is used
+        let failures = |statuses: &[StatusCode]| {
+            statuses
+                .iter()
+                .filter(|status| **status == StatusCode::SERVICE_UNAVAILABLE)
+                .count()
+        };
+        assert_eq!(failures(&statuses(4).await), 2);
+        assert_eq!(failures(&statuses(4).await), 0);
+
+        // After the cooldown it is tried again, and ejected after a single failure
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert_eq!(failures(&statuses(2).await), 1);
+        assert_eq!(failures(&statuses(4).await), 0);
+    }
+
+    #[tokio::test]
+    async fn test_passive_health_invalid_response() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        // A location responding with both Content-Length and Transfer-Encoding
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let invalid_location = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    while stream.read(&mut buf).await.unwrap_or(0) > 0 {
+                        let _ = stream
+                            .write_all(
+                                b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+                            )
+                            .await;
+                    }
+                });
+            }
+        });
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            backends:
+              - name: "invalid.home"
+                backend_type: "loadbalanced"
+                locations:
+                  - "{}"
+                  - "{}"
+                passive_health:
+                  consecutive_failures: 2
+                  cooldown: 60000
+            "#,
+            mock_server.address(),
+            invalid_location
+        ));
+        let mut statuses = Vec::new();
+        for _ in 0..8 {
+            let request = Request::builder()
+                .uri("/")
+                .header(HOST, "invalid.home")
+                .body(Body::empty())
+                .unwrap();
+            let response = test_request(&proxy, "127.0.0.1:50000", request).await;
+            statuses.push(response.status());
+        }
+        // Two invalid responses eject the location, then only the healthy location is used
+        let invalid = |statuses: &[StatusCode]| {
+            statuses
+                .iter()
+                .filter(|status| **status == StatusCode::BAD_GATEWAY)
+                .count()
+        };
+        assert_eq!(invalid(&statuses[..4]), 2);
+        assert_eq!(invalid(&statuses[4..]), 0);
+    }
+
+    #[tokio::test]
+    async fn test_active_health_checks() {
+        let healthy_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("healthy"))
+            .mount(&healthy_server)
+            .await;
+        let unhealthy_server = MockServer::start().await;
+        Mock::given(wiremock::matchers::path("/healthz"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&unhealthy_server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("unhealthy"))
+            .mount(&unhealthy_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            backends:
+              - name: "checked.home"
+                backend_type: "loadbalanced"
+                locations:
+                  - "{}"
+                  - "{}"
+                health_check:
+                  path: "/healthz"
+                  interval: 100
+            "#,
+            healthy_server.address(),
+            unhealthy_server.address()
+        ));
+        tokio::spawn(run_health_checks(
+            proxy.0.clone(),
+            proxy.1.clone(),
+            "checked.home".to_string(),
+            true,
+        ));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let request = Request::builder()
+            .uri("/health")
+            .header(HOST, "localhost:4000")
+            .header("x-no-proxy", "true")
+            .body(Body::empty())
+            .unwrap();
+        let response = test_request(&proxy, "127.0.0.1:50000", request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let health: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            health["checked.home"][healthy_server.address().to_string()],
+            "up"
+        );
+        assert_eq!(
+            health["checked.home"][unhealthy_server.address().to_string()],
+            "down"
+        );
+
+        // The location failing its health check is skipped
+        for _ in 0..4 {
+            let request = Request::builder()
+                .uri("/")
+                .header(HOST, "checked.home")
+                .body(Body::empty())
+                .unwrap();
+            let response = test_request(&proxy, "127.0.0.1:50000", request).await;
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(body, "healthy");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_health_check_warmup_grace() {
+        let healthy_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("healthy"))
+            .mount(&healthy_server)
+            .await;
+        let starting_server = MockServer::start().await;
+        Mock::given(wiremock::matchers::path("/healthz"))
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&starting_server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("starting"))
+            .mount(&starting_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            backends:
+              - name: "warmup.home"
+                backend_type: "loadbalanced"
+                locations:
+                  - "{}"
+                  - "{}"
+                health_check:
+                  path: "/healthz"
+                  interval: 50
+                  warmup_grace: 400
+            "#,
+            healthy_server.address(),
+            starting_server.address()
+        ));
+        tokio::spawn(run_health_checks(
+            proxy.0.clone(),
+            proxy.1.clone(),
+            "warmup.home".to_string(),
+            true,
+        ));
+        let bodies = || async {
+            let mut bodies = Vec::new();
+            for _ in 0..4 {
+                let request = Request::builder()
+                    .uri("/")
+                    .header(HOST, "warmup.home")
+                    .body(Body::empty())
+                    .unwrap();
+                let response = test_request(&proxy, "127.0.0.1:50000", request).await;
+                bodies.push(hyper::body::to_bytes(response.into_body()).await.unwrap());
+            }
+            bodies
+        };
+
+        // Failures during the grace period don't mark the location unhealthy
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(bodies().await.iter().any(|body| body == "starting"));
+
+        // Failures after it do
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert!(bodies().await.iter().all(|body| body == "healthy"));
+    }
+
+    #[tokio::test]
+    async fn test_retry_next_location() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(wiremock::matchers::body_string("payload"))
+            .respond_with(ResponseTemplate::new(201))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        // Nothing listens on port 1, so connections are refused
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            backends:
+              - name: "failover.home"
+                backend_type: "loadbalanced"
+                locations:
+                  - "127.0.0.1:1"
+                  - "{}"
+                retries: 1
+                retry_buffer_bytes: 1024
+            "#,
+            mock_server.address()
+        ));
+        let request = |method: Method, body: &'static str| {
+            Request::builder()
+                .method(method)
+                .uri("/")
+                .header(HOST, "failover.home")
+                .header(header::CONTENT_LENGTH, body.len())
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        // Requests sent to the refusing location are retried on the next location
+        for _ in 0..4 {
+            let response = test_request(&proxy, "127.0.0.1:50000", request(Method::GET, "")).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let response =
+                test_request(&proxy, "127.0.0.1:50000", request(Method::PUT, "payload")).await;
+            assert_eq!(response.status(), StatusCode::CREATED);
+        }
+
+        // POST requests are never retried
+        let mut statuses = Vec::new();
+        for _ in 0..4 {
+            let response =
+                test_request(&proxy, "127.0.0.1:50000", request(Method::POST, "payload")).await;
+            statuses.push(response.status());
+        }
+        let failures = statuses
+            .iter()
+            .filter(|status| **status == StatusCode::SERVICE_UNAVAILABLE)
+            .count();
+        assert_eq!(failures, 2);
+    }
+
+    #[tokio::test]
+    async fn test_retry_untried_locations() {
+        // Backends closing every connection without responding, recording their attempts
+        let mut failing = Vec::new();
+        for _ in 0..2 {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let attempts = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+            let backend_attempts = attempts.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    backend_attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    drop(stream);
+                }
+            });
+            failing.push((address, attempts));
+        }
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let config = |locations: &[String]| {
+            format!(
+                r#"
+                listen: "127.0.0.1:4000"
+                tls: {{}}
+                timeout: 2000
+                backends:
+                  - name: "pool.home"
+                    backend_type: "loadbalanced"
+                    locations: {:?}
+                    retries: 5
+                "#,
+                locations
+            )
+        };
+        let request = || {
+            Request::builder()
+                .uri("/")
+                .header(HOST, "pool.home")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let attempts = || {
+            failing
+                .iter()
+                .map(|(_, attempts)| attempts.load(std::sync::atomic::Ordering::SeqCst))
+                .collect::<Vec<usize>>()
+        };
+        let mut locations: Vec<String> = failing
+            .iter()
+            .map(|(address, _)| address.to_string())
+            .collect();
+
+        // The first two locations fail, and are each tried once before the third succeeds
+        locations.push(mock_server.address().to_string());
+        let proxy = test_proxy(&config(&locations));
+        let response = test_request(&proxy, "127.0.0.1:50000", request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(attempts(), [1, 1]);
+
+        // Once every location has failed the request isn't retried again, despite the retries left
+        locations.pop();
+        let proxy = test_proxy(&config(&locations));
+        let response = test_request(&proxy, "127.0.0.1:50000", request()).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(attempts(), [2, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_retries_exhausted_response() {
+        // Nothing listens on ports 1 and 2, so connections are refused
+        let proxy = test_proxy(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {}
+            timeout: 2000
+            backends:
+              - name: "exhausted.home"
+                backend_type: "loadbalanced"
+                locations:
+                  - "127.0.0.1:1"
+                  - "127.0.0.1:2"
+                retries: 1
+                retries_exhausted_response:
+                  status: 504
+                  body: "Gave up after retrying"
+              - name: "single.home"
+                location: "127.0.0.1:1"
+                retries_exhausted_response:
+                  status: 504
+            "#,
+        );
+        let request = |host: &str| {
+            Request::builder()
+                .uri("/")
+                .header(HOST, host)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = test_request(&proxy, "127.0.0.1:50000", request("exhausted.home")).await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "Gave up after retrying");
+
+        // A failure without retries keeps the backend error
+        let response = test_request(&proxy, "127.0.0.1:50000", request("single.home")).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_capture() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(201).set_body_string("response payload"))
+            .mount(&mock_server)
+            .await;
+        let path = std::env::temp_dir().join("brachyura_test_capture.jsonl");
+        let _ = std::fs::remove_file(&path);
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            backends:
+              - name: "capture.home"
+                location: "{}"
+                capture:
+                  file: "{}"
+                  max_body_bytes: 8
+            "#,
+            mock_server.address(),
+            path.display()
+        ));
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/submit")
+            .header(HOST, "capture.home")
+            .header(header::CONTENT_LENGTH, "7")
+            .body(Body::from("payload"))
+            .unwrap();
+        let response = test_request(&proxy, "127.0.0.1:50000", request).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        // The client still receives the full body
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "response payload");
+
+        // Written once the exchange is complete, by a background task
+        let mut captured = String::new();
+        for _ in 0..50 {
+            captured = std::fs::read_to_string(&path).unwrap_or_default();
+            if !captured.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let record: serde_json::Value = serde_json::from_str(captured.trim()).unwrap();
+        assert_eq!(record["backend"], "capture.home");
+        assert_eq!(record["method"], "POST");
+        assert_eq!(record["status"], 201);
+        assert_eq!(record["request_body"], base64::encode("payload"));
+        assert_eq!(record["request_truncated"], false);
+        assert_eq!(record["response_body"], base64::encode("response"));
+        assert_eq!(record["response_truncated"], true);
+    }
+
+    #[tokio::test]
+    async fn test_trailing_slash_redirect() {
+        let proxy = test_proxy(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {}
+            timeout: 2000
+            backends:
+              - name: "redirect.home"
+                location: "127.0.0.1:1"
+                path_prefix: "/docs/"
+                trailing_slash: "redirect"
+            "#,
+        );
+        let request = Request::builder()
+            .uri("/docs?lang=en")
+            .header(HOST, "redirect.home")
+            .body(Body::empty())
+            .unwrap();
+        let response = test_request(&proxy, "127.0.0.1:50000", request).await;
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.headers()[header::LOCATION], "/docs/?lang=en");
+    }
+
+    #[tokio::test]
+    async fn test_strip_prefix() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(wiremock::matchers::path("/users"))
+            .and(wiremock::matchers::query_param("page", "2"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("users"))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(wiremock::matchers::path("/"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("root"))
+            .mount(&mock_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            backends:
+              - name: "app.home"
+                location: "{}"
+                path_prefix: "/internal"
+                strip_prefix: true
+            "#,
+            mock_server.address()
+        ));
+        let body = |path: &'static str| {
+            let proxy = &proxy;
+            async move {
+                let request = Request::builder()
+                    .uri(path)
+                    .header(HOST, "app.home")
+                    .body(Body::empty())
+                    .unwrap();
+                let response = test_request(proxy, "127.0.0.1:50000", request).await;
+                hyper::body::to_bytes(response.into_body()).await.unwrap()
+            }
+        };
+        assert_eq!(body("/internal/users?page=2").await, "users");
+        assert_eq!(body("/internal").await, "root");
+    }
+
+    #[tokio::test]
+    async fn test_path_prefix_canonical_path() {
+        let api_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("api"))
+            .mount(&api_server)
+            .await;
+        let default_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("default"))
+            .mount(&default_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            backends:
+              - name: "app.home"
+                location: "{}"
+                path_prefix: "/api"
+              - name: "app.home"
+                location: "{}"
+            "#,
+            api_server.address(),
+            default_server.address()
+        ));
+        let body = |path: &'static str| {
+            let proxy = &proxy;
+            async move {
+                let request = Request::builder()
+                    .uri(path)
+                    .header(HOST, "app.home")
+                    .body(Body::empty())
+                    .unwrap();
+                let response = test_request(proxy, "127.0.0.1:50000", request).await;
+                hyper::body::to_bytes(response.into_body()).await.unwrap()
+            }
+        };
+
+        // Encoded forms of the prefix are routed as the prefix itself
+        for path in [
+            "/api/users",
+            "/%61pi/users",
+            "//api/users",
+            "/docs/../api/users",
+        ] {
+            assert_eq!(body(path).await, "api", "{}", path);
+        }
+        for path in ["/api/../docs", "/apis"] {
+            assert_eq!(body(path).await, "default", "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_access_log_timings() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("timed"))
+            .mount(&mock_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            backends:
+              - name: "timing.home"
+                location: "{}"
+            "#,
+            mock_server.address()
+        ));
+        let request = Request::builder()
+            .uri("/")
+            .header(HOST, "timing.home")
+            .body(Body::empty())
+            .unwrap();
+        let access_log = AccessLog::start(&request, "127.0.0.1:50000".parse().unwrap());
+        let response = test_request(&proxy, "127.0.0.1:50000", request).await;
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let response = access_log.log_with(response, move |line| sender.send(line).unwrap());
+        // Logged once the body has been relayed
+        assert!(receiver.try_recv().is_err());
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        let line: serde_json::Value = serde_json::from_str(&receiver.recv().unwrap()).unwrap();
+        for field in ["routing_ms", "connect_ms", "ttfb_ms", "transfer_ms"] {
+            let value = line[field].as_f64();
+            assert!(
+                value.is_some_and(|value| value >= 0.0),
+                "{}: {}",
+                field,
+                line
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_cookie_domain() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .append_header("set-cookie", "session=abc; Domain=backend.internal; Path=/")
+                    .append_header("set-cookie", "theme=dark; Path=/; Secure"),
+            )
+            .mount(&mock_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            backends:
+              - name: "app.home"
+                location: "{}"
+                rewrite_cookie_domain: true
+                rewrite_cookie_path: "/app"
+            "#,
+            mock_server.address()
+        ));
+        let request = Request::builder()
+            .uri("/")
+            .header(HOST, "app.home")
+            .body(Body::empty())
+            .unwrap();
+        let response = test_request(&proxy, "127.0.0.1:50000", request).await;
+        let set_cookies: Vec<&str> = response
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .map(|value| value.to_str().unwrap())
+            .collect();
+        assert_eq!(
+            set_cookies,
+            vec![
+                "session=abc; Domain=app.home; Path=/app",
+                "theme=dark; Path=/app; Secure"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_response_streamed() {
+        // A backend holding back the end of a multi-megabyte chunked response until the client
+        // has received the rest, which would never happen were the body buffered by the proxy
+        const CHUNK: usize = 64 * 1024;
+        const CHUNKS: usize = 64;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 4096];
+            assert!(stream.read(&mut buf).await.unwrap() > 0);
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n")
+                .await
+                .unwrap();
+            for i in 0..CHUNKS {
+                let chunk = vec![b'a' + (i % 26) as u8; CHUNK];
+                stream
+                    .write_all(format!("{:x}\r\n", CHUNK).as_bytes())
+                    .await
+                    .unwrap();
+                stream.write_all(&chunk).await.unwrap();
+                stream.write_all(b"\r\n").await.unwrap();
+            }
+            released.await.unwrap();
+            stream.write_all(b"3\r\nend\r\n0\r\n\r\n").await.unwrap();
+        });
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            backends:
+              - name: "download.home"
+                location: "{}"
+            "#,
+            address
+        ));
+
+        let request = Request::builder()
+            .uri("/large")
+            .header(HOST, "download.home")
+            .body(Body::empty())
+            .unwrap();
+        let response = test_request(&proxy, "127.0.0.1:50000", request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut body = response.into_body();
+        let mut received = Vec::new();
+        while received.len() < CHUNK * CHUNKS {
+            let chunk = tokio::time::timeout(Duration::from_secs(2), body.data())
+                .await
+                .expect("Response body not streamed")
+                .unwrap()
+                .unwrap();
+            received.extend_from_slice(&chunk);
+        }
+        release.send(()).unwrap();
+        while let Some(chunk) = body.data().await {
+            received.extend_from_slice(&chunk.unwrap());
+        }
+
+        let mut expected: Vec<u8> = (0..CHUNKS)
+            .flat_map(|i| vec![b'a' + (i % 26) as u8; CHUNK])
+            .collect();
+        expected.extend_from_slice(b"end");
+        assert_eq!(received.len(), expected.len());
+        assert!(received == expected);
+    }
+
+    #[tokio::test]
+    async fn test_allowed_methods() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            backends:
+              - name: "readonly.home"
+                location: "{}"
+                allowed_methods: ["GET", "HEAD"]
+            "#,
+            mock_server.address()
+        ));
+
+        let request = Request::post("/")
+            .header(HOST, "readonly.home")
+            .body(Body::from("data"))
+            .unwrap();
+        let response = test_request(&proxy, "127.0.0.1:50000", request).await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[header::ALLOW], "GET, HEAD");
+        assert!(mock_server.received_requests().await.unwrap().is_empty());
+
+        let request = Request::get("/")
+            .header(HOST, "readonly.home")
+            .body(Body::empty())
+            .unwrap();
+        let response = test_request(&proxy, "127.0.0.1:50000", request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_blocked_paths() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            blocked_paths: ['/\.git(/|$)', "^/admin"]
+            backends:
+              - name: "blocked.home"
+                location: "{}"
+            "#,
+            mock_server.address()
+        ));
+        let request = |path: &str| {
+            Request::get(path)
+                .header(HOST, "blocked.home")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        for path in [
+            "/.git/config",
+            "/repo/.git",
+            "/admin/users",
+            "/%2egit/config",
+            "//.git/config",
+            "/a/../.git/config",
+            "//admin/users",
+            "/./admin",
+        ] {
+            let response = test_request(&proxy, "127.0.0.1:50000", request(path)).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", path);
+        }
+        assert!(mock_server.received_requests().await.unwrap().is_empty());
+
+        for path in ["/.github", "/app/admin"] {
+            let response = test_request(&proxy, "127.0.0.1:50000", request(path)).await;
+            assert_eq!(response.status(), StatusCode::OK, "{}", path);
+        }
+
+        // An invalid regex is a config error
+        let config: Config = serde_yaml::from_str(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {}
+            blocked_paths: ["(unclosed"]
+            backends: []
+            "#,
+        )
+        .unwrap();
+        let client = Client::new(config.timeouts(), &config.client).unwrap();
+        assert!(ProxyConfig::new(config, client).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_set_headers() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(wiremock::matchers::header(
+                "authorization",
+                "Bearer internal",
+            ))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("server", "internal-api/1.2")
+                    .insert_header("x-frame-options", "ALLOW"),
+            )
+            .mount(&mock_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            backends:
+              - name: "api.home"
+                location: "{}"
+                set_request_headers:
+                  authorization: "Bearer internal"
+                  cookie: ""
+                set_response_headers:
+                  server: ""
+                  x-frame-options: "DENY"
+            "#,
+            mock_server.address()
+        ));
+
+        let request = Request::get("/")
+            .header(HOST, "api.home")
+            .header(header::AUTHORIZATION, "Bearer client")
+            .header(header::COOKIE, "session=abc")
+            .body(Body::empty())
+            .unwrap();
+        let response = test_request(&proxy, "127.0.0.1:50000", request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key(header::SERVER));
+        assert_eq!(response.headers()[header::X_FRAME_OPTIONS], "DENY");
+
+        let received = &mock_server.received_requests().await.unwrap()[0];
+        assert!(!received.headers.contains_key(&"cookie".parse().unwrap()));
+        // The mandatory headers are left as is
+        assert_eq!(received.headers[&"host".parse().unwrap()], "api.home");
+        assert!(received
+            .headers
+            .contains_key(&"x-no-proxy".parse().unwrap()));
+
+        for set_request_headers in [
+            "{ Host: other.home }",
+            "{ x-no-proxy: \"\" }",
+            "{ \"bad name\": x }",
+        ] {
+            let config: Config = serde_yaml::from_str(&format!(
+                r#"
+                listen: "127.0.0.1:4000"
+                tls: {{}}
+                backends:
+                  - name: "api.home"
+                    location: "127.0.0.1:8000"
+                    set_request_headers: {}
+                "#,
+                set_request_headers
+            ))
+            .unwrap();
+            let client = Client::new(config.timeouts(), &config.client).unwrap();
+            assert!(
+                ProxyConfig::new(config, client).is_err(),
+                "{}",
+                set_request_headers
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_preserve_host() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            backends:
+              - name: "vhost.home"
+                location: "{}"
+                preserve_host: true
+              - name: "other.home"
+                location: "{}"
+            "#,
+            mock_server.address(),
+            mock_server.address()
+        ));
+        let request = |host: &str| {
+            Request::get(format!("https://{}/", host))
+                .version(Version::HTTP_2)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        test_request(&proxy, "127.0.0.1:50000", request("vhost.home")).await;
+        test_request(&proxy, "127.0.0.1:50000", request("other.home")).await;
+        let received = mock_server.received_requests().await.unwrap();
+        let host = |i: usize| received[i].headers[&"host".parse().unwrap()].clone();
+        assert_eq!(host(0), "vhost.home");
+        // Without preserve_host the backend receives its own address
+        assert_eq!(host(1), mock_server.address().to_string().as_str());
+    }
+
+    #[tokio::test]
+    async fn test_forward_connection_id() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            forward_connection_id: true
+            backends:
+              - name: "conn.home"
+                location: "{}"
+            "#,
+            mock_server.address()
+        ));
+
+        // The ID is added by the listener, replacing any sent by the client
+        let mut request = Request::get("/")
+            .header(HOST, "conn.home")
+            .header("x-proxy-connection-id", "spoofed")
+            .body(Body::empty())
+            .unwrap();
+        request.extensions_mut().insert(ConnectionId(42));
+        let access_log = AccessLog::start(&request, "127.0.0.1:50000".parse().unwrap());
+        let response = test_request(&proxy, "127.0.0.1:50000", request).await;
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let response = access_log.log_with(response, move |line| sender.send(line).unwrap());
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let line: serde_json::Value = serde_json::from_str(&receiver.recv().unwrap()).unwrap();
+        assert_eq!(line["connection_id"], 42);
+
+        let received = mock_server.received_requests().await.unwrap();
+        assert_eq!(
+            received[0].headers[&"x-proxy-connection-id".parse().unwrap()],
+            "42"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shared_request_timeout() {
+        // A backend closing each connection without responding after 300ms
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_millis(300)).await;
+                    drop(stream);
+                });
+            }
+        });
+        let proxy = |shared: bool| {
+            test_proxy(&format!(
+                r#"
+                listen: "127.0.0.1:4000"
+                tls: {{}}
+                request_timeout: 500
+                backends:
+                  - name: "shared.home"
+                    location: "{}"
+                    retries: 1
+                    shared_request_timeout: {}
+                "#,
+                address, shared
+            ))
+        };
+        let request = || {
+            Request::get("/")
+                .header(HOST, "shared.home")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // Each attempt would be allowed the full 500ms, but the retry only has what is left
+        let start = Instant::now();
+        let response = test_request(&proxy(true), "127.0.0.1:50000", request()).await;
+        let elapsed = start.elapsed();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(elapsed < Duration::from_millis(580), "{:?}", elapsed);
+
+        let start = Instant::now();
+        let response = test_request(&proxy(false), "127.0.0.1:50000", request()).await;
+        assert_ne!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(start.elapsed() >= Duration::from_millis(600));
+    }
+
+    #[tokio::test]
+    async fn test_http10() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        let config = |mode: &str| {
+            format!(
+                r#"
+                listen: "127.0.0.1:4000"
+                tls: {{}}
+                timeout: 2000
+                via_header: append
+                http10: {}
+                backends:
+                  - name: "legacy.home"
+                    location: "{}"
+                "#,
+                mode,
+                mock_server.address()
+            )
+        };
+        let request = || {
+            Request::builder()
+                .uri("/")
+                .version(Version::HTTP_10)
+                .header(HOST, "legacy.home")
+                .header(header::CONNECTION, "keep-alive, x-legacy")
+                .header("keep-alive", "timeout=5")
+                .header("x-legacy", "hop")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let received_via = |index: usize, received: &[wiremock::Request]| {
+            received[index].headers[&"via".parse().unwrap()][0]
+                .as_str()
+                .to_string()
+        };
+
+        // Allow, the headers named by Connection are hop by hop and not forwarded
+        let proxy = test_proxy(&config("allow"));
+        let response = test_request(&proxy, "127.0.0.1:50000", request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let received = mock_server.received_requests().await.unwrap();
+        assert_eq!(received_via(0, &received), "1.0 brachyura");
+        for name in ["connection", "keep-alive", "x-legacy"] {
+            assert!(!received[0].headers.contains_key(&name.parse().unwrap()));
+        }
+
+        // Upgrade
+        let proxy = test_proxy(&config("upgrade"));
+        let response = test_request(&proxy, "127.0.0.1:50000", request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let received = mock_server.received_requests().await.unwrap();
+        assert_eq!(received_via(1, &received), "1.1 brachyura");
+        assert!(!received[1]
+            .headers
+            .contains_key(&"x-legacy".parse().unwrap()));
+
+        // Reject, without reaching the backend
+        let proxy = test_proxy(&config("reject"));
+        let response = test_request(&proxy, "127.0.0.1:50000", request()).await;
+        assert_eq!(response.status(), StatusCode::HTTP_VERSION_NOT_SUPPORTED);
+        assert_eq!(mock_server.received_requests().await.unwrap().len(), 2);
+        let mut http11 = request();
+        *http11.version_mut() = Version::HTTP_11;
+        let response = test_request(&proxy, "127.0.0.1:50000", http11).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_connection_named_proxy_headers() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            via_header: append
+            backends:
+              - name: "named.home"
+                location: "{}"
+            "#,
+            mock_server.address()
+        ));
+        let request = Request::builder()
+            .uri("/")
+            .header(HOST, "named.home")
+            .header(header::CONNECTION, "x-forwarded-for, host, via, x-legacy")
+            .header("x-legacy", "hop")
+            .body(Body::empty())
+            .unwrap();
+
+        // Naming the proxy's headers or host in Connection doesn't remove them
+        let response = test_request(&proxy, "127.0.0.1:50000", request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let received = mock_server.received_requests().await.unwrap();
+        let header = |name: &str| {
+            received[0]
+                .headers
+                .get(&name.parse().unwrap())
+                .map(|values| values[0].as_str().to_string())
+        };
+        assert_eq!(header("x-forwarded-for").as_deref(), Some("127.0.0.1"));
+        assert_eq!(header("via").as_deref(), Some("1.1 brachyura"));
+        assert!(header("host").is_some());
+        assert_eq!(header("x-legacy"), None);
+    }
+
+    #[tokio::test]
+    async fn test_fold_request_headers() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            fold_request_headers: ["cookie"]
+            backends:
+              - name: "fold.home"
+                location: "{}"
+            "#,
+            mock_server.address()
+        ));
+        // e.g. an HTTP/2 client sending each cookie as a separate header
+        let request = Request::builder()
+            .uri("/")
+            .header(HOST, "fold.home")
+            .header(header::COOKIE, "a=1")
+            .header(header::COOKIE, "b=2")
+            .body(Body::empty())
+            .unwrap();
+        let response = test_request(&proxy, "127.0.0.1:50000", request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let requests = mock_server.received_requests().await.unwrap();
+        let cookies: Vec<&str> = requests[0].headers[&"cookie".parse().unwrap()]
+            .iter()
+            .map(|value| value.as_str())
+            .collect();
+        assert_eq!(cookies, vec!["a=1; b=2"]);
+
+        // A header can't be both folded and split
+        let config: Config = serde_yaml::from_str(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {}
+            fold_request_headers: ["X-Tags"]
+            split_request_headers: ["x-tags"]
+            backends: []
+            "#,
+        )
+        .unwrap();
+        let client = Client::new(config.timeouts(), &config.client).unwrap();
+        assert!(ProxyConfig::new(config, client).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fold_split_headers() {
+        let values = |headers: &HeaderMap, name: &str| -> Vec<String> {
+            headers
+                .get_all(name)
+                .iter()
+                .map(|value| value.to_str().unwrap().to_string())
+                .collect()
+        };
+        let mut headers = HeaderMap::new();
+        headers.append("x-tags", HeaderValue::from_static("red"));
+        headers.append("x-tags", HeaderValue::from_static("blue"));
+        headers.append("x-other", HeaderValue::from_static("one"));
+        headers.append("x-other", HeaderValue::from_static("two"));
+        headers.append(
+            header::ACCEPT_LANGUAGE,
+            HeaderValue::from_static("en-GB, en;q=0.8"),
+        );
+        headers.append(header::COOKIE, HeaderValue::from_static("a=1"));
+        headers.append(header::COOKIE, HeaderValue::from_static("b=2"));
+
+        fold_headers(&mut headers, &["x-tags".to_string(), "cookie".to_string()]).unwrap();
+        assert_eq!(values(&headers, "x-tags"), vec!["red, blue"]);
+        assert_eq!(values(&headers, "x-other"), vec!["one", "two"]);
+        assert_eq!(values(&headers, "cookie"), vec!["a=1; b=2"]);
+
+        split_headers(&mut headers, &["accept-language".to_string()]).unwrap();
+        assert_eq!(
+            values(&headers, "accept-language"),
+            vec!["en-GB", "en;q=0.8"]
+        );
+
+        // Values with obs-text (non UTF-8 bytes) are folded and split, not dropped
+        let mut headers = HeaderMap::new();
+        headers.append("x-names", HeaderValue::from_bytes(b"caf\xe9").unwrap());
+        headers.append("x-names", HeaderValue::from_static("tea"));
+        fold_headers(&mut headers, &["x-names".to_string()]).unwrap();
+        assert_eq!(headers["x-names"].as_bytes(), b"caf\xe9, tea");
+        split_headers(&mut headers, &["x-names".to_string()]).unwrap();
+        let split: Vec<&[u8]> = headers
+            .get_all("x-names")
+            .iter()
+            .map(HeaderValue::as_bytes)
+            .collect();
+        assert_eq!(split, vec![&b"caf\xe9"[..], &b"tea"[..]]);
+
+        assert!(fold_headers(&mut headers, &["bad header".to_string()]).is_err());
+
+        // Cookie can't be split, as cookie values may contain commas
+        let config: Config = serde_yaml::from_str(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {}
+            split_request_headers: ["Cookie"]
+            backends: []
+            "#,
+        )
+        .unwrap();
+        let client = Client::new(config.timeouts(), &config.client).unwrap();
+        assert!(ProxyConfig::new(config, client).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrent() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string("slow")
+                    .set_delay(Duration::from_millis(300)),
+            )
+            .mount(&mock_server)
+            .await;
+        // Nothing listens on the second location, so its requests fail to connect
+        let unused = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let refused = unused.local_addr().unwrap();
+        drop(unused);
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            backends:
+              - name: "fragile.home"
+                location: "{}"
+                max_concurrent: 1
+              - name: "queued.home"
+                location: "{}"
+                max_concurrent: 1
+                max_concurrent_wait: 1000
+              - name: "refused.home"
+                location: "{}"
+                max_concurrent: 1
+            "#,
+            mock_server.address(),
+            mock_server.address(),
+            refused
+        ));
+        let request = |host: &str| {
+            Request::builder()
+                .uri("/")
+                .header(HOST, host)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let body = |response: Response<Body>| async {
+            hyper::body::to_bytes(response.into_body()).await.unwrap()
+        };
+
+        // At the limit a further request is refused straight away
+        let start = Instant::now();
+        let (first, second) = tokio::join!(
+            test_request(&proxy, "127.0.0.1:50000", request("fragile.home")),
+            async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                test_request(&proxy, "127.0.0.1:50000", request("fragile.home")).await
+            }
+        );
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(second.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body(second).await, "Backend concurrency limit reached");
+        assert!(start.elapsed() < Duration::from_millis(600));
+        // The permit is held until the response has been streamed
+        let response = test_request(&proxy, "127.0.0.1:50000", request("fragile.home")).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body(first).await, "slow");
+        let response = test_request(&proxy, "127.0.0.1:50000", request("fragile.home")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // With a wait the request queues for the permit
+        let start = Instant::now();
+        let (first, second) = tokio::join!(
+            async {
+                body(test_request(&proxy, "127.0.0.1:50000", request("queued.home")).await).await
+            },
+            async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                test_request(&proxy, "127.0.0.1:50000", request("queued.home")).await
+            }
+        );
+        assert_eq!(first, "slow");
+        assert_eq!(second.status(), StatusCode::OK);
+        assert_eq!(body(second).await, "slow");
+        assert!(start.elapsed() >= Duration::from_millis(600));
+
+        // Permits are also released when the request fails
+        for _ in 0..2 {
+            let response = test_request(&proxy, "127.0.0.1:50000", request("refused.home")).await;
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+            assert_ne!(body(response).await, "Backend concurrency limit reached");
+        }
+
+        // A limit of 0 is a config error
+        let config: Config = serde_yaml::from_str(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {}
+            backends:
+              - name: "closed.home"
+                location: "127.0.0.1:8000"
+                max_concurrent: 0
+            "#,
+        )
+        .unwrap();
+        let client = Client::new(config.timeouts(), &config.client).unwrap();
+        assert!(ProxyConfig::new(config, client).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_and_response_size_metrics() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string("0123456789")
+                    .set_delay(Duration::from_millis(300)),
+            )
+            .mount(&mock_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            backends:
+              - name: "inflight.home"
+                location: "{}"
+            "#,
+            mock_server.address()
+        ));
+        let request = Request::builder()
+            .uri("/")
+            .header(HOST, "inflight.home")
+            .body(Body::empty())
+            .unwrap();
+        let in_flight = crate::metrics::METRICS
+            .requests_in_flight
+            .with_label_values(&["inflight.home"]);
+        let response_size = crate::metrics::METRICS
+            .response_size
+            .with_label_values(&["inflight.home"]);
+
+        let proxied = {
+            let proxy = (proxy.0.clone(), proxy.1.clone());
+            tokio::spawn(async move { test_request(&proxy, "127.0.0.1:50000", request).await })
+        };
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(in_flight.get(), 1);
+        let response = proxied.await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(in_flight.get(), 0);
+        assert_eq!(response_size.get_sample_count(), 1);
+        assert_eq!(response_size.get_sample_sum(), 10.0);
+        assert!(encode_metrics()
+            .unwrap()
+            .contains("http_requests_in_flight{backend=\"inflight.home\"} 0"));
+    }
+
+    #[tokio::test]
+    async fn test_status_remap() {
+        // Responds to each request on a connection with the status given by its path
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0; 4096];
+                    while let Ok(read) = stream.read(&mut buf).await {
+                        if read == 0 {
+                            break;
+                        }
+                        let request = String::from_utf8_lossy(&buf[..read]).to_string();
+                        let status = &request.split_whitespace().nth(1).unwrap()[1..];
+                        let response = format!(
+                            "HTTP/1.1 {} Custom\r\nContent-Length: 6\r\n\r\ncustom",
+                            status
+                        );
+                        stream.write_all(response.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+        let config = |status_remap: &str| {
+            format!(
+                r#"
+                listen: "127.0.0.1:4000"
+                tls: {{}}
+                timeout: 2000
+                backends:
+                  - name: "remap.home"
+                    location: "{}"
+                    status_remap: {}
+                "#,
+                address, status_remap
+            )
+        };
+        // Statuses may be quoted, as TOML keys are always strings
+        let proxy = test_proxy(&config(r#"{280: 200, "599": 503}"#));
+        let request = |path: &str| {
+            Request::builder()
+                .uri(path)
+                .header(HOST, "remap.home")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = test_request(&proxy, "127.0.0.1:50000", request("/280")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "custom");
+        let response = test_request(&proxy, "127.0.0.1:50000", request("/599")).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        // Statuses not configured pass through
+        let response = test_request(&proxy, "127.0.0.1:50000", request("/201")).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let config: Config = serde_yaml::from_str(&config("{280: 1000}")).unwrap();
+        let client = Client::new(config.timeouts(), &config.client).unwrap();
+        assert!(ProxyConfig::new(config, client).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_body_size_metrics() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("x".repeat(300)))
+            .mount(&mock_server)
+            .await;
+        let proxy = test_proxy(&format!(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {{}}
+            timeout: 2000
+            backends:
+              - name: "sizes.home"
+                location: "{}"
+            "#,
+            mock_server.address()
+        ));
+        let request = Request::builder()
+            .method("POST")
+            .uri("/upload")
+            .header(HOST, "sizes.home")
+            .header(header::CONTENT_LENGTH, "2048")
+            .body(Body::from(vec![b'a'; 2048]))
+            .unwrap();
+        let response = test_request(&proxy, "127.0.0.1:50000", request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let received = mock_server.received_requests().await.unwrap();
+        assert_eq!(received[0].body.len(), 2048);
+
+        let metrics = encode_metrics().unwrap();
+        assert!(metrics.contains("http_request_size_bytes_sum{backend=\"sizes.home\"} 2048"));
+        assert!(metrics
+            .contains("http_request_size_bytes_bucket{backend=\"sizes.home\",le=\"10000\"} 1"));
+        assert!(metrics.contains("http_response_size_bytes_sum{backend=\"sizes.home\"} 300"));
     }
 }
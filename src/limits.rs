@@ -0,0 +1,156 @@
+// Limits on the size of responses relayed from backends, and on how long clients can take to
+// send request bodies
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::StreamExt;
+use hyper::body::HttpBody;
+use hyper::http::{header, Request, Response, StatusCode};
+use hyper::Body;
+use log::warn;
+
+pub fn limit_response_body(response: Response<Body>, max_response_bytes: u64) -> Response<Body> {
+    // A response known to exceed the limit up front is replaced with a 502. Otherwise the body
+    // is counted as it is streamed, and aborted with an error (closing the connection) once the
+    // limit is exceeded, since the headers have already been sent by then
+    let content_length = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if content_length.is_some_and(|content_length| content_length > max_response_bytes) {
+        warn!(
+            "Backend response of {} bytes exceeds the limit of {} bytes",
+            content_length.unwrap_or_default(),
+            max_response_bytes
+        );
+        let mut response = Response::new(Body::from("Backend response too large"));
+        *response.status_mut() = StatusCode::BAD_GATEWAY;
+        return response;
+    }
+
+    if response.body().is_end_stream() {
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let mut received: u64 = 0;
+    let limited = body.map(move |chunk| {
+        let chunk = chunk?;
+        received += chunk.len() as u64;
+        if received > max_response_bytes {
+            warn!(
+                "Backend response exceeded the limit of {} bytes, aborting",
+                max_response_bytes
+            );
+            return Err(anyhow::Error::msg("Backend response too large").into());
+        }
+        Ok::<_, Box<dyn std::error::Error + Send + Sync>>(chunk)
+    });
+    Response::from_parts(parts, Body::wrap_stream(limited))
+}
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Clone, Default)]
+pub struct BodyStall(Arc<AtomicBool>); // Set once the request body has timed out
+
+impl BodyStall {
+    pub fn stalled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+pub fn limit_request_body_time(
+    req: Request<Body>,
+    request_body_timeout: Duration,
+) -> (Request<Body>, BodyStall) {
+    // The timeout is between the chunks of the body, from when the headers were received, so
+    // a large upload isn't timed out as long as the client keeps sending it. A stalled body is
+    // aborted with an error, failing the request to the backend
+    let stall = BodyStall::default();
+    if req.body().is_end_stream() {
+        return (req, stall);
+    }
+    let (parts, body) = req.into_parts();
+    let body_stall = stall.clone();
+    let timed = futures::stream::unfold(Some(body), move |body| {
+        let body_stall = body_stall.clone();
+        async move {
+            let mut body = body?;
+            match tokio::time::timeout(request_body_timeout, body.data()).await {
+                Ok(Some(chunk)) => Some((chunk.map_err(BoxError::from), Some(body))),
+                Ok(None) => None,
+                Err(_) => {
+                    warn!(
+                        "Request body not received within {:?}, aborting",
+                        request_body_timeout
+                    );
+                    body_stall.0.store(true, Ordering::SeqCst);
+                    Some((Err(BoxError::from("Request body timeout")), None))
+                }
+            }
+        }
+    });
+    (Request::from_parts(parts, Body::wrap_stream(timed)), stall)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::body::Bytes;
+
+    fn streamed_response(chunks: Vec<&'static str>) -> Response<Body> {
+        let stream = futures::stream::iter(
+            chunks
+                .into_iter()
+                .map(|chunk| Ok::<_, std::io::Error>(Bytes::from(chunk))),
+        );
+        Response::new(Body::wrap_stream(stream))
+    }
+
+    #[tokio::test]
+    async fn test_limit_response_body() {
+        // Within the limit
+        let response = limit_response_body(streamed_response(vec!["12345", "67890"]), 10);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "1234567890");
+
+        // The streamed body exceeds the limit and is aborted
+        let response = limit_response_body(streamed_response(vec!["12345", "67890", "1"]), 10);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(hyper::body::to_bytes(response.into_body()).await.is_err());
+
+        // The content length exceeds the limit
+        let mut response = Response::new(Body::from("12345678901"));
+        response
+            .headers_mut()
+            .insert(header::CONTENT_LENGTH, "11".parse().unwrap());
+        let response = limit_response_body(response, 10);
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_limit_request_body_time() {
+        // A body sent in time is relayed unchanged
+        let (mut sender, body) = Body::channel();
+        let (req, stall) = limit_request_body_time(Request::new(body), Duration::from_millis(50));
+        tokio::spawn(async move {
+            for chunk in ["12345", "67890"] {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                sender.send_data(Bytes::from(chunk)).await.unwrap();
+            }
+        });
+        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+        assert_eq!(body, "1234567890");
+        assert!(!stall.stalled());
+
+        // The client stalls part way through the body
+        let (mut sender, body) = Body::channel();
+        let (req, stall) = limit_request_body_time(Request::new(body), Duration::from_millis(50));
+        sender.send_data(Bytes::from("12345")).await.unwrap();
+        assert!(hyper::body::to_bytes(req.into_body()).await.is_err());
+        assert!(stall.stalled());
+        drop(sender);
+    }
+}
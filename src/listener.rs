@@ -0,0 +1,378 @@
+// Configuration of accepted client connections
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use axum::middleware::AddExtension;
+use axum::Extension;
+use axum_server::accept::{Accept, DefaultAcceptor};
+use futures::future::{self, BoxFuture, FutureExt, TryFutureExt};
+use hyper::server::conn::AddrStream;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tower_layer::Layer;
+
+use super::metrics::METRICS;
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone)]
+pub struct KeepaliveConfig {
+    // Unset options follow the OS defaults
+    pub idle: Option<u64>, // Seconds a connection is idle before probes are sent
+    pub interval: Option<u64>, // Seconds between probes
+    pub count: Option<u32>, // Unacknowledged probes before the connection is dropped
+}
+
+pub fn apply_keepalive<'s>(
+    socket: impl Into<SockRef<'s>>,
+    config: &KeepaliveConfig,
+) -> io::Result<()> {
+    let socket = socket.into();
+    let mut keepalive = TcpKeepalive::new();
+    if let Some(idle) = config.idle {
+        keepalive = keepalive.with_time(Duration::from_secs(idle));
+    }
+    if let Some(interval) = config.interval {
+        keepalive = keepalive.with_interval(Duration::from_secs(interval));
+    }
+    if let Some(count) = config.count {
+        keepalive = keepalive.with_retries(count);
+    }
+    socket.set_keepalive(true)?;
+    socket.set_tcp_keepalive(&keepalive)
+}
+
+#[derive(Debug, Clone)]
+pub struct KeepaliveAcceptor<A = DefaultAcceptor> {
+    // Enables TCP keepalive on accepted connections before passing them to the inner acceptor
+    inner: A,
+    config: Option<KeepaliveConfig>,
+}
+
+impl KeepaliveAcceptor {
+    pub fn new(config: Option<KeepaliveConfig>) -> KeepaliveAcceptor {
+        KeepaliveAcceptor {
+            inner: DefaultAcceptor::new(),
+            config,
+        }
+    }
+}
+
+impl<A, S> Accept<AddrStream, S> for KeepaliveAcceptor<A>
+where
+    A: Accept<AddrStream, S>,
+{
+    type Stream = A::Stream;
+    type Service = A::Service;
+    type Future = A::Future;
+
+    fn accept(&self, stream: AddrStream, service: S) -> Self::Future {
+        if let Some(config) = &self.config {
+            if let Err(e) = apply_keepalive(&stream, config) {
+                warn!(
+                    "Unable to set keepalive for connection from {}: {e}",
+                    stream.remote_addr()
+                );
+            }
+        }
+        self.inner.accept(stream, service)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectionLimitAcceptor<A> {
+    // Limits the concurrent connections from each client IP, closing any beyond the limit
+    inner: A,
+    max_per_ip: Option<usize>,
+    connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl<A> ConnectionLimitAcceptor<A> {
+    pub fn new(max_per_ip: Option<usize>, inner: A) -> ConnectionLimitAcceptor<A> {
+        ConnectionLimitAcceptor {
+            inner,
+            max_per_ip,
+            connections: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<A, S> Accept<AddrStream, S> for ConnectionLimitAcceptor<A>
+where
+    A: Accept<AddrStream, S>,
+    A::Stream: Send + 'static,
+    A::Service: Send + 'static,
+    A::Future: Send + 'static,
+{
+    type Stream = LimitedStream<A::Stream>;
+    type Service = A::Service;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: AddrStream, service: S) -> Self::Future {
+        let guard = match self.max_per_ip {
+            Some(max_per_ip) => {
+                let ip = stream.remote_addr().ip();
+                let mut connections = self.connections.lock().unwrap();
+                let count = connections.entry(ip).or_insert(0);
+                if *count >= max_per_ip {
+                    warn!("Connection limit reached for: {}, closing connection", ip);
+                    return future::ready(Err(io::Error::other("Connection limit reached")))
+                        .boxed();
+                }
+                *count += 1;
+                set_connections_gauge(ip, *count);
+                Some(ConnectionGuard {
+                    ip,
+                    connections: self.connections.clone(),
+                })
+            }
+            None => None,
+        };
+        self.inner
+            .accept(stream, service)
+            .map(|result| {
+                result.map(|(stream, service)| {
+                    (
+                        LimitedStream {
+                            inner: stream,
+                            _guard: guard,
+                        },
+                        service,
+                    )
+                })
+            })
+            .boxed()
+    }
+}
+
+fn set_connections_gauge(ip: IpAddr, count: usize) {
+    let ip = ip.to_string();
+    if count == 0 {
+        let _ = METRICS.connections_per_ip.remove_label_values(&[&ip]);
+    } else {
+        METRICS
+            .connections_per_ip
+            .with_label_values(&[&ip])
+            .set(count as i64);
+    }
+}
+
+struct ConnectionGuard {
+    ip: IpAddr,
+    connections: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(count) = connections.get_mut(&self.ip) {
+            *count -= 1;
+            set_connections_gauge(self.ip, *count);
+            if *count == 0 {
+                connections.remove(&self.ip);
+            }
+        }
+    }
+}
+
+pub struct LimitedStream<T> {
+    // The connection count is released when the stream is dropped
+    inner: T,
+    _guard: Option<ConnectionGuard>,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for LimitedStream<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for LimitedStream<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+// Shared by every listener, so that connection IDs are unique within the process
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+// The ID assigned to a client connection when accepted, added to the extensions of each request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionId(pub u64);
+
+#[derive(Debug, Clone)]
+pub struct ConnectionIdAcceptor<A> {
+    inner: A,
+}
+
+impl<A> ConnectionIdAcceptor<A> {
+    pub fn new(inner: A) -> ConnectionIdAcceptor<A> {
+        ConnectionIdAcceptor { inner }
+    }
+}
+
+impl<A, I, S> Accept<I, S> for ConnectionIdAcceptor<A>
+where
+    A: Accept<I, S>,
+    A::Service: Send + 'static,
+    A::Stream: Send + 'static,
+    A::Future: Send + 'static,
+{
+    type Stream = A::Stream;
+    type Service = AddExtension<A::Service, ConnectionId>;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let connection_id = ConnectionId(NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed));
+        self.inner
+            .accept(stream, service)
+            .map_ok(move |(stream, service)| (stream, Extension(connection_id).layer(service)))
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn test_apply_keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let config = KeepaliveConfig {
+            idle: Some(30),
+            interval: Some(5),
+            count: Some(3),
+        };
+        apply_keepalive(&stream, &config).unwrap();
+
+        let socket = SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+        assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(5));
+        assert_eq!(socket.keepalive_retries().unwrap(), 3);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_connection_limit() {
+        use axum::{routing::get, Router};
+        use axum_server::Handle;
+        use tokio::io::AsyncReadExt;
+
+        let handle = Handle::new();
+        let server = axum_server::bind("127.0.0.1:0".parse().unwrap())
+            .acceptor(ConnectionLimitAcceptor::new(
+                Some(2),
+                KeepaliveAcceptor::new(None),
+            ))
+            .handle(handle.clone())
+            .serve(
+                Router::new()
+                    .route("/", get(|| async { "ok" }))
+                    .into_make_service(),
+            );
+        tokio::spawn(server);
+        let address = handle.listening().await;
+
+        // Connections within the limit stay open
+        let mut first = TcpStream::connect(address).await.unwrap();
+        let mut second = TcpStream::connect(address).await.unwrap();
+        let mut buf = [0; 16];
+        for stream in [&mut first, &mut second] {
+            let read = tokio::time::timeout(Duration::from_millis(100), stream.read(&mut buf));
+            assert!(read.await.is_err());
+        }
+
+        // The excess connection is closed
+        let mut third = TcpStream::connect(address).await.unwrap();
+        let read = tokio::time::timeout(Duration::from_secs(1), third.read(&mut buf));
+        assert_eq!(read.await.unwrap().unwrap(), 0);
+
+        // Closing a connection frees up the limit
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut fourth = TcpStream::connect(address).await.unwrap();
+        let read = tokio::time::timeout(Duration::from_millis(100), fourth.read(&mut buf));
+        assert!(read.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connection_id() {
+        use axum::{routing::get, Router};
+        use axum_server::Handle;
+
+        let handle = Handle::new();
+        let server = axum_server::bind("127.0.0.1:0".parse().unwrap())
+            .acceptor(ConnectionIdAcceptor::new(DefaultAcceptor::new()))
+            .handle(handle.clone())
+            .serve(
+                Router::new()
+                    .route(
+                        "/",
+                        get(
+                            |Extension(connection_id): Extension<ConnectionId>| async move {
+                                connection_id.0.to_string()
+                            },
+                        ),
+                    )
+                    .into_make_service(),
+            );
+        tokio::spawn(server);
+        let uri: hyper::Uri = format!("http://{}/", handle.listening().await)
+            .parse()
+            .unwrap();
+        let connection_id = |client: hyper::Client<hyper::client::HttpConnector>| {
+            let uri = uri.clone();
+            async move {
+                let response = client.get(uri).await.unwrap();
+                hyper::body::to_bytes(response.into_body()).await.unwrap()
+            }
+        };
+
+        // Requests on a kept alive connection share its ID, other connections have their own
+        let client = hyper::Client::new();
+        let first = connection_id(client.clone()).await;
+        let second = connection_id(client).await;
+        let other = connection_id(hyper::Client::new()).await;
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+    }
+}
@@ -1,7 +1,77 @@
 use brachyura::run_server;
 
+const DEFAULT_CONFIG_PATH: &str = "./config.yaml";
+
+const USAGE: &str = "Usage: brachyura [--config <path>]
+
+Options:
+  -c, --config <path>  A YAML or TOML config, by file extension (default ./config.yaml)
+  -h, --help           Print this help
+  -V, --version        Print the version";
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Run(String), // The config path
+    Help,
+    Version,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut config_path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            "-c" | "--config" => match args.next() {
+                Some(path) => config_path = Some(path),
+                None => return Err(format!("{} requires a path", arg)),
+            },
+            _ => match arg.strip_prefix("--config=") {
+                Some(path) => config_path = Some(path.to_string()),
+                None => return Err(format!("Unknown argument: {}", arg)),
+            },
+        }
+    }
+    Ok(Command::Run(
+        config_path.unwrap_or_else(|| String::from(DEFAULT_CONFIG_PATH)),
+    ))
+}
+
 #[tokio::main(worker_threads = 4)]
 async fn main() {
-    let config_path = String::from("./config.yaml");
-    run_server(config_path).await;
+    match parse_args(std::env::args().skip(1)) {
+        Ok(Command::Run(config_path)) => run_server(config_path).await,
+        Ok(Command::Help) => println!("{}", USAGE),
+        Ok(Command::Version) => println!("brachyura {}", env!("CARGO_PKG_VERSION")),
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Command, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[tokio::test]
+    async fn test_parse_args() {
+        assert_eq!(parse(&[]), Ok(Command::Run("./config.yaml".to_string())));
+        assert_eq!(
+            parse(&["--config", "/etc/brachyura/prod.yaml"]),
+            Ok(Command::Run("/etc/brachyura/prod.yaml".to_string()))
+        );
+        assert_eq!(
+            parse(&["--config=prod.toml"]),
+            Ok(Command::Run("prod.toml".to_string()))
+        );
+        assert_eq!(parse(&["-c", "prod.yaml", "--help"]), Ok(Command::Help));
+        assert_eq!(parse(&["-V"]), Ok(Command::Version));
+        assert!(parse(&["--config"]).is_err());
+        assert!(parse(&["prod.yaml"]).is_err());
+    }
 }
@@ -1,17 +1,41 @@
 use std::time::Duration;
 
 use anyhow::Error;
-use hyper::http::Response;
+use hyper::body::HttpBody;
+use hyper::http::{header, HeaderMap, Request, Response};
 use hyper::Body;
 use once_cell::sync::Lazy;
-use prometheus::{self, Encoder, HistogramVec, IntCounterVec, TextEncoder};
-use prometheus::{register_histogram_vec, register_int_counter_vec};
+use prometheus::{
+    self, exponential_buckets, Encoder, Gauge, Histogram, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, TextEncoder,
+};
+use prometheus::{
+    register_gauge, register_histogram, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+};
+use serde::{Deserialize, Serialize};
+
+use super::access_log::ResponseContext;
+use super::body::inspect_body_with_guard;
+use super::client::BackendError;
 
 pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
 
 pub struct Metrics {
     pub http_request_counter: IntCounterVec,
     pub http_request_duration: HistogramVec,
+    pub retry_budget_usage: Gauge,
+    pub retries_suppressed: IntCounter,
+    pub connections_per_ip: IntGaugeVec,
+    pub routing_lock_wait: Histogram,
+    pub tls_handshakes: IntGauge,
+    pub compression_bytes_saved: IntCounterVec,
+    pub cache_hits: IntCounterVec,
+    pub cache_misses: IntCounterVec,
+    pub request_size: HistogramVec,
+    pub response_size: HistogramVec,
+    pub requests_in_flight: IntGaugeVec,
+    pub proxy_errors: IntCounterVec,
 }
 
 impl Metrics {
@@ -20,20 +44,235 @@ impl Metrics {
             http_request_counter: register_int_counter_vec!(
                 "http_request_total",
                 "Number of http requests received",
-                &["status", "backend"]
+                &["status", "backend", "host", "path_prefix"]
             )
             .expect("Error creating prometheus counter"),
 
             http_request_duration: register_histogram_vec!(
                 "http_request_duration_seconds",
                 "The HTTP request latencies in seconds.",
-                &["status", "backend"]
+                &["status", "backend", "host", "path_prefix"]
+            )
+            .expect("Error creating histogram counter"),
+
+            retry_budget_usage: register_gauge!(
+                "retry_budget_usage_ratio",
+                "The fraction of the retry budget used in the current window"
+            )
+            .expect("Error creating prometheus gauge"),
+
+            retries_suppressed: register_int_counter!(
+                "retries_suppressed_total",
+                "Number of retries not made as the retry budget was exhausted"
+            )
+            .expect("Error creating prometheus counter"),
+
+            connections_per_ip: register_int_gauge_vec!(
+                "client_connections",
+                "Current connections per client IP, when max_connections_per_ip is set",
+                &["ip"]
+            )
+            .expect("Error creating prometheus gauge"),
+
+            // Buckets from 1µs, as an uncontended lock is acquired in well under a millisecond
+            routing_lock_wait: register_histogram!(
+                "routing_lock_wait_seconds",
+                "Time spent waiting to acquire the routing state lock in seconds.",
+                exponential_buckets(0.000_001, 4.0, 10).expect("Invalid histogram buckets")
+            )
+            .expect("Error creating histogram counter"),
+
+            tls_handshakes: register_int_gauge!(
+                "tls_handshakes_in_progress",
+                "TLS handshakes in progress, not including those queued by max_concurrent_handshakes"
+            )
+            .expect("Error creating prometheus gauge"),
+
+            compression_bytes_saved: register_int_counter_vec!(
+                "compression_bytes_saved_total",
+                "Bytes by which compressed responses were smaller than the backend responses",
+                &["backend"]
+            )
+            .expect("Error creating prometheus counter"),
+
+            // A backend error replaced by a stale response is a hit, one without a cached
+            // response to serve is a miss
+            cache_hits: register_int_counter_vec!(
+                "cache_hits_total",
+                "Number of backend errors served a cached response by stale_if_error",
+                &["backend"]
+            )
+            .expect("Error creating prometheus counter"),
+
+            cache_misses: register_int_counter_vec!(
+                "cache_misses_total",
+                "Number of backend errors with no cached response for stale_if_error to serve",
+                &["backend"]
+            )
+            .expect("Error creating prometheus counter"),
+
+            // Buckets from 100 bytes to 100MB
+            request_size: register_histogram_vec!(
+                "http_request_size_bytes",
+                "The sizes of proxied request bodies in bytes.",
+                &["backend"],
+                exponential_buckets(100.0, 10.0, 7).expect("Invalid histogram buckets")
+            )
+            .expect("Error creating histogram counter"),
+
+            response_size: register_histogram_vec!(
+                "http_response_size_bytes",
+                "The sizes of proxied response bodies in bytes.",
+                &["backend"],
+                exponential_buckets(100.0, 10.0, 7).expect("Invalid histogram buckets")
             )
             .expect("Error creating histogram counter"),
+
+            requests_in_flight: register_int_gauge_vec!(
+                "http_requests_in_flight",
+                "Requests currently being proxied, until the response head is returned",
+                &["backend"]
+            )
+            .expect("Error creating prometheus gauge"),
+
+            proxy_errors: register_int_counter_vec!(
+                "proxy_error_total",
+                "Number of requests the proxy responded to itself, without a backend response",
+                &["reason"]
+            )
+            .expect("Error creating prometheus counter"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyError {
+    NoHost,        // No Host header, or :authority with HTTP/2
+    NoBackend,     // No backend matching the host and path
+    BadVersion,    // An unsupported HTTP version, or HTTP/1.0 when rejected
+    ConnectFailed, // The backend couldn't be connected to, after any retries
+    Timeout,       // The backend or the request deadline timed out
+    Protocol,      // The backend's response was invalid, e.g. ambiguously framed
+}
+
+impl ProxyError {
+    pub fn from_backend_error(backend_error: Option<&BackendError>) -> Option<ProxyError> {
+        match backend_error? {
+            BackendError::Connect => Some(ProxyError::ConnectFailed),
+            BackendError::Timeout | BackendError::FirstByteTimeout => Some(ProxyError::Timeout),
+            BackendError::Protocol => Some(ProxyError::Protocol),
+            BackendError::Other => None,
+        }
+    }
+
+    fn reason(self) -> &'static str {
+        match self {
+            ProxyError::NoHost => "no_host",
+            ProxyError::NoBackend => "no_backend",
+            ProxyError::BadVersion => "bad_version",
+            ProxyError::ConnectFailed => "connect_failed",
+            ProxyError::Timeout => "timeout",
+            ProxyError::Protocol => "protocol_error",
         }
     }
 }
 
+pub fn record_proxy_error(error: ProxyError) {
+    METRICS
+        .proxy_errors
+        .with_label_values(&[error.reason()])
+        .inc();
+}
+
+pub struct InFlightRequest(IntGauge);
+
+impl InFlightRequest {
+    // Counts the request as in flight to the backend until dropped
+    pub fn start(backend: &str) -> InFlightRequest {
+        let gauge = METRICS.requests_in_flight.with_label_values(&[backend]);
+        gauge.inc();
+        InFlightRequest(gauge)
+    }
+}
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+struct StreamedSize {
+    histogram: Histogram,
+    bytes: u64,
+}
+
+impl StreamedSize {
+    fn add(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+    }
+}
+
+impl Drop for StreamedSize {
+    fn drop(&mut self) {
+        // Includes bodies which were cut short, with the bytes streamed before they were dropped
+        self.histogram.observe(self.bytes as f64);
+    }
+}
+
+fn observe_body_size(headers: &HeaderMap, body: Body, histogram: Histogram) -> Body {
+    // The Content-Length is observed when known, otherwise the bytes are counted as they're
+    // streamed
+    if body.is_end_stream() {
+        histogram.observe(0.0);
+        return body;
+    }
+    let content_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if let Some(content_length) = content_length {
+        histogram.observe(content_length as f64);
+        return body;
+    }
+    let streamed = StreamedSize {
+        histogram,
+        bytes: 0,
+    };
+    inspect_body_with_guard(body, streamed, |streamed, chunk| streamed.add(chunk.len()))
+}
+
+pub fn observe_request_size(req: Request<Body>, backend: &str) -> Request<Body> {
+    let histogram = METRICS.request_size.with_label_values(&[backend]);
+    let (parts, body) = req.into_parts();
+    let body = observe_body_size(&parts.headers, body, histogram);
+    Request::from_parts(parts, body)
+}
+
+pub fn observe_response_size(response: Response<Body>, backend: &str) -> Response<Body> {
+    let histogram = METRICS.response_size.with_label_values(&[backend]);
+    let (parts, body) = response.into_parts();
+    let body = observe_body_size(&parts.headers, body, histogram);
+    Response::from_parts(parts, body)
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MetricsConfig {
+    exclude_paths: Option<Vec<String>>,
+}
+
+impl MetricsConfig {
+    pub fn is_excluded(&self, path: &str) -> bool {
+        // Paths are matched exactly, or by prefix when the pattern ends with a *
+        self.exclude_paths
+            .iter()
+            .flatten()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => path.starts_with(prefix),
+                None => path == pattern,
+            })
+    }
+}
+
 pub fn encode_metrics() -> Result<String, Error> {
     let mut buffer = Vec::new();
     let encoder = TextEncoder::new();
@@ -47,14 +286,26 @@ pub fn record_metrics(
     backend_location: String,
     duration: Duration,
 ) -> Result<(), Error> {
+    // The host is the configured backend name rather than the Host requested, which would
+    // give a label value for every random host sent by clients
+    let context = response.extensions().get::<ResponseContext>();
+    let host = context.and_then(|context| context.host.as_deref());
+    let path_prefix = context.and_then(|context| context.path_prefix.as_deref());
+    let status = response.status();
+    let labels = [
+        status.as_str(),
+        backend_location.as_str(),
+        host.unwrap_or_default(),
+        path_prefix.unwrap_or_default(),
+    ];
     METRICS
         .http_request_counter
-        .with_label_values(&[response.status().as_str(), backend_location.as_str()])
+        .with_label_values(&labels)
         .inc_by(1);
 
     METRICS
         .http_request_duration
-        .with_label_values(&[response.status().as_str(), backend_location.as_str()])
+        .with_label_values(&labels)
         .observe(duration.as_secs_f64());
     Ok(())
 }
@@ -67,12 +318,12 @@ mod tests {
     async fn test_metrics_struct() {
         METRICS
             .http_request_counter
-            .with_label_values(&["200", "test"])
+            .with_label_values(&["200", "test", "test.home", ""])
             .inc_by(1);
         assert!(
             METRICS
                 .http_request_counter
-                .with_label_values(&["200", "test"])
+                .with_label_values(&["200", "test", "test.home", ""])
                 .get()
                 >= 1
         );
@@ -82,7 +333,7 @@ mod tests {
     async fn test_encode_metrics() {
         METRICS
             .http_request_counter
-            .with_label_values(&["200", "test"])
+            .with_label_values(&["200", "test", "test.home", ""])
             .inc_by(1);
         assert!(encode_metrics().unwrap().contains(
             "# HELP http_request_total Number of http requests received\n\
@@ -90,18 +341,88 @@ mod tests {
         ));
     }
 
+    #[tokio::test]
+    async fn test_metrics_config_is_excluded() {
+        let metrics_config = MetricsConfig {
+            exclude_paths: Some(vec!["/healthz".to_string(), "/static/*".to_string()]),
+        };
+        assert!(metrics_config.is_excluded("/healthz"));
+        assert!(metrics_config.is_excluded("/static/favicon.ico"));
+        assert!(!metrics_config.is_excluded("/healthz/deep"));
+        assert!(!metrics_config.is_excluded("/api"));
+        assert!(!MetricsConfig::default().is_excluded("/healthz"));
+    }
+
     #[tokio::test]
     async fn test_record_metrics() {
-        let response = Response::builder().body(Body::from("test")).unwrap();
+        let mut response = Response::builder().body(Body::from("test")).unwrap();
+        response.extensions_mut().insert(ResponseContext {
+            host: Some("api.home".to_string()),
+            path_prefix: Some("/v1/".to_string()),
+            ..Default::default()
+        });
+        assert!(record_metrics(
+            &response,
+            "127.0.0.1:10000".to_string(),
+            Duration::from_micros(10)
+        )
+        .is_ok());
+        assert!(encode_metrics().unwrap().contains(
+            "http_request_total{backend=\"127.0.0.1:10000\",host=\"api.home\",path_prefix=\"/v1/\",status=\"200\"} 1"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_observe_response_size() {
+        let histogram = METRICS.response_size.with_label_values(&["size.test"]);
+        let response = Response::builder()
+            .header(header::CONTENT_LENGTH, "5")
+            .body(Body::from("hello"))
+            .unwrap();
+        observe_response_size(response, "size.test");
+        assert_eq!(histogram.get_sample_count(), 1);
+        assert_eq!(histogram.get_sample_sum(), 5.0);
+
+        // Without a Content-Length the streamed bytes are observed once the body is done
+        let chunks = futures::stream::iter(vec![Ok::<_, std::io::Error>("abc"), Ok("defg")]);
+        let response = Response::new(Body::wrap_stream(chunks));
+        let response = observe_response_size(response, "size.test");
+        assert_eq!(histogram.get_sample_count(), 1);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "abcdefg");
+        assert_eq!(histogram.get_sample_count(), 2);
+        assert_eq!(histogram.get_sample_sum(), 12.0);
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_request() {
+        let gauge = METRICS
+            .requests_in_flight
+            .with_label_values(&["flight.test"]);
+        let in_flight = InFlightRequest::start("flight.test");
+        assert_eq!(gauge.get(), 1);
+        drop(in_flight);
+        assert_eq!(gauge.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_error_from_backend_error() {
         assert_eq!(
-            record_metrics(
-                &response,
-                "127.0.0.1:10000".to_string(),
-                Duration::from_micros(10)
-            )
-            .is_ok(),
-            true
+            ProxyError::from_backend_error(Some(&BackendError::Connect)),
+            Some(ProxyError::ConnectFailed)
+        );
+        assert_eq!(
+            ProxyError::from_backend_error(Some(&BackendError::FirstByteTimeout)),
+            Some(ProxyError::Timeout)
+        );
+        assert_eq!(
+            ProxyError::from_backend_error(Some(&BackendError::Protocol)),
+            Some(ProxyError::Protocol)
+        );
+        assert_eq!(
+            ProxyError::from_backend_error(Some(&BackendError::Other)),
+            None
         );
-        assert!(encode_metrics().unwrap().contains("127.0.0.1:10000"));
+        assert_eq!(ProxyError::from_backend_error(None), None);
     }
 }
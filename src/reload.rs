@@ -0,0 +1,278 @@
+// Reloading of the proxy config while the proxy is running
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::Error;
+#[cfg(unix)]
+use futures::stream::{self, Stream, StreamExt};
+use log::{info, warn};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+#[cfg(unix)]
+use tokio::task::JoinHandle;
+
+use super::client::Client;
+use super::drain::DEFAULT_DRAIN_TIMEOUT;
+use super::health::run_health_checks;
+use super::{read_proxy_config, ProxyConfig, ProxyState, SharedProxyConfig};
+
+#[derive(Clone)]
+pub struct Reloader {
+    config_path: String,
+    proxy_config: SharedProxyConfig,
+    proxy_state: Arc<RwLock<ProxyState>>,
+}
+
+impl Reloader {
+    pub fn new(
+        config_path: String,
+        proxy_config: SharedProxyConfig,
+        proxy_state: Arc<RwLock<ProxyState>>,
+    ) -> Reloader {
+        Reloader {
+            config_path,
+            proxy_config,
+            proxy_state,
+        }
+    }
+
+    pub fn proxy_config(&self) -> Arc<ProxyConfig> {
+        self.proxy_config.read().unwrap().clone()
+    }
+
+    pub async fn reload(&self) -> Result<(), Error> {
+        // An invalid config returns an error, leaving the current config in place
+        let config = read_proxy_config(self.config_path.clone()).await?;
+        let old_proxy_config = self.proxy_config.read().unwrap().clone();
+        // An unchanged client config keeps the current client, and with it the pooled
+        // connections. Files it names, such as ca_path, aren't checked for changes
+        let client = if config.client == old_proxy_config.config.client
+            && config.timeouts() == old_proxy_config.config.timeouts()
+        {
+            old_proxy_config.client.clone()
+        } else {
+            Client::new(config.timeouts(), &config.client)?
+        };
+        let new_proxy_config = Arc::new(ProxyConfig::new(config, client)?);
+
+        let health_checked: HashSet<&String> = old_proxy_config
+            .config
+            .backends
+            .iter()
+            .filter(|backend| backend.health_check.is_some())
+            .filter_map(|backend| backend.name.as_ref())
+            .collect();
+        let new_names: HashSet<&String> = new_proxy_config
+            .config
+            .backends
+            .iter()
+            .filter_map(|backend| backend.name.as_ref())
+            .collect();
+
+        // Existing backends keep their state, new backends get fresh state
+        self.proxy_state
+            .write()
+            .unwrap()
+            .update(&new_proxy_config.config);
+        *self.proxy_config.write().unwrap() = new_proxy_config.clone();
+
+        // Health checks of existing backends follow the new config, backends newly health
+        // checked are started
+        let mut started = HashSet::new();
+        for backend in &new_proxy_config.config.backends {
+            if let (Some(name), Some(_)) = (&backend.name, &backend.health_check) {
+                if !health_checked.contains(name) && started.insert(name) {
+                    tokio::spawn(run_health_checks(
+                        self.proxy_config.clone(),
+                        self.proxy_state.clone(),
+                        name.clone(),
+                        false,
+                    ));
+                }
+            }
+        }
+
+        // Requests already routed to a removed backend hold the old config,
+        // and are given until the drain timeout to complete
+        for backend in &old_proxy_config.config.backends {
+            let name = match &backend.name {
+                Some(name) if !new_names.contains(name) => name.clone(),
+                _ => continue,
+            };
+            if let Some(in_flight) = old_proxy_config.in_flight.get(&name).cloned() {
+                let drain_timeout =
+                    Duration::from_millis(backend.drain_timeout.unwrap_or(DEFAULT_DRAIN_TIMEOUT));
+                info!(
+                    "Backend removed: {}, draining {} in-flight requests",
+                    name,
+                    in_flight.count()
+                );
+                tokio::spawn(async move { in_flight.drain(&name, drain_timeout).await });
+            }
+        }
+        info!("Config reloaded from: {}", self.config_path);
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+pub fn reload_on_sighup(reloader: Reloader) -> Result<JoinHandle<()>, std::io::Error> {
+    // The handler is installed before returning, so a SIGHUP can't terminate the proxy
+    let hangup = signal(SignalKind::hangup())?;
+    let hangups = stream::unfold(hangup, |mut hangup| async move {
+        hangup.recv().await.map(|()| ((), hangup))
+    });
+    Ok(reload_on(reloader, hangups))
+}
+
+#[cfg(unix)]
+fn reload_on<S>(reloader: Reloader, signals: S) -> JoinHandle<()>
+where
+    S: Stream<Item = ()> + Send + 'static,
+{
+    // The config is reloaded for each signal, until the stream ends
+    tokio::spawn(async move {
+        futures::pin_mut!(signals);
+        while signals.next().await.is_some() {
+            info!("SIGHUP received, reloading config");
+            if let Err(e) = reloader.reload().await {
+                warn!("Error reloading config, keeping the current config: {e}");
+            }
+        }
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use hyper::{Body, Request};
+
+    async fn wait_for_backends(reloader: &Reloader, count: usize) -> bool {
+        for _ in 0..50 {
+            if reloader.proxy_config().config.backends.len() == count {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn test_reload_on_sighup() {
+        let config_path = std::env::temp_dir().join("brachyura_test_sighup.yaml");
+        let config_yaml = std::fs::read_to_string("tests/config.yaml").unwrap();
+        std::fs::write(&config_path, &config_yaml).unwrap();
+        let config_path = config_path.to_str().unwrap().to_string();
+        let config = read_proxy_config(config_path.clone()).await.unwrap();
+        let backend_count = config.backends.len();
+        let client = Client::new(config.timeouts(), &config.client).unwrap();
+        let proxy_state = Arc::new(RwLock::new(ProxyState::new(&config)));
+        let proxy_config = Arc::new(ProxyConfig::new(config, client).unwrap());
+        let reloader = Reloader::new(
+            config_path.clone(),
+            Arc::new(RwLock::new(proxy_config)),
+            proxy_state,
+        );
+        // Signals are sent on a channel rather than to the test process
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let signals = stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|()| ((), receiver))
+        });
+        let reloading = reload_on(reloader.clone(), signals);
+        let sighup = || sender.send(()).unwrap();
+
+        let added = format!(
+            "{}\n  - name: \"sighup.home\"\n    location: \"127.0.0.1:8000\"\n",
+            config_yaml.trim_end()
+        );
+        std::fs::write(&config_path, added).unwrap();
+        sighup();
+        assert!(wait_for_backends(&reloader, backend_count + 1).await);
+
+        // An invalid config is rejected, the current config keeps serving
+        std::fs::write(&config_path, "backends: [").unwrap();
+        sighup();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(
+            reloader.proxy_config().config.backends.len(),
+            backend_count + 1
+        );
+
+        // Reloading ends with the signal stream
+        drop(sender);
+        reloading.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reload_keeps_client() {
+        let mock_server = wiremock::MockServer::start().await;
+        wiremock::Mock::given(wiremock::matchers::method("GET"))
+            .respond_with(wiremock::ResponseTemplate::new(200))
+            .mount(&mock_server)
+            .await;
+        let config_path = std::env::temp_dir().join("brachyura_test_reload_client.yaml");
+        let config_yaml = std::fs::read_to_string("tests/config.yaml").unwrap();
+        std::fs::write(&config_path, &config_yaml).unwrap();
+        let config_path = config_path.to_str().unwrap().to_string();
+        let config = read_proxy_config(config_path.clone()).await.unwrap();
+        let client = Client::new(config.timeouts(), &config.client).unwrap();
+        let proxy_state = Arc::new(RwLock::new(ProxyState::new(&config)));
+        let proxy_config = Arc::new(ProxyConfig::new(config, client).unwrap());
+        let reloader = Reloader::new(
+            config_path.clone(),
+            Arc::new(RwLock::new(proxy_config)),
+            proxy_state,
+        );
+        let mut request = Request::new(Body::empty());
+        *request.uri_mut() = format!("{}/", mock_server.uri()).parse().unwrap();
+        let response = reloader
+            .proxy_config()
+            .client
+            .make_request(request, true)
+            .await;
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let location = mock_server.address().to_string();
+        let open = |reloader: &Reloader| {
+            reloader
+                .proxy_config()
+                .client
+                .connections()
+                .get(&location)
+                .map(|counts| counts.open)
+        };
+        assert_eq!(open(&reloader), Some(1));
+
+        // The pooled connection is kept when only the backends change
+        let added = format!(
+            "{}\n  - name: \"client.home\"\n    location: \"127.0.0.1:8000\"\n",
+            config_yaml.trim_end()
+        );
+        std::fs::write(&config_path, &added).unwrap();
+        reloader.reload().await.unwrap();
+        assert_eq!(open(&reloader), Some(1));
+
+        // A changed client config gets a new client
+        std::fs::write(&config_path, format!("connect_timeout: 1000\n{}", added)).unwrap();
+        reloader.reload().await.unwrap();
+        assert_eq!(open(&reloader), None);
+    }
+
+    #[tokio::test]
+    async fn test_reload_on_sighup_installs_handler() {
+        let config = read_proxy_config("tests/config.yaml".to_string())
+            .await
+            .unwrap();
+        let client = Client::new(config.timeouts(), &config.client).unwrap();
+        let proxy_state = Arc::new(RwLock::new(ProxyState::new(&config)));
+        let proxy_config = Arc::new(ProxyConfig::new(config, client).unwrap());
+        let reloader = Reloader::new(
+            "tests/config.yaml".to_string(),
+            Arc::new(RwLock::new(proxy_config)),
+            proxy_state,
+        );
+        let reloading = reload_on_sighup(reloader).unwrap();
+        assert!(!reloading.is_finished());
+        reloading.abort();
+    }
+}
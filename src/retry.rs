@@ -0,0 +1,320 @@
+// Retrying of failed backend requests, limited by a global retry budget so
+// that retries can't multiply the load on backends during an incident
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Error};
+use futures::stream::{self, StreamExt};
+use hyper::body::{Bytes, HttpBody};
+use hyper::http::{header, HeaderValue, Method, Request, Response, StatusCode};
+use hyper::Body;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::client::BackendError;
+use super::has_body;
+use super::metrics::METRICS;
+
+const DEFAULT_BUDGET_PERCENT: u32 = 10;
+const DEFAULT_BUDGET_MIN_RETRIES: u32 = 10;
+const DEFAULT_BUDGET_WINDOW: u64 = 10_000;
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RetryBudgetConfig {
+    percent: Option<u32>, // Retries allowed as a percentage of requests, defaults to 10
+    min_retries: Option<u32>, // Retries always allowed within the window, defaults to 10
+    window: Option<u64>,  // Sliding window (ms), defaults to 10000
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Clone)]
+pub struct RetriesExhaustedResponse {
+    status: Option<u16>,  // Defaults to 503
+    body: Option<String>, // Defaults to "Retries exhausted"
+}
+
+impl RetriesExhaustedResponse {
+    pub fn status(&self) -> Result<StatusCode, Error> {
+        StatusCode::from_u16(self.status.unwrap_or(503)).with_context(|| {
+            format!(
+                "Invalid retries_exhausted_response status: {:?}",
+                self.status
+            )
+        })
+    }
+
+    pub fn to_response(&self) -> Response<Body> {
+        let body = self.body.as_deref().unwrap_or("Retries exhausted");
+        let mut response = Response::new(Body::from(body.to_string()));
+        *response.status_mut() = self.status().unwrap_or(StatusCode::SERVICE_UNAVAILABLE);
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; charset=utf-8"),
+        );
+        response
+    }
+}
+
+// The window is divided into buckets of request and retry counts, so that requests only
+// update atomic counters rather than sharing a lock. Counts expire a bucket at a time, so the
+// budget covers between the window less a bucket and the whole window
+const BUDGET_BUCKETS: u32 = 10;
+
+pub struct RetryBudget {
+    percent: u32,
+    min_retries: u32,
+    start: Instant,
+    bucket_nanos: u128, // The window divided into the buckets
+    requests: [BudgetCounter; BUDGET_BUCKETS as usize],
+    retries: [BudgetCounter; BUDGET_BUCKETS as usize],
+}
+
+// A count in the high bits and the period since the start (wrapping) it's for in the low
+// bits, so that a counter is moved on to a new period and counted in a single update
+#[derive(Default)]
+struct BudgetCounter(AtomicU64);
+
+impl BudgetCounter {
+    fn increment(&self, period: u32) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |packed| {
+                let count = if packed as u32 == period {
+                    (packed >> 32).saturating_add(1)
+                } else {
+                    1
+                };
+                Some(count << 32 | u64::from(period))
+            });
+    }
+
+    fn count(&self, period: u32) -> u64 {
+        // Zero once the period is outside the window
+        let packed = self.0.load(Ordering::Relaxed);
+        if period.wrapping_sub(packed as u32) < BUDGET_BUCKETS {
+            packed >> 32
+        } else {
+            0
+        }
+    }
+}
+
+impl RetryBudget {
+    pub fn new(config: &RetryBudgetConfig) -> RetryBudget {
+        let window = Duration::from_millis(config.window.unwrap_or(DEFAULT_BUDGET_WINDOW));
+        RetryBudget {
+            percent: config.percent.unwrap_or(DEFAULT_BUDGET_PERCENT),
+            min_retries: config.min_retries.unwrap_or(DEFAULT_BUDGET_MIN_RETRIES),
+            start: Instant::now(),
+            bucket_nanos: (window.as_nanos() / u128::from(BUDGET_BUCKETS)).max(1),
+            requests: Default::default(),
+            retries: Default::default(),
+        }
+    }
+
+    pub fn record_request(&self) {
+        let period = self.period();
+        self.requests[(period % BUDGET_BUCKETS) as usize].increment(period);
+    }
+
+    pub fn try_retry(&self) -> bool {
+        // Returns whether a retry is within the budget, recording it if so. Concurrent
+        // retries can each see the last retry left in the budget, so it may be slightly
+        // exceeded under load
+        let period = self.period();
+        let sum = |counters: &[BudgetCounter]| -> u64 {
+            counters.iter().map(|counter| counter.count(period)).sum()
+        };
+        let allowed =
+            u64::from(self.min_retries) + sum(&self.requests) * u64::from(self.percent) / 100;
+        let retries = sum(&self.retries);
+        let within_budget = retries < allowed;
+        if within_budget {
+            self.retries[(period % BUDGET_BUCKETS) as usize].increment(period);
+        } else {
+            METRICS.retries_suppressed.inc();
+        }
+        METRICS
+            .retry_budget_usage
+            .set((retries + u64::from(within_budget)) as f64 / allowed.max(1) as f64);
+        within_budget
+    }
+
+    fn period(&self) -> u32 {
+        // Wraps, counts only being compared with those of recent periods
+        (self.start.elapsed().as_nanos() / self.bucket_nanos) as u32
+    }
+}
+
+pub fn retryable_request(req: &Request<Body>) -> bool {
+    // Only idempotent requests can be resent
+    matches!(req.method(), &Method::GET | &Method::HEAD | &Method::PUT)
+}
+
+pub async fn buffer_body(req: Request<Body>, limit: u64) -> (Request<Body>, Option<Bytes>) {
+    // Buffers a body of up to limit bytes, so that the request can be resent. A larger body is
+    // forwarded unchanged (including the bytes already read), and the request isn't retried
+    if !has_body(&req) {
+        return (req, Some(Bytes::new()));
+    }
+    let content_length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if content_length.is_some_and(|content_length| content_length > limit) {
+        return (req, None);
+    }
+    let (parts, mut body) = req.into_parts();
+    let mut buffered = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let unbuffered = match chunk {
+            Ok(chunk) => {
+                buffered.extend_from_slice(&chunk);
+                if buffered.len() as u64 <= limit {
+                    continue;
+                }
+                Body::wrap_stream(stream::once(async { Ok(Bytes::from(buffered)) }).chain(body))
+            }
+            Err(e) => Body::wrap_stream(stream::iter([Ok(Bytes::from(buffered)), Err(e)])),
+        };
+        return (Request::from_parts(parts, unbuffered), None);
+    }
+    let buffered = Bytes::from(buffered);
+    (
+        Request::from_parts(parts, Body::from(buffered.clone())),
+        Some(buffered),
+    )
+}
+
+pub fn retryable_error(backend_error: Option<&BackendError>) -> bool {
+    // Timed out requests may have been processed by the backend, so aren't retried
+    matches!(
+        backend_error,
+        Some(BackendError::Connect) | Some(BackendError::Protocol) | Some(BackendError::Other)
+    )
+}
+
+pub fn retry_delay<R: Rng>(
+    backoff: Option<u64>,
+    jitter: Option<u64>,
+    retry: u32,
+    rng: &mut R,
+) -> Duration {
+    // The backoff doubles with each retry (the first waits the base delay), plus a random
+    // jitter of up to the configured milliseconds so that clients don't retry in step
+    let backoff = backoff
+        .unwrap_or(0)
+        .saturating_mul(1 << retry.saturating_sub(1).min(16));
+    let jitter = match jitter {
+        Some(jitter) if jitter > 0 => rng.gen_range(0..=jitter),
+        _ => 0,
+    };
+    Duration::from_millis(backoff.saturating_add(jitter))
+}
+
+pub fn clone_request(req: &Request<Body>, body: Bytes) -> Request<Body> {
+    let mut builder = Request::builder()
+        .method(req.method().clone())
+        .uri(req.uri().clone())
+        .version(req.version());
+    if let Some(headers) = builder.headers_mut() {
+        *headers = req.headers().clone();
+    }
+    builder
+        .body(Body::from(body))
+        .expect("Unable to clone request")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_retry_budget() {
+        let budget = RetryBudget::new(&RetryBudgetConfig {
+            percent: Some(10),
+            min_retries: Some(1),
+            window: Some(100),
+        });
+        for _ in 0..20 {
+            budget.record_request();
+        }
+        // 1 retry plus 10% of 20 requests
+        assert!(budget.try_retry());
+        assert!(budget.try_retry());
+        assert!(budget.try_retry());
+        assert!(!budget.try_retry());
+
+        // The budget recovers once the window has passed
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(budget.try_retry());
+        assert!(!budget.try_retry());
+
+        // Requests are counted without a shared lock, none are lost when concurrent
+        let budget = std::sync::Arc::new(RetryBudget::new(&RetryBudgetConfig {
+            percent: Some(10),
+            min_retries: Some(0),
+            window: Some(60_000),
+        }));
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let budget = budget.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        budget.record_request();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let retries = (0..1000).take_while(|_| budget.try_retry()).count();
+        assert_eq!(retries, 800);
+    }
+
+    #[tokio::test]
+    async fn test_buffer_body() {
+        let request = |body: &'static str| {
+            Request::put("/")
+                .header(header::TRANSFER_ENCODING, "chunked")
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let (req, buffered) = buffer_body(request("payload"), 7).await;
+        assert_eq!(buffered.unwrap(), "payload");
+        assert_eq!(hyper::body::to_bytes(req).await.unwrap(), "payload");
+
+        // A larger body is forwarded in full, but can't be retried
+        let (req, buffered) = buffer_body(request("payload"), 6).await;
+        assert!(buffered.is_none());
+        assert_eq!(hyper::body::to_bytes(req).await.unwrap(), "payload");
+
+        let req = Request::put("/")
+            .header(header::CONTENT_LENGTH, "7")
+            .body(Body::from("payload"))
+            .unwrap();
+        let (req, buffered) = buffer_body(req, 6).await;
+        assert!(buffered.is_none());
+        assert_eq!(hyper::body::to_bytes(req).await.unwrap(), "payload");
+    }
+
+    #[tokio::test]
+    async fn test_retry_delay() {
+        let mut rng = rand::thread_rng();
+        assert_eq!(retry_delay(None, None, 1, &mut rng), Duration::ZERO);
+        assert_eq!(
+            retry_delay(Some(100), None, 1, &mut rng),
+            Duration::from_millis(100)
+        );
+        assert_eq!(
+            retry_delay(Some(100), None, 3, &mut rng),
+            Duration::from_millis(400)
+        );
+        for _ in 0..100 {
+            let delay = retry_delay(Some(100), Some(50), 2, &mut rng);
+            assert!(delay >= Duration::from_millis(200));
+            assert!(delay <= Duration::from_millis(250));
+        }
+    }
+}
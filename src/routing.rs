@@ -1,63 +1,507 @@
 // Logic for selecting the request backend
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use super::{Backend, BackendState, ProxyState};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
 
-pub fn router(
-    backends_config: &[Backend],
-    proxy_state: Arc<Mutex<ProxyState>>,
+use hyper::Uri;
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use log::warn;
+
+use super::auth::{claims_match, Claims};
+use super::health::PassiveHealthConfig;
+use super::metrics::METRICS;
+use super::{Backend, BackendState, ProxyState, TrailingSlash};
+
+#[derive(Debug)]
+pub struct Route<'a> {
+    pub backend: &'a Backend,
+    pub location: String,
+    pub reason: &'static str, // How the location was selected
+}
+
+pub fn router<'a>(
+    backends_config: &'a [Backend],
+    proxy_state: Arc<RwLock<ProxyState>>,
     host_header: &str,
-) -> Option<String> {
+    path: &str,
+    local_zone: Option<&str>,
+    claims: Option<&Claims>,
+) -> Option<Route<'a>> {
     // Matches a given host header with a backend
     // Performs load balancing when configured
+    route(
+        backends_config,
+        proxy_state,
+        host_header,
+        path,
+        local_zone,
+        claims,
+        false,
+    )
+}
 
-    // Proxy state mutex is unlocked within this function (rather than in calling code)
-    // so that the mutex guard goes out of scope once the function completes
-    let backends_state = &mut proxy_state.lock().unwrap().backends;
+pub fn peek_router<'a>(
+    backends_config: &'a [Backend],
+    proxy_state: Arc<RwLock<ProxyState>>,
+    host_header: &str,
+    path: &str,
+    local_zone: Option<&str>,
+    claims: Option<&Claims>,
+) -> Option<Route<'a>> {
+    // As router, but the location the next request would be sent to is returned without
+    // advancing the round robin / weighted state, e.g. for route debug requests
+    route(
+        backends_config,
+        proxy_state,
+        host_header,
+        path,
+        local_zone,
+        claims,
+        true,
+    )
+}
 
-    let backend = match_backend(backends_config, host_header)?;
+fn route<'a>(
+    backends_config: &'a [Backend],
+    proxy_state: Arc<RwLock<ProxyState>>,
+    host_header: &str,
+    path: &str,
+    local_zone: Option<&str>,
+    claims: Option<&Claims>,
+    peek: bool,
+) -> Option<Route<'a>> {
+    // The proxy state is only written when the config is reloaded, so requests share a read
+    // lock and the per backend state uses atomics / its own locks
+    let lock_start = Instant::now();
+    let proxy_state = proxy_state.read().unwrap();
+    METRICS
+        .routing_lock_wait
+        .observe(lock_start.elapsed().as_secs_f64());
+    let backends_state = &proxy_state.backends;
+
+    let backend = match_backend(backends_config, host_header, path, claims)?;
 
     // Check if load balancing is enabled
-    if backend.backend_type.as_deref() == Some("loadbalanced") {
-        if backend.locations.is_some() {
-            let backend_state = backends_state.get_mut(&backend.name.clone()?)?.as_mut()?;
-            round_robin_select(backend.locations.as_ref()?, backend_state)
-        } else {
-            // Config not valid
-            None
+    let (location, reason) = if backend.backend_type.as_deref() == Some("loadbalanced") {
+        let locations = backend.locations.as_ref()?; // Config not valid
+        let backend_state = backends_state.get(backend.name.as_ref()?)?.as_ref()?;
+        let health = proxy_state.health.lock().unwrap();
+        let health_checks = health.get(backend.name.as_ref()?);
+        // The standby locations are only used once every active location is unhealthy. If
+        // every standby location is also unhealthy, all the locations are candidates
+        let healthy = match &backend.standby_locations {
+            Some(standby_locations) => {
+                let active = filter_healthy(locations, backend_state, health_checks);
+                let standby = filter_healthy(standby_locations, backend_state, health_checks);
+                match (active.is_empty(), standby.is_empty()) {
+                    (false, _) => active,
+                    (true, false) => standby,
+                    (true, true) => locations.iter().chain(standby_locations).cloned().collect(),
+                }
+            }
+            None => healthy_locations(locations, backend_state, health_checks),
+        };
+        drop(health);
+        let zone_locations = local_zone
+            .zip(backend.location_zones.as_ref())
+            .map(|(local_zone, location_zones)| zone_select(&healthy, location_zones, local_zone));
+        let locations = zone_locations.as_deref().unwrap_or(&healthy);
+        match backend.slo_target_ms {
+            Some(slo_target_ms) => (
+                slo_select(
+                    locations,
+                    backend_state,
+                    slo_target_ms,
+                    &mut rand::thread_rng(),
+                )?,
+                "slo target latency",
+            ),
+            None => match &backend.weights {
+                Some(weights) => {
+                    let all_locations = backend.locations.as_ref()?;
+                    let weights: HashMap<&str, u32> = all_locations
+                        .iter()
+                        .map(String::as_str)
+                        .zip(weights.iter().copied())
+                        .collect();
+                    (
+                        weighted_select(locations, &weights, backend_state, peek)?,
+                        "weighted round robin",
+                    )
+                }
+                None => (
+                    round_robin_select(locations, backend_state, peek)?,
+                    "round robin",
+                ),
+            },
         }
-    } else if backend.location.is_some() {
+    } else {
         // Load balancing not enabled, return the single location / backend
-        backend.location.clone()
+        (backend.location.clone()?, "single location") // None if config not valid
+    };
+
+    Some(Route {
+        backend,
+        location,
+        reason,
+    })
+}
+
+pub fn match_backend<'a>(
+    backends: &'a [Backend],
+    host_header: &str,
+    path: &str,
+    claims: Option<&Claims>,
+) -> Option<&'a Backend> {
+    // An exact name is preferred to a wildcard name, then the backend with the longest
+    // path_prefix matching the path is selected, backends without one match any path.
+    // Backends with match_claims only match requests with a verified token with those claims,
+    // so should be listed before the default backend of the same name
+    backends
+        .iter()
+        .filter(|&backend| {
+            backend
+                .name
+                .as_deref()
+                .is_some_and(|name| host_matches(name, host_header))
+                && backend.match_claims.as_ref().is_none_or(|match_claims| {
+                    claims.is_some_and(|claims| claims_match(match_claims, claims))
+                })
+                && backend.path_prefix.as_deref().is_none_or(|path_prefix| {
+                    let path_prefix = match backend.trailing_slash.unwrap_or_default() {
+                        TrailingSlash::Strict => path_prefix,
+                        TrailingSlash::Ignore | TrailingSlash::Redirect => {
+                            path_prefix.trim_end_matches('/')
+                        }
+                    };
+                    path_prefix_matches(path_prefix, path)
+                })
+        })
+        .min_by_key(|backend| {
+            Reverse((
+                backend.name.as_deref() == Some(host_header),
+                backend.path_prefix.as_deref().map_or(0, str::len),
+            ))
+        })
+}
+
+pub fn host_matches(name: &str, host_header: &str) -> bool {
+    // A wildcard name such as *.apps.home matches a single label subdomain, a.apps.home but not
+    // apps.home or a.b.apps.home
+    match name.strip_prefix("*.") {
+        Some(domain) => host_header
+            .strip_suffix(domain)
+            .and_then(|label| label.strip_suffix('.'))
+            .is_some_and(|label| !label.is_empty() && !label.contains('.')),
+        None => name == host_header,
+    }
+}
+
+fn path_prefix_matches(path_prefix: &str, path: &str) -> bool {
+    // Prefixes match whole path segments, /api matches /api and /api/v1 but not /apis
+    match path.strip_prefix(path_prefix) {
+        Some(rest) => path_prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+pub fn strip_path_prefix(path_prefix: &str, path: &str) -> Option<String> {
+    // Removes a matching prefix, a path left empty becomes /
+    let path_prefix = path_prefix.trim_end_matches('/');
+    if !path_prefix_matches(path_prefix, path) {
+        return None;
+    }
+    let stripped = &path[path_prefix.len()..];
+    Some(if stripped.is_empty() {
+        "/".to_string()
     } else {
-        // Config not valid
-        None
+        stripped.to_string()
+    })
+}
+
+pub fn trailing_slash_redirect(backend: &Backend, uri: &Uri) -> Option<String> {
+    // With redirect configured, the path_prefix itself requested in the other trailing slash
+    // form is redirected to the configured form, keeping the query
+    if backend.trailing_slash != Some(TrailingSlash::Redirect) {
+        return None;
+    }
+    let path_prefix = backend.path_prefix.as_deref()?;
+    let path = uri.path();
+    if path == path_prefix || path.trim_end_matches('/') != path_prefix.trim_end_matches('/') {
+        return None;
     }
+    Some(match uri.query() {
+        Some(query) => format!("{}?{}", path_prefix, query),
+        None => path_prefix.to_string(),
+    })
 }
 
-fn match_backend<'a>(backends: &'a [Backend], host_header: &str) -> Option<&'a Backend> {
-    backends
+pub fn retry_location(backend: &Backend, tried: &[String]) -> Option<String> {
+    // Retries of a load balanced backend go to the next location in the pool which hasn't been
+    // tried, None once all have been, so that no location is tried twice for a request. Other
+    // backends have the one location to retry
+    if backend.backend_type.as_deref() != Some("loadbalanced") {
+        return backend.location.clone().or_else(|| tried.last().cloned());
+    }
+    // Standby locations follow the active ones
+    let locations: Vec<&String> = backend
+        .locations
+        .iter()
+        .chain(backend.standby_locations.iter())
+        .flatten()
+        .collect();
+    let start = tried
+        .last()
+        .and_then(|last| locations.iter().position(|location| *location == last))
+        .map_or(0, |position| position + 1);
+    locations
         .iter()
-        .find(|&backend| backend.name.as_deref() == Some(host_header))
+        .cycle()
+        .skip(start)
+        .take(locations.len())
+        .find(|location| !tried.contains(location))
+        .map(|location| location.to_string())
 }
 
 fn round_robin_select(
-    backend_locations: &Vec<String>,
-    backend_state: &mut BackendState,
+    backend_locations: &[String],
+    backend_state: &BackendState,
+    peek: bool,
 ) -> Option<String> {
     let backend_count = backend_locations.len() as isize;
-    let rr_count = &mut backend_state.rr_count;
+    if backend_count == 0 {
+        return None;
+    }
 
     // If this is the first request or if we've exceeded the number of backends
-    // set the counter to zero and return the first backend
-    if *rr_count == -1 || *rr_count == (backend_count - 1) {
-        *rr_count = 0;
-        Some(backend_locations[0].clone())
+    // set the counter to zero and return the first backend, otherwise the next backend
+    // (The number of backends can shrink when zone affinity excludes locations)
+    let next = |rr_count: isize| {
+        if rr_count == -1 || rr_count >= (backend_count - 1) {
+            0
+        } else {
+            rr_count + 1
+        }
+    };
+    if peek {
+        let previous = backend_state.rr_count.load(Ordering::SeqCst);
+        return Some(backend_locations[next(previous) as usize].clone());
+    }
+    // Updated with compare and swap, so concurrent requests each see a distinct count
+    let previous = backend_state
+        .rr_count
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |rr_count| {
+            Some(next(rr_count))
+        })
+        .expect("Round robin update failed");
+    Some(backend_locations[next(previous) as usize].clone())
+}
+
+fn weighted_select(
+    backend_locations: &[String],
+    weights: &HashMap<&str, u32>,
+    backend_state: &BackendState,
+    peek: bool,
+) -> Option<String> {
+    // Smooth weighted round robin (as used by nginx): each location's current weight grows by
+    // its weight, the location with the highest is selected and reduced by the total. This
+    // interleaves the locations rather than sending runs of requests to the heaviest one.
+    // Locations without a weight have a weight of 1
+    let weight = |location: &String| i64::from(*weights.get(location.as_str()).unwrap_or(&1));
+    let total: i64 = backend_locations.iter().map(weight).sum();
+    if total == 0 {
+        return round_robin_select(backend_locations, backend_state, peek);
+    }
+
+    let mut locked_weights = backend_state.current_weights.lock().unwrap();
+    // A peek selects using a copy of the current weights, leaving them unchanged
+    let mut peeked_weights = peek.then(|| locked_weights.clone());
+    let current_weights = peeked_weights.as_mut().unwrap_or(&mut locked_weights);
+    let mut selected: Option<&String> = None;
+    for location in backend_locations {
+        let current = current_weights.entry(location.clone()).or_insert(0);
+        *current += weight(location);
+        let current = *current;
+        if selected.is_none_or(|selected| current > current_weights[selected]) {
+            selected = Some(location);
+        }
+    }
+    let selected = selected?;
+    *current_weights.get_mut(selected)? -= total;
+    Some(selected.clone())
+}
+
+// Fraction of requests sent to a random location, regardless of latency,
+// so that locations not meeting the SLO are still measured
+const SLO_EXPLORATION: f64 = 0.1;
+// Weighting of the latest latency sample in the moving average
+const LATENCY_SMOOTHING: f64 = 0.3;
+
+fn slo_select(
+    backend_locations: &[String],
+    backend_state: &BackendState,
+    slo_target_ms: u64,
+    rng: &mut impl Rng,
+) -> Option<String> {
+    // Select a location meeting the SLO target latency, locations without
+    // a measured latency yet are considered to be meeting it
+    if rng.gen_bool(SLO_EXPLORATION) {
+        return backend_locations.choose(rng).cloned();
+    }
+
+    let latencies = backend_state.latencies.lock().unwrap();
+    let compliant: Vec<&String> = backend_locations
+        .iter()
+        .filter(|location| {
+            latencies
+                .get(*location)
+                .is_none_or(|latency| *latency <= slo_target_ms as f64)
+        })
+        .collect();
+
+    match compliant.choose(rng) {
+        Some(location) => Some(location.to_string()),
+        // No location meets the SLO, use the fastest
+        None => backend_locations
+            .iter()
+            .min_by(|a, b| latencies[*a].total_cmp(&latencies[*b]))
+            .cloned(),
+    }
+}
+
+// How long a location which failed to respond is avoided by zone aware selection
+const UNHEALTHY_DURATION: Duration = Duration::from_secs(10);
+
+fn healthy_locations(
+    backend_locations: &[String],
+    backend_state: &BackendState,
+    health_checks: Option<&BTreeMap<String, bool>>,
+) -> Vec<String> {
+    // Locations failing health checks or ejected are skipped. If every location
+    // is unhealthy they are all candidates
+    let healthy = filter_healthy(backend_locations, backend_state, health_checks);
+    if healthy.is_empty() {
+        backend_locations.to_vec()
+    } else {
+        healthy
+    }
+}
+
+fn filter_healthy(
+    backend_locations: &[String],
+    backend_state: &BackendState,
+    health_checks: Option<&BTreeMap<String, bool>>,
+) -> Vec<String> {
+    let now = Instant::now();
+    let unhealthy_until = backend_state.unhealthy_until.lock().unwrap();
+    backend_locations
+        .iter()
+        .filter(|location| {
+            unhealthy_until
+                .get(*location)
+                .is_none_or(|until| *until <= now)
+        })
+        .filter(|location| {
+            health_checks
+                .and_then(|health_checks| health_checks.get(*location))
+                .is_none_or(|healthy| *healthy)
+        })
+        .cloned()
+        .collect()
+}
+
+fn zone_select(
+    backend_locations: &[String],
+    location_zones: &HashMap<String, String>,
+    local_zone: &str,
+) -> Vec<String> {
+    // Prefer locations in the local zone, falling back to the other zones when there are none
+    let local: Vec<String> = backend_locations
+        .iter()
+        .filter(|location| location_zones.get(*location).map(String::as_str) == Some(local_zone))
+        .cloned()
+        .collect();
+
+    if local.is_empty() {
+        backend_locations.to_vec()
+    } else {
+        local
+    }
+}
+
+pub fn record_health(
+    proxy_state: &Arc<RwLock<ProxyState>>,
+    backend_name: &str,
+    location: &str,
+    healthy: bool,
+) {
+    let backends_state = &proxy_state.read().unwrap().backends;
+    if let Some(Some(backend_state)) = backends_state.get(backend_name) {
+        let mut unhealthy_until = backend_state.unhealthy_until.lock().unwrap();
+        if healthy {
+            unhealthy_until.remove(location);
+        } else {
+            unhealthy_until.insert(location.to_string(), Instant::now() + UNHEALTHY_DURATION);
+        }
+    }
+}
+
+pub fn record_connect_result(
+    proxy_state: &Arc<RwLock<ProxyState>>,
+    backend_name: &str,
+    location: &str,
+    connected: bool,
+    passive_health: &PassiveHealthConfig,
+) {
+    // A location is ejected after consecutive connection failures. Once the cooldown has
+    // passed it is tried again, and is ejected again straight away if it still fails
+    let backends_state = &proxy_state.read().unwrap().backends;
+    if let Some(Some(backend_state)) = backends_state.get(backend_name) {
+        let mut consecutive_failures = backend_state.consecutive_failures.lock().unwrap();
+        if connected {
+            consecutive_failures.remove(location);
+            return;
+        }
+        let failures = consecutive_failures
+            .entry(location.to_string())
+            .or_insert(0);
+        *failures += 1;
+        if *failures >= passive_health.consecutive_failures() {
+            warn!(
+                "Ejecting backend: {} location: {} after {} consecutive failures",
+                backend_name, location, failures
+            );
+            backend_state.unhealthy_until.lock().unwrap().insert(
+                location.to_string(),
+                Instant::now() + passive_health.cooldown(),
+            );
+        }
     }
-    // return the next backend
-    else {
-        *rr_count += 1;
-        Some(backend_locations[*rr_count as usize].clone())
+}
+
+pub fn record_latency(
+    proxy_state: &Arc<RwLock<ProxyState>>,
+    backend_name: &str,
+    location: &str,
+    latency: Duration,
+) {
+    let backends_state = &proxy_state.read().unwrap().backends;
+    if let Some(Some(backend_state)) = backends_state.get(backend_name) {
+        let latency_ms = latency.as_secs_f64() * 1000.0;
+        backend_state
+            .latencies
+            .lock()
+            .unwrap()
+            .entry(location.to_string())
+            .and_modify(|average| {
+                *average = LATENCY_SMOOTHING * latency_ms + (1.0 - LATENCY_SMOOTHING) * *average
+            })
+            .or_insert(latency_ms);
     }
 }
 
@@ -65,54 +509,598 @@ fn round_robin_select(
 mod tests {
 
     use super::*;
-    use crate::{read_proxy_config_yaml, router, ProxyState};
+    use crate::{read_proxy_config, router, ProxyState};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
 
     #[tokio::test]
     async fn test_router_single_backend() {
-        let config = read_proxy_config_yaml("tests/config.yaml".to_string())
+        let config = read_proxy_config("tests/config.yaml".to_string())
             .await
             .unwrap();
 
-        let proxy_state = Arc::new(Mutex::new(ProxyState::new(&config)));
+        let proxy_state = Arc::new(RwLock::new(ProxyState::new(&config)));
 
-        let backend = router(&config.backends, proxy_state, "test.home");
-        assert_eq!(backend.unwrap(), "127.0.0.1:8000")
+        let route = router(&config.backends, proxy_state, "test.home", "/", None, None).unwrap();
+        assert_eq!(route.backend.name.as_deref(), Some("test.home"));
+        assert_eq!(route.location, "127.0.0.1:8000")
+    }
+
+    #[tokio::test]
+    async fn test_routing_lock_wait_metric() {
+        let config = read_proxy_config("tests/config.yaml".to_string())
+            .await
+            .unwrap();
+        let proxy_state = Arc::new(RwLock::new(ProxyState::new(&config)));
+        let sample_count = METRICS.routing_lock_wait.get_sample_count();
+
+        router(&config.backends, proxy_state, "test.home", "/", None, None).unwrap();
+        assert!(METRICS.routing_lock_wait.get_sample_count() > sample_count);
+        assert!(crate::metrics::encode_metrics()
+            .unwrap()
+            .contains("routing_lock_wait_seconds_bucket"));
     }
 
     #[tokio::test]
     async fn test_router_loadbalanced_backend() {
-        let config = read_proxy_config_yaml("tests/config.yaml".to_string())
+        let config = read_proxy_config("tests/config.yaml".to_string())
             .await
             .unwrap();
-        let proxy_state = Arc::new(Mutex::new(ProxyState::new(&config)));
+        let proxy_state = Arc::new(RwLock::new(ProxyState::new(&config)));
 
-        let backend = router(&config.backends, proxy_state, "test-lb.home");
-        assert_eq!(backend.unwrap(), "127.0.0.1:8000")
+        let route = router(
+            &config.backends,
+            proxy_state,
+            "test-lb.home",
+            "/",
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(route.location, "127.0.0.1:8000")
     }
 
     #[tokio::test]
     async fn test_round_robin_select() {
-        let config = read_proxy_config_yaml("tests/config.yaml".to_string())
+        let config = read_proxy_config("tests/config.yaml".to_string())
             .await
             .unwrap();
-        let mut proxy_mut_state = ProxyState::new(&config).backends;
+        let backends_state = ProxyState::new(&config).backends;
         let backend_name = String::from("test-lb2.home");
-        let backend_state = proxy_mut_state
-            .get_mut(&backend_name.clone())
-            .unwrap()
-            .as_mut()
-            .unwrap();
+        let backend_state = backends_state.get(&backend_name).unwrap().as_ref().unwrap();
         let backend_locations = config.backends[1].locations.as_ref().unwrap();
 
-        let first_backend = round_robin_select(backend_locations, backend_state).unwrap();
+        let first_backend = round_robin_select(backend_locations, backend_state, false).unwrap();
         assert_eq!(first_backend, String::from("127.0.0.1:8000"));
-        let second_backend = round_robin_select(backend_locations, backend_state).unwrap();
+        let second_backend = round_robin_select(backend_locations, backend_state, false).unwrap();
         assert_eq!(second_backend, String::from("127.0.0.1:8001"));
-        let third_backend = round_robin_select(backend_locations, backend_state).unwrap();
+        let third_backend = round_robin_select(backend_locations, backend_state, false).unwrap();
         assert_eq!(third_backend, String::from("127.0.0.1:8000"));
-        let fourth_backend = round_robin_select(backend_locations, backend_state).unwrap();
+        let fourth_backend = round_robin_select(backend_locations, backend_state, false).unwrap();
         assert_eq!(fourth_backend, String::from("127.0.0.1:8001"));
-        let fifth_backend = round_robin_select(backend_locations, backend_state).unwrap();
+        let fifth_backend = round_robin_select(backend_locations, backend_state, false).unwrap();
         assert_eq!(fifth_backend, String::from("127.0.0.1:8000"));
     }
+
+    #[tokio::test]
+    async fn test_weighted_select() {
+        let locations = vec![
+            String::from("127.0.0.1:8000"),
+            String::from("127.0.0.1:8001"),
+        ];
+        let weights = HashMap::from([("127.0.0.1:8000", 3), ("127.0.0.1:8001", 1)]);
+        let backend_state = BackendState::new();
+
+        let selected: Vec<String> = (0..8)
+            .map(|_| weighted_select(&locations, &weights, &backend_state, false).unwrap())
+            .collect();
+        let heavy = selected
+            .iter()
+            .filter(|location| **location == locations[0]);
+        assert_eq!(heavy.count(), 6);
+        // The lighter location isn't starved until the end of each cycle
+        assert_eq!(
+            selected[..4].iter().filter(|l| **l == locations[1]).count(),
+            1
+        );
+
+        // Without a weight a location has a weight of 1
+        let weights = HashMap::from([("127.0.0.1:8000", 1)]);
+        let backend_state = BackendState::new();
+        let selected: Vec<String> = (0..4)
+            .map(|_| weighted_select(&locations, &weights, &backend_state, false).unwrap())
+            .collect();
+        assert_eq!(selected.iter().filter(|l| **l == locations[0]).count(), 2);
+
+        // A peek returns the next selection without making it
+        let weights = HashMap::from([("127.0.0.1:8000", 1), ("127.0.0.1:8001", 2)]);
+        let backend_state = BackendState::new();
+        for _ in 0..2 {
+            let peeked = weighted_select(&locations, &weights, &backend_state, true).unwrap();
+            assert_eq!(peeked, locations[1]);
+        }
+        let selected = weighted_select(&locations, &weights, &backend_state, false).unwrap();
+        assert_eq!(selected, locations[1]);
+        let peeked = weighted_select(&locations, &weights, &backend_state, true).unwrap();
+        assert_eq!(peeked, locations[0]);
+    }
+
+    #[tokio::test]
+    async fn test_router_weighted_backend() {
+        let config: crate::Config = serde_yaml::from_str(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {}
+            backends:
+              - name: "weighted.home"
+                backend_type: "loadbalanced"
+                locations: ["127.0.0.1:8000", "127.0.0.1:8001"]
+                weights: [3, 1]
+            "#,
+        )
+        .unwrap();
+        let proxy_state = Arc::new(RwLock::new(ProxyState::new(&config)));
+        let routes: Vec<Route> = (0..8)
+            .map(|_| {
+                router(
+                    &config.backends,
+                    proxy_state.clone(),
+                    "weighted.home",
+                    "/",
+                    None,
+                    None,
+                )
+                .unwrap()
+            })
+            .collect();
+        let heavy = routes
+            .iter()
+            .filter(|route| route.location == "127.0.0.1:8000");
+        assert_eq!(heavy.count(), 6);
+        assert_eq!(routes[0].reason, "weighted round robin");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn test_round_robin_concurrent() {
+        let config = read_proxy_config("tests/config.yaml".to_string())
+            .await
+            .unwrap();
+        let config = Arc::new(config);
+        let proxy_state = Arc::new(RwLock::new(ProxyState::new(&config)));
+
+        // Parallel requests each take a distinct turn, so the locations are evenly used
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let config = config.clone();
+                let proxy_state = proxy_state.clone();
+                tokio::spawn(async move {
+                    (0..500)
+                        .map(|_| {
+                            router(
+                                &config.backends,
+                                proxy_state.clone(),
+                                "test-lb2.home",
+                                "/",
+                                None,
+                                None,
+                            )
+                            .unwrap()
+                            .location
+                        })
+                        .collect::<Vec<String>>()
+                })
+            })
+            .collect();
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for task in tasks {
+            for location in task.await.unwrap() {
+                *counts.entry(location).or_insert(0) += 1;
+            }
+        }
+        assert_eq!(counts["127.0.0.1:8000"], 2000);
+        assert_eq!(counts["127.0.0.1:8001"], 2000);
+    }
+
+    #[tokio::test]
+    async fn test_slo_select() {
+        let locations = vec![
+            String::from("127.0.0.1:8000"),
+            String::from("127.0.0.1:8001"),
+        ];
+        let proxy_state = Arc::new(RwLock::new(ProxyState {
+            backends: HashMap::from([(String::from("slo.home"), Some(BackendState::new()))]),
+            initial_health_checks: Default::default(),
+            health: Default::default(),
+            concurrency_limits: Default::default(),
+        }));
+        record_latency(
+            &proxy_state,
+            "slo.home",
+            "127.0.0.1:8000",
+            Duration::from_millis(20),
+        );
+        record_latency(
+            &proxy_state,
+            "slo.home",
+            "127.0.0.1:8001",
+            Duration::from_millis(200),
+        );
+
+        let mut rng = StdRng::seed_from_u64(1);
+        let state = proxy_state.read().unwrap();
+        let backend_state = state.backends.get("slo.home").unwrap().as_ref().unwrap();
+        let mut fast_count = 0;
+        for _ in 0..1000 {
+            if slo_select(&locations, backend_state, 50, &mut rng).unwrap() == "127.0.0.1:8000" {
+                fast_count += 1;
+            }
+        }
+        // The location meeting the SLO receives the majority of traffic, but the
+        // other location still receives some requests
+        assert!(
+            fast_count > 900,
+            "fast location selected {} times",
+            fast_count
+        );
+        assert!(fast_count < 1000);
+
+        // Neither location meets the SLO, the fastest is preferred
+        let mut fast_count = 0;
+        for _ in 0..1000 {
+            if slo_select(&locations, backend_state, 10, &mut rng).unwrap() == "127.0.0.1:8000" {
+                fast_count += 1;
+            }
+        }
+        assert!(
+            fast_count > 900,
+            "fast location selected {} times",
+            fast_count
+        );
+    }
+
+    #[tokio::test]
+    async fn test_zone_affinity() {
+        let config: crate::Config = serde_yaml::from_str(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {}
+            local_zone: "zone-a"
+            backends:
+              - name: "zoned.home"
+                backend_type: "loadbalanced"
+                locations:
+                  - "127.0.0.1:8000"
+                  - "127.0.0.1:8001"
+                  - "127.0.0.1:8002"
+                location_zones:
+                  "127.0.0.1:8000": "zone-b"
+                  "127.0.0.1:8001": "zone-a"
+                  "127.0.0.1:8002": "zone-a"
+            "#,
+        )
+        .unwrap();
+        let proxy_state = Arc::new(RwLock::new(ProxyState::new(&config)));
+        let route = |proxy_state: &Arc<RwLock<ProxyState>>| {
+            router(
+                &config.backends,
+                proxy_state.clone(),
+                "zoned.home",
+                "/",
+                config.local_zone.as_deref(),
+                None,
+            )
+            .unwrap()
+            .location
+        };
+
+        // Same zone locations are preferred
+        for _ in 0..4 {
+            assert_ne!(route(&proxy_state), "127.0.0.1:8000");
+        }
+
+        // One same zone location unhealthy, the other is used
+        record_health(&proxy_state, "zoned.home", "127.0.0.1:8001", false);
+        for _ in 0..4 {
+            assert_eq!(route(&proxy_state), "127.0.0.1:8002");
+        }
+
+        // Both same zone locations unhealthy, fall back to the other zone
+        record_health(&proxy_state, "zoned.home", "127.0.0.1:8002", false);
+        assert_eq!(route(&proxy_state), "127.0.0.1:8000");
+
+        // A same zone location recovers
+        record_health(&proxy_state, "zoned.home", "127.0.0.1:8001", true);
+        assert_eq!(route(&proxy_state), "127.0.0.1:8001");
+    }
+
+    #[tokio::test]
+    async fn test_standby_locations() {
+        let config: crate::Config = serde_yaml::from_str(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {}
+            backends:
+              - name: "dr.home"
+                backend_type: "loadbalanced"
+                locations:
+                  - "127.0.0.1:8000"
+                  - "127.0.0.1:8001"
+                standby_locations:
+                  - "127.0.0.1:9000"
+                  - "127.0.0.1:9001"
+            "#,
+        )
+        .unwrap();
+        let proxy_state = Arc::new(RwLock::new(ProxyState::new(&config)));
+        let route = |proxy_state: &Arc<RwLock<ProxyState>>| {
+            router(
+                &config.backends,
+                proxy_state.clone(),
+                "dr.home",
+                "/",
+                None,
+                None,
+            )
+            .unwrap()
+            .location
+        };
+
+        // Round robin within the active group
+        let mut active: Vec<String> = (0..4).map(|_| route(&proxy_state)).collect();
+        active.sort();
+        assert_eq!(
+            active,
+            [
+                "127.0.0.1:8000",
+                "127.0.0.1:8000",
+                "127.0.0.1:8001",
+                "127.0.0.1:8001"
+            ]
+        );
+
+        // Traffic stays on the remaining active location
+        record_health(&proxy_state, "dr.home", "127.0.0.1:8000", false);
+        for _ in 0..4 {
+            assert_eq!(route(&proxy_state), "127.0.0.1:8001");
+        }
+
+        // The whole active group is unhealthy, round robin within the standby group
+        record_health(&proxy_state, "dr.home", "127.0.0.1:8001", false);
+        let mut standby: Vec<String> = (0..4).map(|_| route(&proxy_state)).collect();
+        standby.sort();
+        assert_eq!(
+            standby,
+            [
+                "127.0.0.1:9000",
+                "127.0.0.1:9000",
+                "127.0.0.1:9001",
+                "127.0.0.1:9001"
+            ]
+        );
+
+        // Every location is unhealthy, round robin across both groups
+        record_health(&proxy_state, "dr.home", "127.0.0.1:9000", false);
+        record_health(&proxy_state, "dr.home", "127.0.0.1:9001", false);
+        let mut all: Vec<String> = (0..4).map(|_| route(&proxy_state)).collect();
+        all.sort();
+        assert_eq!(
+            all,
+            [
+                "127.0.0.1:8000",
+                "127.0.0.1:8001",
+                "127.0.0.1:9000",
+                "127.0.0.1:9001"
+            ]
+        );
+
+        // An active location recovers
+        record_health(&proxy_state, "dr.home", "127.0.0.1:8000", true);
+        assert_eq!(route(&proxy_state), "127.0.0.1:8000");
+
+        // Retries move on to the standby group after the last active location
+        let backend = &config.backends[0];
+        assert_eq!(
+            retry_location(backend, &["127.0.0.1:8001".to_string()]).unwrap(),
+            "127.0.0.1:9000"
+        );
+        // Tried locations are skipped, until there are none left
+        let tried: Vec<String> = vec!["127.0.0.1:9000", "127.0.0.1:8000", "127.0.0.1:8001"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(retry_location(backend, &tried).unwrap(), "127.0.0.1:9001");
+        let tried: Vec<String> = tried
+            .into_iter()
+            .chain(Some("127.0.0.1:9001".to_string()))
+            .collect();
+        assert_eq!(retry_location(backend, &tried), None);
+    }
+
+    #[tokio::test]
+    async fn test_router_path_prefix() {
+        let config: crate::Config = serde_yaml::from_str(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {}
+            backends:
+              - name: "api.home"
+                location: "127.0.0.1:8000"
+              - name: "api.home"
+                location: "127.0.0.1:8001"
+                path_prefix: "/api"
+              - name: "api.home"
+                location: "127.0.0.1:8002"
+                path_prefix: "/api/v2"
+              - name: "api.home"
+                location: "127.0.0.1:8003"
+                path_prefix: "/static/"
+            "#,
+        )
+        .unwrap();
+        let proxy_state = Arc::new(RwLock::new(ProxyState::new(&config)));
+        let location = |path: &str| {
+            router(
+                &config.backends,
+                proxy_state.clone(),
+                "api.home",
+                path,
+                None,
+                None,
+            )
+            .unwrap()
+            .location
+        };
+        // The longest matching prefix wins, regardless of the order of the backends
+        assert_eq!(location("/api/v2/users"), "127.0.0.1:8002");
+        assert_eq!(location("/api/v2"), "127.0.0.1:8002");
+        assert_eq!(location("/api/v1/users"), "127.0.0.1:8001");
+        assert_eq!(location("/api"), "127.0.0.1:8001");
+        // Prefixes match whole segments
+        assert_eq!(location("/api/v20"), "127.0.0.1:8001");
+        assert_eq!(location("/apis"), "127.0.0.1:8000");
+        assert_eq!(location("/static/app.js"), "127.0.0.1:8003");
+        assert_eq!(location("/"), "127.0.0.1:8000");
+    }
+
+    #[tokio::test]
+    async fn test_trailing_slash() {
+        let config: crate::Config = serde_yaml::from_str(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {}
+            backends:
+              - name: "strict.home"
+                location: "127.0.0.1:8000"
+              - name: "strict.home"
+                location: "127.0.0.1:8001"
+                path_prefix: "/api/"
+              - name: "ignore.home"
+                location: "127.0.0.1:8000"
+              - name: "ignore.home"
+                location: "127.0.0.1:8001"
+                path_prefix: "/api/"
+                trailing_slash: "ignore"
+              - name: "redirect.home"
+                location: "127.0.0.1:8000"
+              - name: "redirect.home"
+                location: "127.0.0.1:8001"
+                path_prefix: "/api"
+                trailing_slash: "redirect"
+            "#,
+        )
+        .unwrap();
+        let proxy_state = Arc::new(RwLock::new(ProxyState::new(&config)));
+        let location = |host: &str, path: &str| {
+            router(
+                &config.backends,
+                proxy_state.clone(),
+                host,
+                path,
+                None,
+                None,
+            )
+            .unwrap()
+            .location
+        };
+        let redirect = |host: &str, path: &str| {
+            let route = router(
+                &config.backends,
+                proxy_state.clone(),
+                host,
+                path,
+                None,
+                None,
+            )
+            .unwrap();
+            trailing_slash_redirect(route.backend, &path.parse().unwrap())
+        };
+
+        assert_eq!(location("strict.home", "/api/"), "127.0.0.1:8001");
+        assert_eq!(location("strict.home", "/api"), "127.0.0.1:8000");
+        assert_eq!(redirect("strict.home", "/api/"), None);
+
+        assert_eq!(location("ignore.home", "/api/"), "127.0.0.1:8001");
+        assert_eq!(location("ignore.home", "/api"), "127.0.0.1:8001");
+        assert_eq!(location("ignore.home", "/apis"), "127.0.0.1:8000");
+        assert_eq!(redirect("ignore.home", "/api"), None);
+
+        assert_eq!(location("redirect.home", "/api/"), "127.0.0.1:8001");
+        assert_eq!(redirect("redirect.home", "/api"), None);
+        assert_eq!(redirect("redirect.home", "/api/users"), None);
+        assert_eq!(redirect("redirect.home", "/api/"), Some("/api".to_string()));
+        assert_eq!(
+            redirect("redirect.home", "/api/?page=2"),
+            Some("/api?page=2".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_strip_path_prefix() {
+        assert_eq!(
+            strip_path_prefix("/internal", "/internal/users"),
+            Some("/users".to_string())
+        );
+        assert_eq!(
+            strip_path_prefix("/internal/", "/internal/users"),
+            Some("/users".to_string())
+        );
+        // Stripping the whole path leaves /
+        assert_eq!(
+            strip_path_prefix("/internal", "/internal"),
+            Some("/".to_string())
+        );
+        assert_eq!(
+            strip_path_prefix("/internal", "/internal/"),
+            Some("/".to_string())
+        );
+        assert_eq!(strip_path_prefix("/internal", "/internals"), None);
+        assert_eq!(strip_path_prefix("/internal", "/other"), None);
+    }
+
+    #[tokio::test]
+    async fn test_wildcard_host() {
+        assert!(host_matches("*.apps.home", "a.apps.home"));
+        assert!(!host_matches("*.apps.home", "apps.home"));
+        assert!(!host_matches("*.apps.home", "a.b.apps.home"));
+        assert!(!host_matches("*.apps.home", "aapps.home"));
+        assert!(!host_matches("*.apps.home", ".apps.home"));
+
+        let config: crate::Config = serde_yaml::from_str(
+            r#"
+            listen: "127.0.0.1:4000"
+            tls: {}
+            backends:
+              - name: "*.apps.home"
+                location: "127.0.0.1:8000"
+              - name: "*.apps.home"
+                location: "127.0.0.1:8001"
+                path_prefix: "/api"
+              - name: "a.apps.home"
+                location: "127.0.0.1:8002"
+            "#,
+        )
+        .unwrap();
+        let location = |host: &str, path: &str| {
+            match_backend(&config.backends, host, path, None)
+                .and_then(|backend| backend.location.clone())
+        };
+        assert_eq!(
+            location("b.apps.home", "/"),
+            Some("127.0.0.1:8000".to_string())
+        );
+        assert_eq!(
+            location("b.apps.home", "/api"),
+            Some("127.0.0.1:8001".to_string())
+        );
+        // An exact name beats a wildcard, even one listed first with a longer path prefix
+        assert_eq!(
+            location("a.apps.home", "/"),
+            Some("127.0.0.1:8002".to_string())
+        );
+        assert_eq!(
+            location("a.apps.home", "/api"),
+            Some("127.0.0.1:8002".to_string())
+        );
+        assert_eq!(location("apps.home", "/"), None);
+    }
 }
@@ -0,0 +1,221 @@
+// OpenTelemetry tracing of requests, a span per request exported by OTLP with the W3C trace
+// context propagated to backends. Spans are only recorded when built with the otel feature
+use anyhow::Error;
+use hyper::{Body, Request, Response};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "otel")]
+use super::access_log::ResponseContext;
+#[cfg(feature = "otel")]
+use hyper::http::{HeaderMap, HeaderName, HeaderValue};
+#[cfg(feature = "otel")]
+use opentelemetry::propagation::{Extractor, Injector, TextMapPropagator};
+#[cfg(feature = "otel")]
+use opentelemetry::trace::{SpanKind, Status, TraceContextExt, Tracer};
+#[cfg(feature = "otel")]
+use opentelemetry::{global, Context, KeyValue};
+#[cfg(feature = "otel")]
+use opentelemetry_otlp::WithExportConfig;
+#[cfg(feature = "otel")]
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+#[cfg(feature = "otel")]
+use opentelemetry_sdk::{runtime, trace as sdktrace, Resource};
+
+#[cfg(not(feature = "otel"))]
+use log::warn;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct TracingConfig {
+    pub otlp_endpoint: String, // The collector's OTLP/HTTP URL, e.g. http://127.0.0.1:4318
+    pub service_name: Option<String>, // Defaults to brachyura
+}
+
+#[cfg(feature = "otel")]
+pub fn init_tracing(tracing_config: &TracingConfig) -> Result<(), Error> {
+    // Spans are exported in batches from the tokio runtime, to the endpoint at startup
+    let service_name = tracing_config
+        .service_name
+        .clone()
+        .unwrap_or_else(|| "brachyura".to_string());
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(&tracing_config.otlp_endpoint),
+        )
+        .with_trace_config(
+            sdktrace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                service_name,
+            )])),
+        )
+        .install_batch(runtime::Tokio)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn init_tracing(_tracing_config: &TracingConfig) -> Result<(), Error> {
+    warn!("Tracing is configured, but brachyura was built without the otel feature");
+    Ok(())
+}
+
+#[cfg(feature = "otel")]
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+#[cfg(feature = "otel")]
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+#[cfg(feature = "otel")]
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+#[cfg(feature = "otel")]
+impl<'a> Injector for HeaderInjector<'a> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (key.parse::<HeaderName>(), HeaderValue::from_str(&value)) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+pub struct RequestSpan {
+    #[cfg(feature = "otel")]
+    context: Context, // Holds the span
+}
+
+#[cfg(feature = "otel")]
+impl RequestSpan {
+    pub fn start(req: &mut Request<Body>) -> RequestSpan {
+        // The span continues the client's trace when it sent a traceparent, and the backend
+        // is sent a traceparent with the span as its parent, with any tracestate passed on
+        let propagator = TraceContextPropagator::new();
+        let parent = propagator.extract(&HeaderExtractor(req.headers()));
+        let tracer = global::tracer("brachyura");
+        let span = tracer
+            .span_builder(req.method().to_string())
+            .with_kind(SpanKind::Server)
+            .with_attributes(vec![
+                KeyValue::new("http.method", req.method().to_string()),
+                KeyValue::new("http.target", req.uri().path().to_string()),
+            ])
+            .start_with_context(&tracer, &parent);
+        let context = parent.with_span(span);
+        propagator.inject_context(&context, &mut HeaderInjector(req.headers_mut()));
+        RequestSpan { context }
+    }
+
+    pub fn end(self, response: &Response<Body>) {
+        let span = self.context.span();
+        span.set_attribute(KeyValue::new(
+            "http.status_code",
+            i64::from(response.status().as_u16()),
+        ));
+        let response_context = response.extensions().get::<ResponseContext>();
+        if let Some(location) = response_context.and_then(|context| context.location.clone()) {
+            span.set_attribute(KeyValue::new("backend.location", location));
+        }
+        if let Some(name) = response_context.and_then(|context| context.host.clone()) {
+            span.set_attribute(KeyValue::new("backend.name", name));
+        }
+        if response.status().is_server_error() {
+            span.set_status(Status::error(response.status().to_string()));
+        }
+        span.end();
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+impl RequestSpan {
+    pub fn start(_req: &mut Request<Body>) -> RequestSpan {
+        RequestSpan {}
+    }
+
+    pub fn end(self, _response: &Response<Body>) {}
+}
+
+#[cfg(all(test, feature = "otel"))]
+mod tests {
+    use super::*;
+    use futures::future::BoxFuture;
+    use opentelemetry::Value;
+    use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, Clone, Default)]
+    struct RecordingExporter(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for RecordingExporter {
+        fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+            self.0.lock().unwrap().extend(batch);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_span() {
+        let exporter = RecordingExporter::default();
+        let provider = sdktrace::TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        global::set_tracer_provider(provider.clone());
+
+        // A client's trace is continued, the backend's parent being the proxy's span
+        let mut req = Request::builder()
+            .uri("/orders")
+            .header(
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            )
+            .header("tracestate", "vendor=abc")
+            .body(Body::empty())
+            .unwrap();
+        let span = RequestSpan::start(&mut req);
+        let traceparent = req.headers()["traceparent"].to_str().unwrap().to_string();
+        assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(!traceparent.contains("00f067aa0ba902b7"));
+        assert_eq!(req.headers()["tracestate"], "vendor=abc");
+
+        let mut response = Response::builder().status(502).body(Body::empty()).unwrap();
+        response.extensions_mut().insert(ResponseContext {
+            location: Some("127.0.0.1:8000".to_string()),
+            host: Some("orders.home".to_string()),
+            ..Default::default()
+        });
+        span.end(&response);
+        provider.force_flush();
+        let spans = exporter.0.lock().unwrap().clone();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(
+            spans[0].span_context.span_id().to_string(),
+            traceparent[36..52]
+        );
+        assert_eq!(spans[0].parent_span_id.to_string(), "00f067aa0ba902b7");
+        assert_eq!(spans[0].span_kind, SpanKind::Server);
+        let attribute = |key: &str| {
+            spans[0]
+                .attributes
+                .iter()
+                .find(|attribute| attribute.key.as_str() == key)
+                .map(|attribute| attribute.value.clone())
+        };
+        assert_eq!(attribute("http.status_code"), Some(Value::I64(502)));
+        assert_eq!(
+            attribute("backend.location"),
+            Some(Value::from("127.0.0.1:8000"))
+        );
+
+        // Without a traceparent the request starts a new trace
+        let mut req = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let span = RequestSpan::start(&mut req);
+        assert!(req.headers().contains_key("traceparent"));
+        span.end(&Response::new(Body::empty()));
+    }
+}
@@ -0,0 +1,293 @@
+// Connecting to backends through an upstream HTTP proxy. Plain HTTP requests are sent to the
+// proxy in absolute form, HTTPS connections are tunneled with CONNECT
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use anyhow::Error;
+use futures::future::BoxFuture;
+use hyper::client::connect::{Connected, Connection};
+use hyper::http::{uri::Scheme, HeaderValue};
+use hyper::service::Service;
+use hyper::Uri;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+// The most response header bytes read from the proxy for a CONNECT request
+const MAX_CONNECT_RESPONSE: usize = 8192;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct UpstreamProxyConfig {
+    pub url: String, // e.g. http://proxy.internal:3128
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct UpstreamProxy {
+    uri: Uri,
+    authorization: Option<HeaderValue>, // Basic Proxy-Authorization
+}
+
+impl UpstreamProxy {
+    pub fn new(config: &UpstreamProxyConfig) -> Result<UpstreamProxy, Error> {
+        let uri: Uri = config.url.parse()?;
+        if uri.scheme() != Some(&Scheme::HTTP) || uri.authority().is_none() {
+            return Err(Error::msg(format!(
+                "Invalid upstream_proxy url {}, only http proxies are supported",
+                config.url
+            )));
+        }
+        let authorization = match &config.username {
+            Some(username) => {
+                let credentials = format!(
+                    "{}:{}",
+                    username,
+                    config.password.as_deref().unwrap_or_default()
+                );
+                Some(HeaderValue::from_str(&format!(
+                    "Basic {}",
+                    base64::encode(credentials)
+                ))?)
+            }
+            None => None,
+        };
+        Ok(UpstreamProxy { uri, authorization })
+    }
+
+    pub fn authorization(&self) -> Option<&HeaderValue> {
+        self.authorization.as_ref()
+    }
+}
+
+#[derive(Clone)]
+pub struct ProxyConnector<C> {
+    inner: C,
+    proxy: Option<UpstreamProxy>, // Connections are made directly without a proxy
+}
+
+impl<C> ProxyConnector<C> {
+    pub fn new(inner: C, proxy: Option<UpstreamProxy>) -> ProxyConnector<C> {
+        ProxyConnector { inner, proxy }
+    }
+}
+
+impl<C> Service<Uri> for ProxyConnector<C>
+where
+    C: Service<Uri>,
+    C::Response: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    C::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    C::Future: Send + 'static,
+{
+    type Response = ProxyStream<C::Response>;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let proxy = match &self.proxy {
+            Some(proxy) => proxy.clone(),
+            None => {
+                let connecting = self.inner.call(uri);
+                return Box::pin(async move {
+                    Ok(ProxyStream {
+                        inner: connecting.await.map_err(Into::into)?,
+                        proxied: false,
+                    })
+                });
+            }
+        };
+        let connecting = self.inner.call(proxy.uri.clone());
+        Box::pin(async move {
+            let mut stream = connecting.await.map_err(Into::into)?;
+            if uri.scheme() != Some(&Scheme::HTTPS) {
+                // The proxy forwards the absolute form requests hyper sends on the connection
+                return Ok(ProxyStream {
+                    inner: stream,
+                    proxied: true,
+                });
+            }
+            let host = uri.host().ok_or("Backend URI has no host")?;
+            let authority = format!("{}:{}", host, uri.port_u16().unwrap_or(443));
+            tunnel(&mut stream, &authority, proxy.authorization()).await?;
+            Ok(ProxyStream {
+                inner: stream,
+                proxied: false,
+            })
+        })
+    }
+}
+
+async fn tunnel<T: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut T,
+    authority: &str,
+    authorization: Option<&HeaderValue>,
+) -> Result<(), io::Error> {
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", authority, authority);
+    if let Some(authorization) = authorization {
+        request.push_str("Proxy-Authorization: ");
+        request.push_str(authorization.to_str().unwrap_or_default());
+        request.push_str("\r\n");
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read byte by byte, so nothing sent by the backend after the proxy's response is consumed
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_CONNECT_RESPONSE {
+            return Err(io::Error::other("Upstream proxy response too large"));
+        }
+        let mut byte = [0];
+        if stream.read(&mut byte).await? == 0 {
+            return Err(io::Error::other("Upstream proxy closed the connection"));
+        }
+        response.push(byte[0]);
+    }
+    let status_line = String::from_utf8_lossy(&response);
+    let status = status_line.split_whitespace().nth(1);
+    if status != Some("200") {
+        return Err(io::Error::other(format!(
+            "Upstream proxy refused CONNECT to {}: {}",
+            authority,
+            status_line.lines().next().unwrap_or_default()
+        )));
+    }
+    Ok(())
+}
+
+pub struct ProxyStream<T> {
+    inner: T,
+    proxied: bool, // Requests are sent to the proxy in absolute form
+}
+
+impl<T: Connection> Connection for ProxyStream<T> {
+    fn connected(&self) -> Connected {
+        self.inner.connected().proxy(self.proxied)
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for ProxyStream<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for ProxyStream<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, ClientConfig, Timeouts};
+    use hyper::http::StatusCode;
+    use hyper::{Body, Request};
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+
+    async fn mock_proxy(response: &'static str) -> (String, Arc<Mutex<String>>) {
+        // Records the request received, responding with the given response
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(String::new()));
+        let recorded = received.clone();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 4096];
+            let read = stream.read(&mut buf).await.unwrap();
+            *recorded.lock().unwrap() = String::from_utf8_lossy(&buf[..read]).to_string();
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        (format!("http://{}", address), received)
+    }
+
+    fn proxied_client(url: String) -> Client {
+        let client_config = ClientConfig {
+            upstream_proxy: Some(UpstreamProxyConfig {
+                url,
+                username: Some("user".to_string()),
+                password: Some("pass".to_string()),
+            }),
+            ..Default::default()
+        };
+        Client::new(
+            Timeouts {
+                request: Some(2000),
+                ..Default::default()
+            },
+            &client_config,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_upstream_proxy_http() {
+        let (url, received) =
+            mock_proxy("HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\nvia proxy").await;
+        let client = proxied_client(url);
+
+        // The backend isn't resolved or connected to, only the proxy
+        let request = Request::get("http://backend.invalid:8080/path")
+            .body(Body::empty())
+            .unwrap();
+        let response = client.make_request(request, true).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "via proxy");
+
+        let received = received.lock().unwrap().to_lowercase();
+        assert!(received.starts_with("get http://backend.invalid:8080/path http/1.1\r\n"));
+        // user:pass
+        assert!(received.contains("proxy-authorization: basic dxnlcjpwyxnz\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_upstream_proxy_connect() {
+        let (url, received) = mock_proxy("HTTP/1.1 403 Forbidden\r\n\r\n").await;
+        let client = proxied_client(url);
+
+        let request = Request::get("https://backend.invalid/path")
+            .body(Body::empty())
+            .unwrap();
+        let response = client.make_request(request, true).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let received = received.lock().unwrap().clone();
+        assert!(received.starts_with("CONNECT backend.invalid:443 HTTP/1.1\r\n"));
+        assert!(received.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_upstream_proxy_url() {
+        let config = |url: &str| UpstreamProxyConfig {
+            url: url.to_string(),
+            username: None,
+            password: None,
+        };
+        assert!(UpstreamProxy::new(&config("http://proxy.internal:3128")).is_ok());
+        assert!(UpstreamProxy::new(&config("https://proxy.internal:3128")).is_err());
+        assert!(UpstreamProxy::new(&config("proxy.internal")).is_err());
+    }
+}
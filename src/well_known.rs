@@ -0,0 +1,44 @@
+// Well known files (favicon.ico and robots.txt) served by the proxy itself,
+// so that they don't reach the backends
+use anyhow::{Context, Error};
+use hyper::body::Bytes;
+use hyper::http::{header, HeaderValue, Method, Response};
+use hyper::Body;
+
+pub struct WellKnown {
+    favicon: Option<Bytes>,
+    robots_txt: Option<Bytes>,
+}
+
+impl WellKnown {
+    pub fn load(favicon_path: Option<&str>, robots_txt: Option<&str>) -> Result<WellKnown, Error> {
+        // The favicon is read once at startup
+        let favicon = favicon_path
+            .map(|path| {
+                std::fs::read(path).with_context(|| format!("Unable to read favicon: {}", path))
+            })
+            .transpose()?
+            .map(Bytes::from);
+        Ok(WellKnown {
+            favicon,
+            robots_txt: robots_txt.map(|robots_txt| Bytes::from(robots_txt.to_string())),
+        })
+    }
+
+    pub fn response(&self, method: &Method, path: &str) -> Option<Response<Body>> {
+        // None when the file isn't configured, so the request is proxied as normal
+        if !matches!(method, &Method::GET | &Method::HEAD) {
+            return None;
+        }
+        let (body, content_type) = match path {
+            "/favicon.ico" => (self.favicon.as_ref()?, "image/x-icon"),
+            "/robots.txt" => (self.robots_txt.as_ref()?, "text/plain; charset=utf-8"),
+            _ => return None,
+        };
+        let mut response = Response::new(Body::from(body.clone()));
+        response
+            .headers_mut()
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+        Some(response)
+    }
+}
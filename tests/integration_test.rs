@@ -13,6 +13,12 @@ pub struct MockBackend {
     pub mock_server: Option<MockServer>,
 }
 
+impl Default for MockBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MockBackend {
     pub const fn new() -> Self {
         Self { mock_server: None }
@@ -33,6 +39,11 @@ impl MockBackend {
                 .respond_with(ResponseTemplate::new(200))
                 .mount(&mock_server)
                 .await;
+            Mock::given(method("HEAD"))
+                .and(path("/download"))
+                .respond_with(ResponseTemplate::new(200).insert_header("content-length", "1048576"))
+                .mount(&mock_server)
+                .await;
             Mock::given(method("POST"))
                 .and(path("/test"))
                 .respond_with(ResponseTemplate::new(200))
@@ -53,8 +64,11 @@ impl MockBackend {
     }
 }
 
-static MOCK_BACKEND: Mutex<MockBackend> = Mutex::new(MockBackend::new());
-static MOCK_BACKEND2: Mutex<MockBackend> = Mutex::new(MockBackend::new());
+// Held across the await while a backend starts, so an async mutex
+static MOCK_BACKEND: tokio::sync::Mutex<MockBackend> =
+    tokio::sync::Mutex::const_new(MockBackend::new());
+static MOCK_BACKEND2: tokio::sync::Mutex<MockBackend> =
+    tokio::sync::Mutex::const_new(MockBackend::new());
 static PROXY_STARTED: Mutex<bool> = Mutex::new(false);
 static TEST_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
@@ -134,11 +148,8 @@ async fn http_request(
     } else {
         client_method = client.get(url);
     }
-    if host_header.is_some() {
-        client_method
-            .header(HOST, host_header.unwrap())
-            .send()
-            .await
+    if let Some(host_header) = host_header {
+        client_method.header(HOST, host_header).send().await
     } else if no_proxy.is_some() && no_proxy.unwrap() {
         client_method.header("x-no-proxy", "true").send().await
     } else {
@@ -150,7 +161,7 @@ async fn http_request(
 async fn http1_get() {
     MOCK_BACKEND
         .lock()
-        .unwrap()
+        .await
         .init("127.0.0.1:8000", "This is the mock backend!")
         .await;
     let proxy_parent = start_proxy();
@@ -174,11 +185,38 @@ async fn http1_get() {
     finish(proxy_parent);
 }
 
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn http1_get_plaintext() {
+    MOCK_BACKEND
+        .lock()
+        .await
+        .init("127.0.0.1:8000", "This is the mock backend!")
+        .await;
+    let proxy_parent = start_proxy();
+
+    // Sleep this thread while the server starts up
+    thread::sleep(time::Duration::from_millis(1000));
+
+    // Send a request to the plaintext listener, which shares the backends of the TLS one
+    let resp = http_request(
+        "http1",
+        "http://localhost:4080/test",
+        Some("test.home"),
+        None,
+        None,
+    )
+    .await;
+
+    assert_response(resp, 200, Some("This is the mock backend!")).await;
+
+    finish(proxy_parent);
+}
+
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
 async fn http1_get_no_host_header() {
     MOCK_BACKEND
         .lock()
-        .unwrap()
+        .await
         .init("127.0.0.1:8000", "This is the mock backend!")
         .await;
     let proxy_parent = start_proxy();
@@ -199,7 +237,7 @@ async fn http1_get_no_host_header() {
 async fn http1_get_no_proxy_header_status() {
     MOCK_BACKEND
         .lock()
-        .unwrap()
+        .await
         .init("127.0.0.1:8000", "This is the mock backend!")
         .await;
     let proxy_parent = start_proxy();
@@ -227,7 +265,7 @@ async fn http1_get_no_proxy_header_status() {
 async fn http1_get_no_proxy_header_metrics() {
     MOCK_BACKEND
         .lock()
-        .unwrap()
+        .await
         .init("127.0.0.1:8000", "This is the mock backend!")
         .await;
     let proxy_parent = start_proxy();
@@ -267,7 +305,7 @@ async fn http1_get_no_proxy_header_metrics() {
 async fn http1_head() {
     MOCK_BACKEND
         .lock()
-        .unwrap()
+        .await
         .init("127.0.0.1:8000", "This is the mock backend!")
         .await;
     let proxy_parent = start_proxy();
@@ -291,11 +329,41 @@ async fn http1_head() {
     finish(proxy_parent);
 }
 
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn http1_head_content_length() {
+    MOCK_BACKEND
+        .lock()
+        .await
+        .init("127.0.0.1:8000", "This is the mock backend!")
+        .await;
+    let proxy_parent = start_proxy();
+
+    // Sleep this thread while the server starts up
+    thread::sleep(time::Duration::from_millis(1000));
+
+    let resp = http_request(
+        "http1",
+        "https://localhost:4000/download",
+        Some("test.home"),
+        None,
+        Some(Method::HEAD),
+    )
+    .await
+    .unwrap();
+
+    // The backend's Content-Length is forwarded, although the response has no body
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-length"], "1048576");
+    assert!(resp.bytes().await.unwrap().is_empty());
+
+    finish(proxy_parent);
+}
+
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
 async fn http1_post() {
     MOCK_BACKEND
         .lock()
-        .unwrap()
+        .await
         .init("127.0.0.1:8000", "This is the mock backend!")
         .await;
     let proxy_parent = start_proxy();
@@ -323,7 +391,7 @@ async fn http1_post() {
 async fn http1_put() {
     MOCK_BACKEND
         .lock()
-        .unwrap()
+        .await
         .init("127.0.0.1:8000", "This is the mock backend!")
         .await;
     let proxy_parent = start_proxy();
@@ -351,7 +419,7 @@ async fn http1_put() {
 async fn http2_get() {
     MOCK_BACKEND
         .lock()
-        .unwrap()
+        .await
         .init("127.0.0.1:8000", "This is the mock backend!")
         .await;
     let proxy_parent = start_proxy();
@@ -379,7 +447,7 @@ async fn http2_get() {
 async fn http2_get_no_host_header() {
     MOCK_BACKEND
         .lock()
-        .unwrap()
+        .await
         .init("127.0.0.1:8000", "This is the mock backend!")
         .await;
     let proxy_parent = start_proxy();
@@ -400,7 +468,7 @@ async fn http2_get_no_host_header() {
 async fn http2_get_no_proxy_header_status() {
     MOCK_BACKEND
         .lock()
-        .unwrap()
+        .await
         .init("127.0.0.1:8000", "This is the mock backend!")
         .await;
     let proxy_parent = start_proxy();
@@ -428,7 +496,7 @@ async fn http2_get_no_proxy_header_status() {
 async fn http2_get_no_proxy_header_metrics() {
     MOCK_BACKEND
         .lock()
-        .unwrap()
+        .await
         .init("127.0.0.1:8000", "This is the mock backend!")
         .await;
     let proxy_parent = start_proxy();
@@ -468,7 +536,7 @@ async fn http2_get_no_proxy_header_metrics() {
 async fn http2_head() {
     MOCK_BACKEND
         .lock()
-        .unwrap()
+        .await
         .init("127.0.0.1:8000", "This is the mock backend!")
         .await;
     let proxy_parent = start_proxy();
@@ -496,7 +564,7 @@ async fn http2_head() {
 async fn http2_post() {
     MOCK_BACKEND
         .lock()
-        .unwrap()
+        .await
         .init("127.0.0.1:8000", "This is the mock backend!")
         .await;
     let proxy_parent = start_proxy();
@@ -524,7 +592,7 @@ async fn http2_post() {
 async fn http2_put() {
     MOCK_BACKEND
         .lock()
-        .unwrap()
+        .await
         .init("127.0.0.1:8000", "This is the mock backend!")
         .await;
     let proxy_parent = start_proxy();
@@ -552,12 +620,12 @@ async fn http2_put() {
 async fn load_balancing_round_robin() {
     MOCK_BACKEND
         .lock()
-        .unwrap()
+        .await
         .init("127.0.0.1:8000", "This is the mock backend!")
         .await;
     MOCK_BACKEND2
         .lock()
-        .unwrap()
+        .await
         .init("127.0.0.1:8001", "This is the mock backend 2!")
         .await;
     let proxy_parent = start_proxy();